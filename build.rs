@@ -135,18 +135,8 @@ fn main() {
 
     let pwd = std::env::current_dir().unwrap();
 
-    // Pull wit from git repo
-    let wit_dir = pwd.join("wit");
-    fs::create_dir_all(&wit_dir).unwrap();
-    let wit_file = wit_dir.join("kinode.wit");
-    if !wit_file.exists() {
-        // TODO: cache in better way
-        let mut wit_file = std::fs::File::create(&wit_file).unwrap();
-        let kinode_wit_url =
-            "https://raw.githubusercontent.com/kinode-dao/kinode-wit/master/kinode.wit";
-        let mut response = reqwest::blocking::get(kinode_wit_url).unwrap();
-        io::copy(&mut response, &mut wit_file).unwrap();
-    }
+    // kinode.wit is vendored in wit/, pinned to the interface the kernel
+    // implements, rather than pulled from kinode-wit master
 
     // Create target.wasm (compiled .wit) & world
     run_command(Command::new("wasm-tools").args([
@@ -103,6 +103,10 @@ pub enum LocalRequest {
     /// no blob; select a downloaded package and install it
     /// if requested, will return an InstallResponse indicating success/failure
     Install(PackageId),
+    /// no blob; install a downloaded package as with Install, limiting the bytes
+    /// it may store across vfs, kv, sqlite and graphdb to `quota`
+    /// if requested, will return an InstallResponse indicating success/failure
+    InstallWithQuota { package: PackageId, quota: u64 },
     /// Takes no blob; Select an installed package and uninstall it.
     /// This will kill the processes in the **manifest** of the package,
     /// but not the processes that were spawned by those processes! Take
@@ -167,6 +171,16 @@ pub enum Resp {
     FTWorkerResult(FTWorkerResult),
 }
 
+/// the subset of the runtime's StateAction used here
+#[derive(Debug, Serialize, Deserialize)]
+enum StateAction {
+    DeleteState(ProcessId),
+    SetQuota {
+        package_id: PackageId,
+        quota: Option<u64>,
+    },
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct ManifestCap {
     process: String,
@@ -322,13 +336,22 @@ fn handle_local_request(our: &Address, request: &LocalRequest, state: &mut State
                 _ => DownloadResponse::Failure,
             },
         ),
-        LocalRequest::Install(package) => match handle_install(our, package) {
+        LocalRequest::Install(package) => match handle_install(our, package, None) {
             Ok(()) => LocalResponse::InstallResponse(InstallResponse::Success),
             Err(e) => {
                 println!("{:?}", e);
                 LocalResponse::InstallResponse(InstallResponse::Failure)
             }
         },
+        LocalRequest::InstallWithQuota { package, quota } => {
+            match handle_install(our, package, Some(*quota)) {
+                Ok(()) => LocalResponse::InstallResponse(InstallResponse::Success),
+                Err(e) => {
+                    println!("{:?}", e);
+                    LocalResponse::InstallResponse(InstallResponse::Failure)
+                }
+            }
+        }
         LocalRequest::Uninstall(package) => match handle_uninstall(package) {
             Ok(()) => LocalResponse::UninstallResponse(UninstallResponse::Success),
             Err(_) => LocalResponse::UninstallResponse(UninstallResponse::Failure),
@@ -427,8 +450,19 @@ fn handle_new_package(
     Ok(())
 }
 
-fn handle_install(our: &Address, package: &PackageId) -> anyhow::Result<()> {
+fn handle_install(our: &Address, package: &PackageId, quota: Option<u64>) -> anyhow::Result<()> {
     let drive_path = format!("/{}/pkg", package);
+    // set the quota before any process runs, so that all of its writes count.
+    // installing without one keeps whatever quota was set before.
+    if quota.is_some() {
+        Request::new()
+            .target(("our", "state", "distro", "sys"))
+            .body(serde_json::to_vec(&StateAction::SetQuota {
+                package_id: package.clone(),
+                quota,
+            })?)
+            .send_and_await_response(5)??;
+    }
     Request::new()
         .target(("our", "vfs", "distro", "sys"))
         .body(serde_json::to_vec(&vfs::VfsRequest {
@@ -654,7 +688,7 @@ fn handle_uninstall(package: &PackageId) -> anyhow::Result<()> {
     };
     let manifest = String::from_utf8(blob.bytes)?;
     let manifest = serde_json::from_str::<Vec<kt::PackageManifestEntry>>(&manifest)?;
-    // reading from the package manifest, kill every process
+    // reading from the package manifest, kill every process and delete its state
     for entry in &manifest {
         let process_id = format!("{}:{}", entry.process_name, package);
         let Ok(parsed_new_process_id) = process_id.parse::<ProcessId>() else {
@@ -663,6 +697,12 @@ fn handle_uninstall(package: &PackageId) -> anyhow::Result<()> {
         Request::new()
             .target(("our", "kernel", "distro", "sys"))
             .body(serde_json::to_vec(&kt::KernelCommand::KillProcess(
+                parsed_new_process_id.clone(),
+            ))?)
+            .send()?;
+        Request::new()
+            .target(("our", "state", "distro", "sys"))
+            .body(serde_json::to_vec(&StateAction::DeleteState(
                 parsed_new_process_id,
             ))?)
             .send()?;
@@ -675,6 +715,14 @@ fn handle_uninstall(package: &PackageId) -> anyhow::Result<()> {
             action: vfs::VfsAction::RemoveDirAll,
         })?)
         .send_and_await_response(5)??;
+    // and lift its quota, so a reinstall without one isn't held to it
+    Request::new()
+        .target(("our", "state", "distro", "sys"))
+        .body(serde_json::to_vec(&StateAction::SetQuota {
+            package_id: package.clone(),
+            quota: None,
+        })?)
+        .send_and_await_response(5)??;
     Ok(())
 }
 
@@ -15,6 +15,7 @@ wit_bindgen::generate!({
 #[derive(Debug, Serialize, Deserialize)]
 pub enum LocalRequest {
     Install(PackageId),
+    InstallWithQuota { package: PackageId, quota: u64 },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -40,10 +41,24 @@ fn init(our: Address) {
 
     if arg.is_empty() {
         println!("install: 1 argument required, the package id of the app");
+        println!("optionally followed by the most bytes it may store");
         println!("example: install app:publisher.os");
+        println!("example: install app:publisher.os 1000000000");
         return;
     };
 
+    let (arg, quota) = arg.split_once(' ').unwrap_or((arg.as_str(), ""));
+    let quota = match quota.trim() {
+        "" => None,
+        quota => match quota.parse::<u64>() {
+            Ok(quota) => Some(quota),
+            Err(_) => {
+                println!("install: invalid quota, must be a number of bytes");
+                return;
+            }
+        },
+    };
+
     let Ok(package_id) = arg.parse::<PackageId>() else {
         println!("install: invalid package id, make sure to include package name and publisher");
         println!("example: app_name:publisher_name");
@@ -52,7 +67,16 @@ fn init(our: Address) {
 
     let Ok(Ok(Message::Response { body, .. })) =
         Request::to((our.node(), ("main", "app_store", "sys")))
-            .body(serde_json::to_vec(&LocalRequest::Install(package_id.clone())).unwrap())
+            .body(
+                serde_json::to_vec(&match quota {
+                    None => LocalRequest::Install(package_id.clone()),
+                    Some(quota) => LocalRequest::InstallWithQuota {
+                        package: package_id.clone(),
+                        quota,
+                    },
+                })
+                .unwrap(),
+            )
             .send_and_await_response(5)
     else {
         println!("install: failed to get a response from app_store..!");
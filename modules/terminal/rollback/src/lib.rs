@@ -0,0 +1,104 @@
+use kinode_process_lib::{
+    await_next_request_body, call_init, println, Address, ProcessId, Request,
+};
+use serde::{Deserialize, Serialize};
+
+wit_bindgen::generate!({
+    path: "../../../wit",
+    world: "process",
+    exports: {
+        world: Component,
+    },
+});
+
+/// the subset of the runtime's `StateAction` used here
+#[derive(Serialize)]
+enum StateAction {
+    ListVersions(ProcessId),
+    RestoreVersion { process_id: ProcessId, version: u64 },
+}
+
+#[derive(Deserialize)]
+struct StateVersion {
+    version: u64,
+    timestamp: u64,
+    len: u64,
+}
+
+#[derive(Deserialize)]
+enum StateResponse {
+    ListVersions { versions: Vec<StateVersion> },
+    RestoreVersion,
+    Err(serde_json::Value),
+}
+
+call_init!(init);
+
+/// `rollback <process>` lists the kept versions of a process's state;
+/// `rollback <process> <version>` makes that version its current state.
+fn init(_our: Address) {
+    let Ok(args) = await_next_request_body() else {
+        println!("rollback: failed to get args, aborting");
+        return;
+    };
+
+    let line = String::from_utf8(args).unwrap_or_default();
+    let (process, version) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+
+    let Ok(process_id) = process.parse::<ProcessId>() else {
+        println!("rollback: usage: rollback <process> [<version>]");
+        return;
+    };
+
+    let action = if version.is_empty() {
+        StateAction::ListVersions(process_id.clone())
+    } else {
+        let Ok(version) = version.trim().parse::<u64>() else {
+            println!("rollback: invalid version {}", version);
+            return;
+        };
+        StateAction::RestoreVersion {
+            process_id: process_id.clone(),
+            version,
+        }
+    };
+
+    let Ok(Ok(response)) = Request::new()
+        .target(("our", "state", "distro", "sys"))
+        .body(serde_json::to_vec(&action).unwrap())
+        .send_and_await_response(5)
+    else {
+        println!("rollback: no response from state");
+        return;
+    };
+
+    match serde_json::from_slice::<StateResponse>(response.body()) {
+        Ok(StateResponse::ListVersions { versions }) if versions.is_empty() => {
+            println!("rollback: no versions kept for {}", process_id);
+        }
+        Ok(StateResponse::ListVersions { versions }) => {
+            let lines: Vec<String> = versions
+                .iter()
+                .map(|v| {
+                    format!(
+                        "  {}: {} bytes, written at {} (unix ms)",
+                        v.version, v.len, v.timestamp
+                    )
+                })
+                .collect();
+            println!("versions of {}:\n{}", process_id, lines.join("\n"));
+        }
+        Ok(StateResponse::RestoreVersion) => {
+            println!(
+                "rollback: restored {}, which will see it once it next reads its state (e.g. on restart)",
+                process_id
+            );
+        }
+        Ok(StateResponse::Err(error)) => {
+            println!("rollback: {}", error);
+        }
+        Err(_) => {
+            println!("rollback: unexpected response from state");
+        }
+    }
+}
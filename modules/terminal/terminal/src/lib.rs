@@ -108,6 +108,10 @@ impl Guest for Component {
                             "top".to_string(),
                             "top:terminal:sys".parse::<ProcessId>().unwrap(),
                         ),
+                        (
+                            "rollback".to_string(),
+                            "rollback:terminal:sys".parse::<ProcessId>().unwrap(),
+                        ),
                     ]),
                 },
             };
@@ -0,0 +1,219 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+/// starts data encrypted at rest. data is only taken to be encrypted if the
+/// header that follows also authenticates under the store's key, so plaintext
+/// that happens to start with these bytes is still read as-is.
+const MAGIC: &[u8; 8] = b"\x00KNENC02";
+
+const ID_LEN: usize = 8;
+
+const NONCE_LEN: usize = 12;
+
+/// bytes after each chunk's ciphertext: the AES-GCM tag
+const TAG_LEN: usize = 16;
+
+/// bytes before the chunks: the magic, a random id binding the chunks to this
+/// header, and a tag over both that proves the header was written with our key
+pub const HEADER_LEN: usize = MAGIC.len() + ID_LEN + TAG_LEN;
+
+/// plaintext bytes in each chunk; only the last chunk may hold fewer
+pub const CHUNK_LEN: u64 = 64 * 1024;
+
+/// bytes each chunk adds to its plaintext: its nonce and tag
+pub const CHUNK_OVERHEAD: u64 = (NONCE_LEN + TAG_LEN) as u64;
+
+/// id of one piece of encrypted data, from its header
+pub type DataId = [u8; ID_LEN];
+
+/// Encryption of one store's data at rest, keyed from the keyfile's `file_key`.
+///
+/// Encrypted data is a header followed by chunks of up to `CHUNK_LEN` bytes of
+/// plaintext, each sealed on its own with a fresh nonce, so that a range of a
+/// large file can be read or rewritten without touching the rest of it. Each
+/// chunk is bound to its data's id, its index and whether it is the last, so
+/// chunks can't be moved between files or reordered, and the data can't be
+/// truncated unnoticed.
+///
+/// Data is always decrypted on read if it was encrypted; `enabled` (set with
+/// `--encrypt-at-rest`) decides whether new writes are encrypted.
+pub struct AtRest {
+    pub enabled: bool,
+    key: [u8; 32],
+    cipher: Aes256Gcm,
+}
+
+fn invalid_data(error: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error.to_string())
+}
+
+impl AtRest {
+    /// `context` separates the keys of different stores, e.g. `b"kinode vfs"`
+    pub fn new(file_key: &[u8], context: &[u8], enabled: bool) -> Self {
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, file_key)
+            .expand(context, &mut key)
+            .expect("32 bytes is a valid hkdf output length");
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+        AtRest {
+            enabled,
+            key,
+            cipher,
+        }
+    }
+
+    /// the raw derived key, for stores that do their own encryption
+    pub fn key(&self) -> &[u8; 32] {
+        &self.key
+    }
+
+    /// the header's tag is sealed under a nonce no chunk's random nonce is
+    /// expected to take: the id followed by all ones
+    fn header_nonce(id: &DataId) -> [u8; NONCE_LEN] {
+        let mut nonce = [0xffu8; NONCE_LEN];
+        nonce[..ID_LEN].copy_from_slice(id);
+        nonce
+    }
+
+    /// a header for new encrypted data, along with its id
+    pub fn new_header(&self) -> ([u8; HEADER_LEN], DataId) {
+        let id: DataId = rand::random();
+        let mut header = [0u8; HEADER_LEN];
+        header[..MAGIC.len()].copy_from_slice(MAGIC);
+        header[MAGIC.len()..MAGIC.len() + ID_LEN].copy_from_slice(&id);
+        let tag = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&Self::header_nonce(&id)),
+                Payload {
+                    msg: &[],
+                    aad: &header[..MAGIC.len() + ID_LEN],
+                },
+            )
+            .unwrap();
+        header[MAGIC.len() + ID_LEN..].copy_from_slice(&tag);
+        (header, id)
+    }
+
+    /// the id of data encrypted under our key, if `head` starts with its header
+    pub fn check_header(&self, head: &[u8]) -> Option<DataId> {
+        if head.len() < HEADER_LEN || !head.starts_with(MAGIC) {
+            return None;
+        }
+        let id: DataId = head[MAGIC.len()..MAGIC.len() + ID_LEN].try_into().unwrap();
+        self.cipher
+            .decrypt(
+                Nonce::from_slice(&Self::header_nonce(&id)),
+                Payload {
+                    msg: &head[MAGIC.len() + ID_LEN..HEADER_LEN],
+                    aad: &head[..MAGIC.len() + ID_LEN],
+                },
+            )
+            .ok()
+            .map(|_| id)
+    }
+
+    fn chunk_aad(id: &DataId, index: u64, last: bool) -> Vec<u8> {
+        [id.as_slice(), &index.to_le_bytes(), &[last as u8]].concat()
+    }
+
+    pub fn seal_chunk(&self, id: &DataId, index: u64, last: bool, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: &Self::chunk_aad(id, index, last),
+                },
+            )
+            .unwrap();
+        [nonce.as_slice(), &ciphertext].concat()
+    }
+
+    pub fn open_chunk(
+        &self,
+        id: &DataId,
+        index: u64,
+        last: bool,
+        chunk: &[u8],
+    ) -> Result<Vec<u8>, std::io::Error> {
+        if (chunk.len() as u64) < CHUNK_OVERHEAD {
+            return Err(invalid_data("encrypted data is truncated"));
+        }
+        self.cipher
+            .decrypt(
+                Nonce::from_slice(&chunk[..NONCE_LEN]),
+                Payload {
+                    msg: &chunk[NONCE_LEN..],
+                    aad: &Self::chunk_aad(id, index, last),
+                },
+            )
+            .map_err(|_| invalid_data("failed to decrypt data at rest"))
+    }
+
+    /// number of chunks holding `len` bytes of plaintext. empty data still
+    /// has one, so that its end is marked.
+    pub fn chunk_count(len: u64) -> u64 {
+        len.div_ceil(CHUNK_LEN).max(1)
+    }
+
+    /// where chunk `index` starts in encrypted data
+    pub fn chunk_offset(index: u64) -> u64 {
+        HEADER_LEN as u64 + index * (CHUNK_LEN + CHUNK_OVERHEAD)
+    }
+
+    /// length of the plaintext held in `len` bytes of encrypted data
+    pub fn plaintext_len(len: u64) -> u64 {
+        let chunks = len.saturating_sub(HEADER_LEN as u64);
+        let full = chunks / (CHUNK_LEN + CHUNK_OVERHEAD);
+        let rest = chunks % (CHUNK_LEN + CHUNK_OVERHEAD);
+        full * CHUNK_LEN + rest.saturating_sub(CHUNK_OVERHEAD)
+    }
+
+    /// encrypt `plaintext` if writes are encrypted, else return it unchanged
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        if !self.enabled {
+            return plaintext.to_vec();
+        }
+        self.encrypt(plaintext)
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let (header, id) = self.new_header();
+        let count = Self::chunk_count(plaintext.len() as u64);
+        let mut data =
+            Vec::with_capacity(HEADER_LEN + plaintext.len() + (count * CHUNK_OVERHEAD) as usize);
+        data.extend_from_slice(&header);
+        for index in 0..count {
+            let start = (index * CHUNK_LEN) as usize;
+            let end = (start + CHUNK_LEN as usize).min(plaintext.len());
+            data.extend(self.seal_chunk(&id, index, index == count - 1, &plaintext[start..end]));
+        }
+        data
+    }
+
+    /// decrypt `data` if it was encrypted, else return it unchanged
+    pub fn open(&self, data: Vec<u8>) -> Result<Vec<u8>, std::io::Error> {
+        let Some(id) = self.check_header(&data) else {
+            return Ok(data);
+        };
+        let len = Self::plaintext_len(data.len() as u64);
+        let count = Self::chunk_count(len);
+        let mut plaintext = Vec::with_capacity(len as usize);
+        for index in 0..count {
+            let start = Self::chunk_offset(index) as usize;
+            let end = (start + (CHUNK_LEN + CHUNK_OVERHEAD) as usize).min(data.len());
+            if start >= end {
+                return Err(invalid_data("encrypted data is truncated"));
+            }
+            plaintext.extend(self.open_chunk(&id, index, index == count - 1, &data[start..end])?);
+        }
+        Ok(plaintext)
+    }
+}
@@ -1,18 +1,40 @@
 use anyhow::Result;
 use dashmap::DashMap;
+use futures::StreamExt;
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::Path;
 use std::sync::Arc;
 use surrealdb::engine::local::{Db, RocksDb};
 use surrealdb::opt::Config;
 use surrealdb::sql::Kind;
-use surrealdb::Surreal;
+use surrealdb::{Notification, Surreal};
 use tokio::fs;
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 
+use crate::quota::{QuotaExceeded, StorageQuotas};
 use crate::types::*;
 
 pub type SurrealDBConn = Surreal<Db>;
 
+/// open dbs, shared with snapshots so that the store can be dumped
+pub type OpenGdbs = Arc<DashMap<(PackageId, String), Mutex<SurrealDBConn>>>;
+
+/// where a restored snapshot leaves its dumps of the store, next to the
+/// store itself. they are imported the first time the store is opened.
+pub const RESTORE_DIR: &str = "graphdb_restore";
+
+/// live queries started with `GraphDbAction::Live`, by live_id
+type LiveQueries = Arc<DashMap<u64, LiveQuery>>;
+
+struct LiveQuery {
+    db_key: (PackageId, String),
+    owner: Address,
+    /// forwards notifications to `owner`. aborting it drops the query's stream,
+    /// which kills the query in surrealdb.
+    handle: JoinHandle<()>,
+}
+
 lazy_static::lazy_static! {
     static ref READ_KEYWORDS: HashSet<String> = {
         let mut set = HashSet::new();
@@ -40,6 +62,9 @@ pub async fn gdb(
     mut recv_from_loop: MessageReceiver,
     send_to_caps_oracle: CapMessageSender,
     home_directory_path: String,
+    snapshot_lock: SnapshotLock,
+    open_gdbs: OpenGdbs,
+    quotas: Arc<StorageQuotas>,
 ) -> anyhow::Result<()> {
     let graphdb_path = format!("{}/graphdb", &home_directory_path);
 
@@ -47,9 +72,8 @@ pub async fn gdb(
         panic!("failed creating graphdb dir! {:?}", e);
     }
 
-    let open_gdbs: Arc<DashMap<(PackageId, String), Mutex<SurrealDBConn>>> =
-        Arc::new(DashMap::new());
     let txs: Arc<DashMap<u64, Vec<(GraphDbAction, Vec<Kind>)>>> = Arc::new(DashMap::new());
+    let lives: LiveQueries = Arc::new(DashMap::new());
 
     let mut process_queues: HashMap<ProcessId, Arc<Mutex<VecDeque<KernelMessage>>>> =
         HashMap::new();
@@ -66,6 +90,23 @@ pub async fn gdb(
                     continue;
                 }
 
+                if km.source.process == *KERNEL_PROCESS_ID {
+                    if let Message::Request(Request { ref body, .. }) = km.message {
+                        if let Ok(KernelNotification::ProcessExited(process)) =
+                            serde_json::from_slice(body)
+                        {
+                            lives.retain(|_, live| {
+                                if live.owner.process == process {
+                                    live.handle.abort();
+                                    return false;
+                                }
+                                true
+                            });
+                        }
+                    }
+                    continue;
+                }
+
                 let queue = process_queues
                     .entry(km.source.process.clone())
                     .or_insert_with(|| Arc::new(Mutex::new(VecDeque::new())))
@@ -84,20 +125,28 @@ pub async fn gdb(
                 let open_gdbs = open_gdbs.clone();
 
                 let txs = txs.clone();
+                let lives = lives.clone();
                 let graphdb_path = graphdb_path.clone();
 
+                let snapshot_lock = snapshot_lock.clone();
+                let quotas = quotas.clone();
+
                 tokio::spawn(async move {
                     let mut queue_lock = queue.lock().await;
                     if let Some(km) = queue_lock.pop_front() {
+                        // keep snapshots from copying the store mid-request
+                        let _snapshot_guard = snapshot_lock.read().await;
                         if let Err(e) = handle_request(
                             our_node.clone(),
                             km.clone(),
                             open_gdbs.clone(),
                             txs.clone(),
+                            lives.clone(),
                             send_to_loop.clone(),
                             send_to_terminal.clone(),
                             send_to_caps_oracle.clone(),
                             graphdb_path.clone(),
+                            quotas.clone(),
                         )
                         .await
                         {
@@ -115,12 +164,14 @@ pub async fn gdb(
 async fn handle_request(
     our_node: String,
     km: KernelMessage,
-    open_gdbs: Arc<DashMap<(PackageId, String), Mutex<SurrealDBConn>>>,
+    open_gdbs: OpenGdbs,
     _txs: Arc<DashMap<u64, Vec<(GraphDbAction, Vec<Kind>)>>>,
+    lives: LiveQueries,
     send_to_loop: MessageSender,
     send_to_terminal: PrintSender,
     send_to_caps_oracle: CapMessageSender,
     graphdb_path: String,
+    quotas: Arc<StorageQuotas>,
 ) -> Result<(), GraphDbError> {
     let KernelMessage {
         id,
@@ -155,12 +206,26 @@ async fn handle_request(
         our_node.clone(),
         source.clone(),
         open_gdbs.clone(),
+        lives.clone(),
         send_to_caps_oracle.clone(),
         &request,
         graphdb_path.clone(),
     )
     .await?;
 
+    // the size of a write isn't known until it has run, so it is charged by
+    // the statement and parameters it is made from
+    let incoming = match &request.action {
+        GraphDbAction::Write { statement } => {
+            statement.len() + blob.as_ref().map_or(0, |b| b.bytes.len())
+        }
+        GraphDbAction::Define { resource } => resource.query()?.len(),
+        _ => 0,
+    };
+    quotas
+        .reserve_graphdb(&request.package_id, incoming as u64)
+        .await?;
+
     let db_name = request.db.clone();
 
     let (body, bytes) = match &request.action {
@@ -184,7 +249,7 @@ async fn handle_request(
             db.use_ns(source.process.package()).await.unwrap();
             db.use_db(db_name).await.unwrap();
 
-            let query = db.query(resource.clone().query());
+            let query = db.query(resource.query()?);
 
             query.await.map_err(|err| GraphDbError::SurrealDBError {
                 action: "".into(),
@@ -316,6 +381,111 @@ async fn handle_request(
 
             (serde_json::to_vec(&GraphDbResponse::Ok).unwrap(), None)
         }
+        GraphDbAction::Live { statement } => {
+            let db_key = (request.package_id.clone(), request.db.clone());
+            let db = match open_gdbs.get(&db_key) {
+                None => {
+                    return Err(GraphDbError::NoDb);
+                }
+                Some(db) => db,
+            };
+
+            let first_word = statement
+                .split_whitespace()
+                .next()
+                .map(|word| word.to_uppercase())
+                .unwrap_or("".to_string());
+            if first_word != "LIVE" {
+                return Err(GraphDbError::NotAReadKeyword);
+            }
+
+            let db = db.lock().await;
+            db.use_ns(source.process.package()).await.unwrap();
+            db.use_db(db_name).await.unwrap();
+
+            let mut response = db.query(statement.clone()).await?;
+            let mut stream = response.stream::<Notification<serde_json::Value>>(0)?;
+
+            let live_id = rand::random::<u64>();
+            let owner = Address {
+                node: our_node.clone(),
+                process: source.process.clone(),
+            };
+            let handle = {
+                let our_node = our_node.clone();
+                let owner = owner.clone();
+                let (package_id, db) = db_key.clone();
+                let lives = lives.clone();
+                let send_to_loop = send_to_loop.clone();
+                tokio::spawn(async move {
+                    while let Some(notification) = stream.next().await {
+                        let Ok(notification) = notification else {
+                            continue;
+                        };
+                        let action = match notification.action {
+                            surrealdb::Action::Create => GraphDbLiveAction::Create,
+                            surrealdb::Action::Update => GraphDbLiveAction::Update,
+                            surrealdb::Action::Delete => GraphDbLiveAction::Delete,
+                            #[allow(unreachable_patterns)]
+                            _ => continue,
+                        };
+                        let _ = send_to_loop
+                            .send(KernelMessage {
+                                id: rand::random(),
+                                source: Address {
+                                    node: our_node.clone(),
+                                    process: GRAPHDB_PROCESS_ID.clone(),
+                                },
+                                target: owner.clone(),
+                                rsvp: None,
+                                message: Message::Request(Request {
+                                    inherit: false,
+                                    expects_response: None,
+                                    body: serde_json::to_vec(&GraphDbLiveNotification {
+                                        package_id: package_id.clone(),
+                                        db: db.clone(),
+                                        live_id,
+                                        action,
+                                    })
+                                    .unwrap(),
+                                    metadata: None,
+                                    capabilities: vec![],
+                                }),
+                                lazy_load_blob: Some(LazyLoadBlob {
+                                    mime: Some("application/json".into()),
+                                    bytes: notification.data.to_string().into_bytes(),
+                                }),
+                            })
+                            .await;
+                    }
+                    // the query was killed from surrealdb's side
+                    lives.remove(&live_id);
+                })
+            };
+            lives.insert(
+                live_id,
+                LiveQuery {
+                    db_key,
+                    owner,
+                    handle,
+                },
+            );
+
+            (
+                serde_json::to_vec(&GraphDbResponse::Live { live_id }).unwrap(),
+                None,
+            )
+        }
+        GraphDbAction::Kill { live_id } => {
+            let db_key = (request.package_id.clone(), request.db.clone());
+            match lives.remove_if(live_id, |_, live| {
+                live.db_key == db_key && live.owner.process == source.process
+            }) {
+                None => return Err(GraphDbError::NoLiveQuery),
+                Some((_, live)) => live.handle.abort(),
+            }
+            (serde_json::to_vec(&GraphDbResponse::Ok).unwrap(), None)
+        }
         GraphDbAction::Backup => {
             // TODO: implement and test
             // for db_ref in open_gdbs.iter() {
@@ -374,7 +544,8 @@ async fn handle_request(
 async fn check_caps(
     our_node: String,
     source: Address,
-    open_gdbs: Arc<DashMap<(PackageId, String), Mutex<SurrealDBConn>>>,
+    open_gdbs: OpenGdbs,
+    lives: LiveQueries,
     mut send_to_caps_oracle: CapMessageSender,
     request: &GraphDbRequest,
     graphdb_path: String,
@@ -409,7 +580,7 @@ async fn check_caps(
             }
             Ok(())
         }
-        GraphDbAction::Read { .. } => {
+        GraphDbAction::Read { .. } | GraphDbAction::Live { .. } | GraphDbAction::Kill { .. } => {
             send_to_caps_oracle
                 .send(CapMessage::Has {
                     on: source.process.clone(),
@@ -490,6 +661,7 @@ async fn check_caps(
             }
 
             fs::create_dir_all(&graphdb_path).await?;
+            let restore_path = Path::new(&graphdb_path).with_file_name(RESTORE_DIR);
 
             let db = SurrealDBConn::new::<RocksDb>((graphdb_path, Config::default()))
                 .await
@@ -524,6 +696,9 @@ async fn check_caps(
                     error: err.to_string(),
                 })?;
 
+            // a snapshot restored into this node is imported on the store's first open
+            import_restored(&db, &restore_path).await?;
+
             println!("\n graphdb: created/opened db: {}", request.db);
 
             open_gdbs.insert(
@@ -540,7 +715,15 @@ async fn check_caps(
             }
 
             let db_path = format!("{}/{}/{}", graphdb_path, request.package_id, request.db);
-            open_gdbs.remove(&(request.package_id.clone(), request.db.clone()));
+            let db_key = (request.package_id.clone(), request.db.clone());
+            open_gdbs.remove(&db_key);
+            lives.retain(|_, live| {
+                if live.db_key == db_key {
+                    live.handle.abort();
+                    return false;
+                }
+                true
+            });
 
             fs::remove_dir_all(&db_path).await?;
             Ok(())
@@ -575,6 +758,85 @@ async fn add_capability(
     Ok(())
 }
 
+/// export every database in the store into `dest`, as `{namespace}/{database}.surql`.
+/// the store's files can't be copied consistently while it is open, so snapshots
+/// dump it instead, through the handle of an open db. returns false, having
+/// dumped nothing, if no db is open.
+pub async fn dump_open_store(open_gdbs: &OpenGdbs, dest: &Path) -> Result<bool, GraphDbError> {
+    let db = match open_gdbs.iter().next() {
+        None => return Ok(false),
+        Some(db_ref) => db_ref.value().lock().await.clone(),
+    };
+    // names become paths, so must not be able to leave `dest`
+    let is_plain = |name: &str| {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+    };
+    for ns in info_names(&db, "INFO FOR KV;", "namespaces").await? {
+        db.use_ns(&ns).await?;
+        for name in info_names(&db, "INFO FOR NS;", "databases").await? {
+            if !is_plain(&ns) || !is_plain(&name) {
+                return Err(GraphDbError::InputError {
+                    error: format!("can't dump database {ns}/{name}"),
+                });
+            }
+            db.use_db(&name).await?;
+            let ns_path = dest.join(&ns);
+            fs::create_dir_all(&ns_path).await?;
+            db.export(ns_path.join(format!("{name}.surql"))).await?;
+        }
+    }
+    Ok(true)
+}
+
+/// the names under `key` in the result of an `INFO FOR` query
+async fn info_names(
+    db: &SurrealDBConn,
+    query: &str,
+    key: &str,
+) -> Result<Vec<String>, GraphDbError> {
+    let mut response = db.query(query).await?;
+    let info: surrealdb::sql::Value = response.take(0)?;
+    Ok(match info.into_json().get(key) {
+        Some(serde_json::Value::Object(names)) => names.keys().cloned().collect(),
+        _ => vec![],
+    })
+}
+
+/// import the dumps left in `restore_path` by a restored snapshot, then remove them
+async fn import_restored(db: &SurrealDBConn, restore_path: &Path) -> Result<(), GraphDbError> {
+    if fs::metadata(restore_path).await.is_err() {
+        return Ok(());
+    }
+    let mut namespaces = fs::read_dir(restore_path).await?;
+    while let Some(ns_entry) = namespaces.next_entry().await? {
+        let ns = ns_entry.file_name().to_string_lossy().into_owned();
+        let mut dumps = fs::read_dir(ns_entry.path()).await?;
+        while let Some(dump) = dumps.next_entry().await? {
+            let path = dump.path();
+            if path
+                .extension()
+                .map_or(true, |extension| extension != "surql")
+            {
+                continue;
+            }
+            let Some(name) = path
+                .file_stem()
+                .map(|name| name.to_string_lossy().into_owned())
+            else {
+                continue;
+            };
+            db.use_ns(&ns).await?;
+            db.use_db(&name).await?;
+            db.import(&path).await?;
+        }
+    }
+    fs::remove_dir_all(restore_path).await?;
+    Ok(())
+}
+
 fn make_error_message(our_name: String, km: &KernelMessage, error: GraphDbError) -> KernelMessage {
     KernelMessage {
         id: km.id,
@@ -639,6 +901,15 @@ impl From<tokio::sync::mpsc::error::SendError<CapMessage>> for GraphDbError {
     }
 }
 
+impl From<QuotaExceeded> for GraphDbError {
+    fn from(err: QuotaExceeded) -> Self {
+        GraphDbError::QuotaExceeded {
+            quota: err.quota,
+            usage: err.usage,
+        }
+    }
+}
+
 impl From<std::io::Error> for GraphDbError {
     fn from(err: std::io::Error) -> Self {
         GraphDbError::IOError {
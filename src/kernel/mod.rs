@@ -1,10 +1,12 @@
+use crate::encryption::AtRest;
 use crate::types::STATE_PROCESS_ID;
 use crate::types::{self as t, VFS_PROCESS_ID};
 use crate::KERNEL_PROCESS_ID;
 use anyhow::Result;
-use ring::signature;
+use dashmap::DashMap;
+use ring::signature::{self, KeyPair};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
@@ -14,11 +16,17 @@ use wasmtime::{Config, Engine, WasmBacktraceDetails};
 pub mod process;
 /// Implement the functions served to processes by `kinode.wit`.
 mod standard_host;
+/// Serve the published `kinode.wit` 0.5.0 through the functions of the current one.
+mod standard_host_v0;
 
 const PROCESS_CHANNEL_CAPACITY: usize = 100;
 
 const DEFAULT_WIT_VERSION: u32 = 0;
 
+/// how often the kernel advances the wasmtime epoch. every process yields back
+/// to the runtime at least once per tick, and CPU limits are measured in ticks.
+pub const EPOCH_TICK_MS: u64 = 10;
+
 type ProcessMessageSender =
     tokio::sync::mpsc::Sender<Result<t::KernelMessage, t::WrappedSendError>>;
 type ProcessMessageReceiver =
@@ -36,12 +44,219 @@ struct StartProcessMetadata {
 type Senders = HashMap<t::ProcessId, ProcessSender>;
 //  handles are for managing liveness, map is for persistence and metadata.
 type ProcessHandles = HashMap<t::ProcessId, JoinHandle<Result<()>>>;
+//  restarts of each process by its OnExit::Restart behavior.
+//  shared with process loops, which outlive their own entries in the kernel.
+pub type RestartTracker = Arc<DashMap<t::ProcessId, Restarts>>;
+
+#[derive(Default)]
+pub struct Restarts {
+    /// times at which the process was restarted, within its policy window
+    pub history: VecDeque<std::time::Instant>,
+    /// the restart waiting out its backoff, aborted if the process is killed
+    pub pending: Option<JoinHandle<()>>,
+}
 
 enum ProcessSender {
     Runtime(t::MessageSender),
     Userspace(ProcessMessageSender),
 }
 
+/// when the kernel drops a Request from a local process, hand the process a
+/// SendError through the same path as network errors, so that it fails fast
+/// instead of waiting out its own timeout.
+fn throw_send_error(senders: &Senders, km: t::KernelMessage, kind: t::SendErrorKind) {
+    if !matches!(km.message, t::Message::Request(_)) {
+        return;
+    }
+    let Some(ProcessSender::Userspace(sender)) = senders.get(&km.source.process) else {
+        return;
+    };
+    // a process that isn't draining its queue must not stall the kernel loop
+    let sender = sender.clone();
+    tokio::spawn(async move {
+        let _ = sender
+            .send(Err(t::WrappedSendError {
+                id: km.id,
+                source: km.source.clone(),
+                error: t::SendError {
+                    kind,
+                    target: km.target,
+                    message: km.message,
+                    lazy_load_blob: km.lazy_load_blob,
+                },
+            }))
+            .await;
+    });
+}
+
+/// delegation chains longer than this are treated as invalid
+const MAX_DELEGATION_DEPTH: usize = 8;
+
+fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// unrestricted caps are signed as-is; restricted ones are signed together with their grant
+fn sign_capability(
+    keypair: &signature::Ed25519KeyPair,
+    cap: &t::Capability,
+    grant: Option<&t::CapabilityGrant>,
+) -> Vec<u8> {
+    let bytes = match grant {
+        None => rmp_serde::to_vec(cap).unwrap(),
+        Some(grant) => rmp_serde::to_vec(&(cap, grant)).unwrap(),
+    };
+    keypair.sign(&bytes).as_ref().to_vec()
+}
+
+/// a cap is valid if `on` holds it and, if it is restricted: it has not expired,
+/// its signature covers its grant, and, if delegated, the delegator still validly
+/// holds the parent cap with the signature it was delegated from.
+fn capability_is_valid(
+    process_map: &t::ProcessMap,
+    keypair: &signature::Ed25519KeyPair,
+    on: &t::ProcessId,
+    cap: &t::Capability,
+    depth: usize,
+) -> bool {
+    let Some(process) = process_map.get(on) else {
+        return false;
+    };
+    let Some(sig) = process.capabilities.get(cap) else {
+        return false;
+    };
+    let Some(grant) = process.capability_grants.get(cap) else {
+        return true;
+    };
+    if grant.is_expired(now_ms()) {
+        return false;
+    }
+    let public_key =
+        signature::UnparsedPublicKey::new(&signature::ED25519, keypair.public_key().as_ref());
+    if public_key
+        .verify(&rmp_serde::to_vec(&(cap, grant)).unwrap(), sig)
+        .is_err()
+    {
+        return false;
+    }
+    match &grant.delegation {
+        None => true,
+        Some(delegation) => {
+            depth < MAX_DELEGATION_DEPTH
+                && process_map
+                    .get(&delegation.delegator)
+                    .and_then(|p| p.capabilities.get(&delegation.parent))
+                    == Some(&delegation.parent_signature)
+                && capability_is_valid(
+                    process_map,
+                    keypair,
+                    &delegation.delegator,
+                    &delegation.parent,
+                    depth + 1,
+                )
+        }
+    }
+}
+
+/// the signature and grant, if restricted, with which `parent` passes `cap` on
+/// to a process it initializes, or `None` if it doesn't validly hold `cap`.
+/// a restricted cap keeps its expiry and delegation, so it is revoked with the
+/// parent's, and is signed again for its new holder.
+fn inherit_capability(
+    process_map: &t::ProcessMap,
+    keypair: &signature::Ed25519KeyPair,
+    parent: &t::ProcessId,
+    cap: &t::Capability,
+) -> Option<(Vec<u8>, Option<t::CapabilityGrant>)> {
+    if !capability_is_valid(process_map, keypair, parent, cap, 0) {
+        return None;
+    }
+    let grant = process_map.get(parent)?.capability_grants.get(cap).cloned();
+    Some((sign_capability(keypair, cap, grant.as_ref()), grant))
+}
+
+/// a delegated cap must narrow its parent: same issuer, and params that
+/// `narrows` the parent's. params that aren't JSON must be equal.
+fn attenuates(parent: &t::Capability, child: &t::Capability) -> bool {
+    if parent.issuer != child.issuer {
+        return false;
+    }
+    if parent.params == child.params {
+        return true;
+    }
+    let (Ok(parent), Ok(child)) = (
+        serde_json::from_str::<serde_json::Value>(&parent.params),
+        serde_json::from_str::<serde_json::Value>(&child.params),
+    ) else {
+        return false;
+    };
+    narrows(&parent, &child)
+}
+
+/// Whether `child` permits no more than `parent`, without knowing what the
+/// issuer means by either: the only narrowing that holds for any issuer is
+/// dropping alternatives from a list of them.
+///
+/// Arrays are read as lists of allowed alternatives: `child` may keep a
+/// non-empty subset of them, each narrowed in turn. Objects must have the same
+/// fields, each narrowed, since an issuer may read a missing field as "any" and
+/// an extra one as an extra permission. Anything else must be equal.
+fn narrows(parent: &serde_json::Value, child: &serde_json::Value) -> bool {
+    use serde_json::Value;
+    match (parent, child) {
+        (Value::Array(parent), Value::Array(child)) => {
+            !child.is_empty()
+                && child
+                    .iter()
+                    .all(|c| parent.iter().any(|p| narrows(p, c)))
+        }
+        (Value::Object(parent), Value::Object(child)) => {
+            parent.len() == child.len()
+                && parent
+                    .iter()
+                    .all(|(key, p)| child.get(key).is_some_and(|c| narrows(p, c)))
+        }
+        _ => parent == child,
+    }
+}
+
+/// remove `caps` from `target`. if `cascade`, also remove them from every
+/// process spawned by `target`, transitively, since it may have passed them on.
+/// returns true if any capability was removed.
+fn revoke_capabilities(
+    process_map: &mut t::ProcessMap,
+    target: &t::ProcessId,
+    caps: &[t::Capability],
+    cascade: bool,
+) -> bool {
+    let mut revoked = false;
+    let mut visited = HashSet::new();
+    let mut to_visit = vec![target.clone()];
+    while let Some(on) = to_visit.pop() {
+        if !visited.insert(on.clone()) {
+            continue;
+        }
+        if let Some(entry) = process_map.get_mut(&on) {
+            for cap in caps {
+                revoked |= entry.capabilities.remove(cap).is_some();
+                entry.capability_grants.remove(cap);
+            }
+        }
+        if cascade {
+            to_visit.extend(
+                process_map
+                    .iter()
+                    .filter(|(_, p)| p.spawned_by.as_ref() == Some(&on))
+                    .map(|(id, _)| id.clone()),
+            );
+        }
+    }
+    revoked
+}
+
 /// persist kernel's process_map state for next bootup
 /// and (TODO) wait for filesystem to respond in the affirmative
 async fn persist_state(
@@ -88,6 +303,7 @@ async fn handle_kernel_request(
     process_map: &mut t::ProcessMap,
     caps_oracle: t::CapMessageSender,
     engine: &Engine,
+    restart_tracker: &RestartTracker,
 ) {
     let t::Message::Request(request) = km.message else {
         return;
@@ -133,6 +349,30 @@ async fn handle_kernel_request(
                     }))
                     .await;
             }
+            for listener in t::BOOT_LISTENERS.iter() {
+                let _ = send_to_loop
+                    .send(t::KernelMessage {
+                        id: rand::random(),
+                        source: t::Address {
+                            node: our_name.clone(),
+                            process: KERNEL_PROCESS_ID.clone(),
+                        },
+                        target: t::Address {
+                            node: our_name.clone(),
+                            process: listener.clone(),
+                        },
+                        rsvp: None,
+                        message: t::Message::Request(t::Request {
+                            inherit: false,
+                            expects_response: None,
+                            body: serde_json::to_vec(&t::KernelNotification::Booted).unwrap(),
+                            metadata: None,
+                            capabilities: vec![],
+                        }),
+                        lazy_load_blob: None,
+                    })
+                    .await;
+            }
         }
         t::KernelCommand::Shutdown => {
             for handle in process_handles.values() {
@@ -149,7 +389,16 @@ async fn handle_kernel_request(
             on_exit,
             initial_capabilities,
             public,
+            limits,
+            restart_policy,
+            spawned_by,
+            capability_grants,
         } => {
+            let (spawned_by, capability_grants) = if km.source.process == *KERNEL_PROCESS_ID {
+                (spawned_by, capability_grants)
+            } else {
+                (None, vec![])
+            };
             let Some(blob) = km.lazy_load_blob else {
                 let _ = send_to_terminal
                     .send(t::Printout {
@@ -187,20 +436,48 @@ async fn handle_kernel_request(
             };
 
             // check cap sigs & transform valid to unsigned to be plugged into procs
-            let parent_caps: &HashMap<t::Capability, Vec<u8>> =
-                &process_map.get(&km.source.process).unwrap().capabilities;
             let mut valid_capabilities: HashMap<t::Capability, Vec<u8>> = HashMap::new();
+            let mut grants: HashMap<t::Capability, t::CapabilityGrant> = HashMap::new();
             if km.source.process == "kernel:distro:sys" {
                 for cap in initial_capabilities {
                     let sig = keypair.sign(&rmp_serde::to_vec(&cap).unwrap());
                     valid_capabilities.insert(cap, sig.as_ref().to_vec());
                 }
+                // restricted caps stay restricted: either carried over by the kernel
+                // across a restart, or inherited from the parent that passed them on.
+                // caps the parent holds but has let expire or had revoked are dropped.
+                grants = capability_grants
+                    .into_iter()
+                    .filter(|(cap, _)| valid_capabilities.contains_key(cap))
+                    .collect();
+                if let Some(parent) = spawned_by.as_ref() {
+                    for cap in valid_capabilities.keys().cloned().collect::<Vec<_>>() {
+                        if grants.contains_key(&cap)
+                            || !process_map
+                                .get(parent)
+                                .is_some_and(|p| p.capabilities.contains_key(&cap))
+                        {
+                            continue;
+                        }
+                        match inherit_capability(process_map, &keypair, parent, &cap) {
+                            Some((_, Some(grant))) => {
+                                grants.insert(cap, grant);
+                            }
+                            Some((_, None)) => {}
+                            None => {
+                                valid_capabilities.remove(&cap);
+                            }
+                        }
+                    }
+                }
             } else {
                 for cap in initial_capabilities {
-                    match parent_caps.get(&cap) {
-                        // NOTE: verifying sigs here would be unnecessary
-                        Some(sig) => {
-                            valid_capabilities.insert(cap, sig.to_vec());
+                    match inherit_capability(process_map, &keypair, &km.source.process, &cap) {
+                        Some((sig, grant)) => {
+                            if let Some(grant) = grant {
+                                grants.insert(cap.clone(), grant);
+                            }
+                            valid_capabilities.insert(cap, sig);
                         }
                         None => {
                             println!(
@@ -212,6 +489,9 @@ async fn handle_kernel_request(
                     }
                 }
             }
+            for (cap, grant) in &grants {
+                valid_capabilities.insert(cap.clone(), sign_capability(&keypair, cap, Some(grant)));
+            }
             // give the initializer and itself the messaging cap.
             // NOTE: we do this even if the process is public, because
             // a process might redundantly call grant_capabilities.
@@ -257,6 +537,7 @@ async fn handle_kernel_request(
                 process_map,
                 engine,
                 caps_oracle,
+                restart_tracker,
                 &StartProcessMetadata {
                     source: if let Some(ref rsvp) = km.rsvp {
                         rsvp.clone()
@@ -269,7 +550,11 @@ async fn handle_kernel_request(
                         wit_version,
                         on_exit,
                         capabilities: valid_capabilities,
+                        capability_grants: grants,
                         public,
+                        limits,
+                        restart_policy,
+                        spawned_by,
                     },
                     reboot: false,
                 },
@@ -309,6 +594,7 @@ async fn handle_kernel_request(
         t::KernelCommand::GrantCapabilities {
             target,
             capabilities,
+            expires,
         } => {
             let Some(entry) = process_map.get_mut(&target) else {
                 let _ = send_to_terminal
@@ -322,21 +608,160 @@ async fn handle_kernel_request(
                     .await;
                 return;
             };
-            let signed_caps: Vec<(t::Capability, Vec<u8>)> = capabilities
-                .iter()
-                .map(|cap| {
-                    (
-                        cap.clone(),
-                        keypair
-                            .sign(&rmp_serde::to_vec(&cap).unwrap())
-                            .as_ref()
-                            .to_vec(),
-                    )
-                })
-                .collect();
-            entry.capabilities.extend(signed_caps);
+            let grant = expires.map(|expires| t::CapabilityGrant {
+                expires: Some(expires),
+                delegation: None,
+            });
+            for cap in capabilities {
+                let sig = sign_capability(&keypair, &cap, grant.as_ref());
+                match &grant {
+                    Some(grant) => entry.capability_grants.insert(cap.clone(), grant.clone()),
+                    None => entry.capability_grants.remove(&cap),
+                };
+                entry.capabilities.insert(cap, sig);
+            }
             let _ = persist_state(&our_name, &send_to_loop, process_map).await;
         }
+        t::KernelCommand::DelegateCapability {
+            target,
+            parent,
+            params,
+            expires,
+        } => {
+            let delegator = km.source.process.clone();
+            let child = t::Capability {
+                issuer: parent.issuer.clone(),
+                params,
+            };
+            // a target that already holds the cap unrestricted keeps it that way
+            let already_unrestricted = process_map.get(&target).is_some_and(|entry| {
+                entry.capabilities.contains_key(&child)
+                    && !entry.capability_grants.contains_key(&child)
+            });
+            let response = if !capability_is_valid(process_map, &keypair, &delegator, &parent, 0)
+                || !attenuates(&parent, &child)
+                || !process_map.contains_key(&target)
+                || already_unrestricted
+            {
+                let _ = send_to_terminal
+                    .send(t::Printout {
+                        verbosity: 0,
+                        content: format!(
+                            "kernel: {} can't delegate {} to {} as {}",
+                            delegator, parent, target, child
+                        ),
+                    })
+                    .await;
+                t::KernelResponse::DelegateCapabilityError
+            } else {
+                let delegator_entry = process_map.get(&delegator).unwrap();
+                let parent_signature = delegator_entry.capabilities.get(&parent).unwrap().clone();
+                // a delegated cap can never outlive its parent
+                let parent_expires = delegator_entry
+                    .capability_grants
+                    .get(&parent)
+                    .and_then(|grant| grant.expires);
+                let expires = match (expires, parent_expires) {
+                    (Some(e), Some(p)) => Some(e.min(p)),
+                    (e, p) => e.or(p),
+                };
+                let grant = t::CapabilityGrant {
+                    expires,
+                    delegation: Some(t::Delegation {
+                        delegator,
+                        parent,
+                        parent_signature,
+                    }),
+                };
+                let sig = sign_capability(&keypair, &child, Some(&grant));
+                let entry = process_map.get_mut(&target).unwrap();
+                entry.capability_grants.insert(child.clone(), grant);
+                entry.capabilities.insert(child, sig);
+                let _ = persist_state(&our_name, &send_to_loop, process_map).await;
+                t::KernelResponse::DelegatedCapability
+            };
+            if request.expects_response.is_none() {
+                return;
+            }
+            send_to_loop
+                .send(t::KernelMessage {
+                    id: km.id,
+                    source: t::Address {
+                        node: our_name.clone(),
+                        process: KERNEL_PROCESS_ID.clone(),
+                    },
+                    target: km.rsvp.unwrap_or(km.source),
+                    rsvp: None,
+                    message: t::Message::Response((
+                        t::Response {
+                            inherit: false,
+                            body: serde_json::to_vec(&response).unwrap(),
+                            metadata: None,
+                            capabilities: vec![],
+                        },
+                        None,
+                    )),
+                    lazy_load_blob: None,
+                })
+                .await
+                .expect("event loop: fatal: sender died");
+        }
+        t::KernelCommand::RevokeCapabilities {
+            target,
+            capabilities,
+        } => {
+            let response = if !process_map.contains_key(&target) {
+                let _ = send_to_terminal
+                    .send(t::Printout {
+                        verbosity: 0,
+                        content: format!(
+                            "kernel: no such process {:?} to RevokeCapabilities",
+                            target
+                        ),
+                    })
+                    .await;
+                t::KernelResponse::RevokeCapabilitiesError
+            } else {
+                // only the issuer of a capability, or the kernel, may revoke it
+                let capabilities: Vec<t::Capability> = capabilities
+                    .into_iter()
+                    .filter(|cap| {
+                        km.source.process == *KERNEL_PROCESS_ID
+                            || (cap.issuer.node == our_name
+                                && cap.issuer.process == km.source.process)
+                    })
+                    .collect();
+                if revoke_capabilities(process_map, &target, &capabilities, true) {
+                    let _ = persist_state(&our_name, &send_to_loop, process_map).await;
+                }
+                t::KernelResponse::RevokedCapabilities
+            };
+            if request.expects_response.is_none() {
+                return;
+            }
+            send_to_loop
+                .send(t::KernelMessage {
+                    id: km.id,
+                    source: t::Address {
+                        node: our_name.clone(),
+                        process: KERNEL_PROCESS_ID.clone(),
+                    },
+                    target: km.rsvp.unwrap_or(km.source),
+                    rsvp: None,
+                    message: t::Message::Response((
+                        t::Response {
+                            inherit: false,
+                            body: serde_json::to_vec(&response).unwrap(),
+                            metadata: None,
+                            capabilities: vec![],
+                        },
+                        None,
+                    )),
+                    lazy_load_blob: None,
+                })
+                .await
+                .expect("event loop: fatal: sender died");
+        }
         // send 'run' message to a process that's already been initialized
         t::KernelCommand::RunProcess(process_id) => {
             if let Some(ProcessSender::Userspace(process_sender)) = senders.get(&process_id) {
@@ -420,11 +845,99 @@ async fn handle_kernel_request(
                     .expect("event loop: fatal: sender died");
             }
         }
+        t::KernelCommand::SetProcessLimits { target, limits } => {
+            let response = match process_map.get_mut(&target) {
+                Some(entry) => {
+                    entry.limits = limits;
+                    let _ = persist_state(&our_name, &send_to_loop, process_map).await;
+                    t::KernelResponse::SetProcessLimits
+                }
+                None => {
+                    let _ = send_to_terminal
+                        .send(t::Printout {
+                            verbosity: 0,
+                            content: format!(
+                                "kernel: no such process {:?} to SetProcessLimits",
+                                target
+                            ),
+                        })
+                        .await;
+                    t::KernelResponse::SetProcessLimitsError
+                }
+            };
+            if request.expects_response.is_none() {
+                return;
+            }
+            send_to_loop
+                .send(t::KernelMessage {
+                    id: km.id,
+                    source: t::Address {
+                        node: our_name.clone(),
+                        process: KERNEL_PROCESS_ID.clone(),
+                    },
+                    target: km.rsvp.unwrap_or(km.source),
+                    rsvp: None,
+                    message: t::Message::Response((
+                        t::Response {
+                            inherit: false,
+                            body: serde_json::to_vec(&response).unwrap(),
+                            metadata: None,
+                            capabilities: vec![],
+                        },
+                        None,
+                    )),
+                    lazy_load_blob: None,
+                })
+                .await
+                .expect("event loop: fatal: sender died");
+        }
+        t::KernelCommand::GetProcessLimits(process_id) => {
+            let response = match process_map.get(&process_id) {
+                Some(entry) => t::KernelResponse::GetProcessLimits(entry.limits.clone()),
+                None => t::KernelResponse::GetProcessLimitsError,
+            };
+            if request.expects_response.is_none() {
+                return;
+            }
+            send_to_loop
+                .send(t::KernelMessage {
+                    id: km.id,
+                    source: t::Address {
+                        node: our_name.clone(),
+                        process: KERNEL_PROCESS_ID.clone(),
+                    },
+                    target: km.rsvp.unwrap_or(km.source),
+                    rsvp: None,
+                    message: t::Message::Response((
+                        t::Response {
+                            inherit: false,
+                            body: serde_json::to_vec(&response).unwrap(),
+                            metadata: None,
+                            capabilities: vec![],
+                        },
+                        None,
+                    )),
+                    lazy_load_blob: None,
+                })
+                .await
+                .expect("event loop: fatal: sender died");
+        }
         t::KernelCommand::KillProcess(process_id) => {
             // brutal and savage killing: aborting the task.
             // do not do this to a process if you don't want to risk
             // dropped messages / un-replied-to-requests
             let _ = senders.remove(&process_id);
+            // a process exiting kills itself through the kernel before its
+            // OnExit::Restart, which must still count towards its restart limit.
+            // any other kill is on purpose: it starts the count over and cancels
+            // a restart still waiting out its backoff.
+            if km.source.process != *KERNEL_PROCESS_ID {
+                if let Some((_, restarts)) = restart_tracker.remove(&process_id) {
+                    if let Some(pending) = restarts.pending {
+                        pending.abort();
+                    }
+                }
+            }
             let process_handle = match process_handles.remove(&process_id) {
                 Some(ph) => ph,
                 None => {
@@ -440,6 +953,33 @@ async fn handle_kernel_request(
             process_handle.abort();
             process_map.remove(&process_id);
             let _ = persist_state(&our_name, &send_to_loop, process_map).await;
+            for listener in t::PROCESS_EXIT_LISTENERS.iter() {
+                let _ = send_to_loop
+                    .send(t::KernelMessage {
+                        id: rand::random(),
+                        source: t::Address {
+                            node: our_name.clone(),
+                            process: KERNEL_PROCESS_ID.clone(),
+                        },
+                        target: t::Address {
+                            node: our_name.clone(),
+                            process: listener.clone(),
+                        },
+                        rsvp: None,
+                        message: t::Message::Request(t::Request {
+                            inherit: false,
+                            expects_response: None,
+                            body: serde_json::to_vec(&t::KernelNotification::ProcessExited(
+                                process_id.clone(),
+                            ))
+                            .unwrap(),
+                            metadata: None,
+                            capabilities: vec![],
+                        }),
+                        lazy_load_blob: None,
+                    })
+                    .await;
+            }
             if request.expects_response.is_none() {
                 return;
             }
@@ -535,6 +1075,7 @@ async fn start_process(
     process_map: &mut t::ProcessMap,
     engine: &Engine,
     caps_oracle: t::CapMessageSender,
+    restart_tracker: &RestartTracker,
     process_metadata: &StartProcessMetadata,
 ) -> Result<()> {
     let (send_to_process, recv_in_process) =
@@ -565,6 +1106,9 @@ async fn start_process(
             .unwrap_or(DEFAULT_WIT_VERSION),
         on_exit: process_metadata.persisted.on_exit.clone(),
         public: process_metadata.persisted.public,
+        limits: process_metadata.persisted.limits.clone(),
+        restart_policy: process_metadata.persisted.restart_policy.clone(),
+        spawned_by: process_metadata.persisted.spawned_by.clone(),
     };
     process_handles.insert(
         id.clone(),
@@ -578,6 +1122,7 @@ async fn start_process(
             km_blob_bytes,
             caps_oracle,
             engine.clone(),
+            restart_tracker.clone(),
         )),
     );
 
@@ -627,14 +1172,28 @@ pub async fn kernel(
     home_directory_path: String,
     contract_address: String,
     runtime_extensions: Vec<(t::ProcessId, t::MessageSender, bool)>,
+    vfs_at_rest: Arc<AtRest>,
 ) -> Result<()> {
     let mut config = Config::new();
     config.cache_config_load_default().unwrap();
     config.wasm_backtrace_details(WasmBacktraceDetails::Enable);
     config.wasm_component_model(true);
     config.async_support(true);
+    // epoch interruption lets the kernel preempt processes that never await
+    // a host call, and measure how long they have been executing
+    config.epoch_interruption(true);
     let engine = Engine::new(&config).unwrap();
 
+    let epoch_engine = engine.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_millis(EPOCH_TICK_MS));
+        loop {
+            interval.tick().await;
+            epoch_engine.increment_epoch();
+        }
+    });
+    let restart_tracker: RestartTracker = Arc::new(DashMap::new());
+
     let vfs_path = format!("{}/vfs", home_directory_path);
     tokio::fs::create_dir_all(&vfs_path)
         .await
@@ -659,13 +1218,15 @@ pub async fn kernel(
         // runtime extensions will have a bytes_handle of "", because they have no
         // WASM code saved in filesystem.
         if persisted.on_exit.is_restart() && !persisted.wasm_bytes_handle.is_empty() {
-            // read wasm bytes directly from vfs
+            // read wasm bytes directly from vfs, decrypting them if the vfs
+            // wrote them encrypted
             // start process.
             let wasm_bytes = match tokio::fs::read(format!(
                 "{}/{}",
                 vfs_path, persisted.wasm_bytes_handle
             ))
             .await
+            .and_then(|bytes| vfs_at_rest.open(bytes))
             {
                 Ok(bytes) => bytes,
                 Err(e) => {
@@ -739,6 +1300,7 @@ pub async fn kernel(
             &mut process_map,
             &engine,
             caps_oracle_sender.clone(),
+            &restart_tracker,
             &metadata,
         )
         .await
@@ -869,20 +1431,23 @@ pub async fn kernel(
                 // enforce that if message is directed over the network, process has capability to do so
                 if kernel_message.source.node == our.name
                   && kernel_message.target.node != our.name {
-                    let Some(proc) = process_map.get(&kernel_message.source.process) else {
+                    if !process_map.contains_key(&kernel_message.source.process) {
                         continue
                     };
-                    if !proc.capabilities.contains_key(
+                    if !capability_is_valid(
+                        &process_map,
+                        &keypair,
+                        &kernel_message.source.process,
                         &t::Capability {
                             issuer: t::Address {
                                 node: our.name.clone(),
                                 process: KERNEL_PROCESS_ID.clone(),
                             },
                             params: "\"network\"".into(),
-                        }
+                        },
+                        0,
                     ) {
                         // capabilities are not correct! skip this message.
-                        // TODO: some kind of error thrown back at process?
                         let _ = send_to_terminal.send(
                             t::Printout {
                                 verbosity: 0,
@@ -892,13 +1457,14 @@ pub async fn kernel(
                                 )
                             }
                         ).await;
+                        throw_send_error(&senders, kernel_message, t::SendErrorKind::NoCapability);
                         continue;
                     }
                 } else if kernel_message.source.node != our.name {
                     // note that messaging restrictions only apply to *local* processes:
                     // your process can be messaged by any process remotely if it has
                     // networking capabilities.
-                    let Some(persisted) = process_map.get(&kernel_message.target.process) else {
+                    if !process_map.contains_key(&kernel_message.target.process) {
                         let _ = send_to_terminal
                             .send(t::Printout {
                                 verbosity: 0,
@@ -910,14 +1476,19 @@ pub async fn kernel(
                             .await;
                         continue;
                     };
-                    if !persisted.capabilities.contains_key(
-                            &t::Capability {
-                                issuer: t::Address {
+                    if !capability_is_valid(
+                        &process_map,
+                        &keypair,
+                        &kernel_message.target.process,
+                        &t::Capability {
+                            issuer: t::Address {
                                 node: our.name.clone(),
                                 process: KERNEL_PROCESS_ID.clone(),
                             },
                             params: "\"network\"".into(),
-                    }) {
+                        },
+                        0,
+                    ) {
                         // capabilities are not correct! skip this message.
                         let _ = send_to_terminal.send(
                             t::Printout {
@@ -937,21 +1508,27 @@ pub async fn kernel(
                         && kernel_message.source.process != *STATE_PROCESS_ID
                         && kernel_message.source.process != *VFS_PROCESS_ID
                     {
-                        let Some(persisted_source) = process_map.get(&kernel_message.source.process) else {
+                        if !process_map.contains_key(&kernel_message.source.process) {
                             continue
                         };
                         let Some(persisted_target) = process_map.get(&kernel_message.target.process) else {
+                            throw_send_error(&senders, kernel_message, t::SendErrorKind::ProcessNotFound);
                             continue
                         };
-                        if !persisted_target.public && !persisted_source.capabilities.contains_key(&t::Capability {
+                        if !persisted_target.public && !capability_is_valid(
+                            &process_map,
+                            &keypair,
+                            &kernel_message.source.process,
+                            &t::Capability {
                                 issuer: t::Address {
                                     node: our.name.clone(),
                                     process: kernel_message.target.process.clone(),
                                 },
                                 params: "\"messaging\"".into(),
-                            }) {
+                            },
+                            0,
+                        ) {
                             // capabilities are not correct! skip this message.
-                            // TODO some kind of error thrown back at process?
                             let _ = send_to_terminal.send(
                                 t::Printout {
                                     verbosity: 0,
@@ -961,6 +1538,7 @@ pub async fn kernel(
                                     )
                                 }
                             ).await;
+                            throw_send_error(&senders, kernel_message, t::SendErrorKind::NoCapability);
                             continue;
                         }
                     }
@@ -1000,6 +1578,7 @@ pub async fn kernel(
                         &mut process_map,
                         caps_oracle_sender.clone(),
                         &engine,
+                        &restart_tracker,
                     ).await;
                 } else {
                     // pass message to appropriate runtime module or process
@@ -1036,6 +1615,9 @@ pub async fn kernel(
                                 })
                                 .await
                                 .expect("event loop: fatal: terminal sender died");
+                            if kernel_message.source.node == our.name {
+                                throw_send_error(&senders, kernel_message, t::SendErrorKind::ProcessNotFound);
+                            }
                         }
                     }
                 }
@@ -1044,45 +1626,62 @@ pub async fn kernel(
             Some(cap_message) = caps_oracle_receiver.recv() => {
                 match cap_message {
                     t::CapMessage::Add { on, caps, responder } => {
-                        // insert cap in process map
+                        // insert cap in process map. a restricted cap that is still
+                        // valid keeps its restrictions; one that expired or lost its
+                        // delegation is replaced by the unrestricted cap.
+                        let still_restricted: HashSet<t::Capability> = caps
+                            .iter()
+                            .filter(|cap| capability_is_valid(&process_map, &keypair, &on, cap, 0))
+                            .filter(|cap| process_map[&on].capability_grants.contains_key(cap))
+                            .cloned()
+                            .collect();
                         let Some(entry) = process_map.get_mut(&on) else {
                             let _ = responder.send(false);
                             continue;
                         };
-                        let signed_caps: Vec<(t::Capability, Vec<u8>)> =
-                            caps.iter().map(|cap| (
-                                cap.clone(),
-                                keypair.sign(&rmp_serde::to_vec(&cap).unwrap()).as_ref().to_vec()
-                            )).collect();
-                        entry.capabilities.extend(signed_caps);
+                        for cap in caps.iter().filter(|cap| !still_restricted.contains(cap)) {
+                            entry.capability_grants.remove(cap);
+                            entry.capabilities.insert(cap.clone(), sign_capability(&keypair, cap, None));
+                        }
                         let _ = persist_state(&our.name, &send_to_loop, &process_map).await;
                         let _ = responder.send(true);
                     },
-                    t::CapMessage::_Drop { on, cap, responder } => {
-                        // remove cap from process map
-                        let Some(entry) = process_map.get_mut(&on) else {
+                    t::CapMessage::Drop { on, caps, responder } => {
+                        // remove caps from process map
+                        if !process_map.contains_key(&on) {
                             let _ = responder.send(false);
                             continue;
-                        };
-                        entry.capabilities.remove(&cap);
-                        let _ = persist_state(&our.name, &send_to_loop, &process_map).await;
+                        }
+                        if revoke_capabilities(&mut process_map, &on, &caps, false) {
+                            let _ = persist_state(&our.name, &send_to_loop, &process_map).await;
+                        }
                         let _ = responder.send(true);
                     },
                     t::CapMessage::Has { on, cap, responder } => {
                         // return boolean on responder
+                        let _ = responder.send(
+                            capability_is_valid(&process_map, &keypair, &on, &cap, 0)
+                        );
+                    },
+                    t::CapMessage::GetGrants { on, responder } => {
                         let _ = responder.send(
                             match process_map.get(&on) {
-                                None => false,
-                                Some(p) => p.capabilities.contains_key(&cap),
+                                None => vec![],
+                                Some(p) => p.capability_grants.clone().into_iter().collect(),
                             }
                         );
                     },
                     t::CapMessage::GetAll { on, responder } => {
-                        // return all caps, signed, on responder
+                        // return all valid caps, signed, on responder
                         let _ = responder.send(
                             match process_map.get(&on) {
                                 None => vec![],
-                                Some(p) => p.capabilities.clone().into_iter().collect(),
+                                Some(p) => p
+                                    .capabilities
+                                    .iter()
+                                    .filter(|(cap, _)| capability_is_valid(&process_map, &keypair, &on, cap, 0))
+                                    .map(|(cap, sig)| (cap.clone(), sig.clone()))
+                                    .collect(),
                             }
                         );
                     },
@@ -1101,8 +1700,10 @@ pub async fn kernel(
                                                     .as_ref()
                                                     .to_vec()
                                             ))
-                                        // otherwise, only attach previously saved caps
-                                        // NOTE we don't need to verify the sigs!
+                                        // otherwise, only attach previously saved caps.
+                                        // restricted caps are shared by delegation, not attached
+                                        } else if p.capability_grants.contains_key(cap) {
+                                            None
                                         } else {
                                             match p.capabilities.get(cap) {
                                                 None => None,
@@ -1119,3 +1720,85 @@ pub async fn kernel(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(capabilities: HashMap<t::Capability, Vec<u8>>) -> t::PersistedProcess {
+        t::PersistedProcess {
+            wasm_bytes_handle: String::new(),
+            wit_version: None,
+            on_exit: t::OnExit::None,
+            capabilities,
+            capability_grants: HashMap::new(),
+            public: false,
+            limits: t::ProcessLimits::default(),
+            restart_policy: t::RestartPolicy::default(),
+            spawned_by: None,
+        }
+    }
+
+    #[test]
+    fn child_inherits_expiring_cap_with_its_grant() {
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let keypair = signature::Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+
+        let parent_id = t::ProcessId::new(Some("parent"), "app", "sys");
+        let child_id = t::ProcessId::new(Some("child"), "app", "sys");
+        let cap = |params: &str| t::Capability {
+            issuer: t::Address::new("our", ("store", "app", "sys")),
+            params: params.into(),
+        };
+        let (expiring, expired, unrestricted, missing) =
+            (cap("\"a\""), cap("\"b\""), cap("\"c\""), cap("\"d\""));
+        let expiring_grant = t::CapabilityGrant {
+            expires: Some(now_ms() + 3_600_000),
+            delegation: None,
+        };
+        let expired_grant = t::CapabilityGrant {
+            expires: Some(now_ms() - 1),
+            delegation: None,
+        };
+
+        let mut parent = process(HashMap::from([
+            (
+                expiring.clone(),
+                sign_capability(&keypair, &expiring, Some(&expiring_grant)),
+            ),
+            (
+                expired.clone(),
+                sign_capability(&keypair, &expired, Some(&expired_grant)),
+            ),
+            (
+                unrestricted.clone(),
+                sign_capability(&keypair, &unrestricted, None),
+            ),
+        ]));
+        parent.capability_grants = HashMap::from([
+            (expiring.clone(), expiring_grant.clone()),
+            (expired.clone(), expired_grant),
+        ]);
+        let mut process_map = t::ProcessMap::from([(parent_id.clone(), parent)]);
+
+        let (sig, grant) =
+            inherit_capability(&process_map, &keypair, &parent_id, &expiring).unwrap();
+        assert_eq!(grant.as_ref(), Some(&expiring_grant));
+        let (unrestricted_sig, unrestricted_grant) =
+            inherit_capability(&process_map, &keypair, &parent_id, &unrestricted).unwrap();
+        assert_eq!(unrestricted_grant, None);
+        assert!(inherit_capability(&process_map, &keypair, &parent_id, &expired).is_none());
+        assert!(inherit_capability(&process_map, &keypair, &parent_id, &missing).is_none());
+
+        let mut child = process(HashMap::from([
+            (expiring.clone(), sig),
+            (unrestricted.clone(), unrestricted_sig),
+        ]));
+        child.capability_grants = HashMap::from([(expiring.clone(), expiring_grant)]);
+        child.spawned_by = Some(parent_id);
+        process_map.insert(child_id.clone(), child);
+        assert!(capability_is_valid(&process_map, &keypair, &child_id, &expiring, 0));
+        assert!(capability_is_valid(&process_map, &keypair, &child_id, &unrestricted, 0));
+    }
+}
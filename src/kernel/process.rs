@@ -1,4 +1,5 @@
-use crate::kernel::{ProcessMessageReceiver, ProcessMessageSender};
+use crate::kernel::standard_host_v0;
+use crate::kernel::{ProcessMessageReceiver, ProcessMessageSender, RestartTracker, EPOCH_TICK_MS};
 use crate::types as t;
 use crate::KERNEL_PROCESS_ID;
 use anyhow::Result;
@@ -9,7 +10,7 @@ use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
 use tokio::task::JoinHandle;
 use wasmtime::component::*;
-use wasmtime::{Engine, Store};
+use wasmtime::{Engine, ResourceLimiter, Store, UpdateDeadline};
 use wasmtime_wasi::preview2::{pipe::MemoryOutputPipe, Table, WasiCtx, WasiCtxBuilder, WasiView};
 
 bindgen!({
@@ -30,14 +31,48 @@ pub struct ProcessState {
     pub contexts: HashMap<u64, (t::ProcessContext, JoinHandle<()>)>,
     pub message_queue: VecDeque<Result<t::KernelMessage, t::WrappedSendError>>,
     pub caps_oracle: t::CapMessageSender,
+    /// epoch ticks spent executing since the last message was delivered
+    pub cpu_ticks: u64,
 }
 
 pub struct ProcessWasi {
     pub process: ProcessState,
+    limiter: ProcessLimiter,
     table: Table,
     wasi: WasiCtx,
 }
 
+/// enforces the memory and table limits a process was initialized with.
+/// growth past a limit fails, which traps the process.
+struct ProcessLimiter {
+    limits: t::ProcessLimits,
+}
+
+impl ResourceLimiter for ProcessLimiter {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> Result<bool> {
+        match self.limits.max_memory_bytes {
+            Some(max) if desired > max => Err(anyhow::anyhow!(
+                "memory limit exceeded: wanted {desired} bytes, limit is {max}"
+            )),
+            _ => Ok(true),
+        }
+    }
+
+    fn table_growing(&mut self, _current: u32, desired: u32, _maximum: Option<u32>) -> Result<bool> {
+        match self.limits.max_table_elements {
+            Some(max) if desired > max => Err(anyhow::anyhow!(
+                "table limit exceeded: wanted {desired} elements, limit is {max}"
+            )),
+            _ => Ok(true),
+        }
+    }
+}
+
 impl WasiView for ProcessWasi {
     fn table(&self) -> &Table {
         &self.table
@@ -333,6 +368,8 @@ impl ProcessState {
         &mut self,
         res: Result<t::KernelMessage, t::WrappedSendError>,
     ) -> Result<(wit::Address, wit::Message), (wit::SendError, Option<wit::Context>)> {
+        // every delivered message resets the CPU budget of the process
+        self.cpu_ticks = 0;
         let (context, km) = match res {
             Ok(km) => match &km.message {
                 t::Message::Request(_) => {
@@ -452,6 +489,7 @@ pub async fn make_process_loop(
     wasm_bytes: Vec<u8>,
     caps_oracle: t::CapMessageSender,
     engine: Engine,
+    restart_tracker: RestartTracker,
 ) -> Result<()> {
     // before process can be instantiated, need to await 'run' message from kernel
     let mut pre_boot_queue = Vec::<Result<t::KernelMessage, t::WrappedSendError>>::new();
@@ -492,6 +530,8 @@ pub async fn make_process_loop(
 
     let mut linker = Linker::new(&engine);
     Process::add_to_linker(&mut linker, |state: &mut ProcessWasi| state).unwrap();
+    // processes built against the published 0.5.0 import it instead
+    standard_host_v0::Process::add_to_linker(&mut linker, |state: &mut ProcessWasi| state).unwrap();
 
     let table = Table::new();
     let wasi_stderr = MemoryOutputPipe::new(STACK_TRACE_SIZE);
@@ -514,11 +554,33 @@ pub async fn make_process_loop(
                 contexts: HashMap::new(),
                 message_queue: VecDeque::new(),
                 caps_oracle: caps_oracle.clone(),
+                cpu_ticks: 0,
+            },
+            limiter: ProcessLimiter {
+                limits: metadata.limits.clone(),
             },
             table,
             wasi,
         },
     );
+    store.limiter(|state| &mut state.limiter);
+
+    // called once per epoch tick while the process is executing wasm.
+    // a process over its CPU budget is trapped; otherwise it yields back
+    // to the runtime so that no single process can starve the others.
+    store.epoch_deadline_callback(|mut ctx| {
+        let process = &mut ctx.data_mut().process;
+        process.cpu_ticks += 1;
+        if let Some(max) = process.metadata.limits.max_cpu_ms_per_message {
+            if process.cpu_ticks * EPOCH_TICK_MS > max {
+                return Err(anyhow::anyhow!(
+                    "CPU limit exceeded: ran for over {max}ms without receiving a message"
+                ));
+            }
+        }
+        Ok(UpdateDeadline::Yield(1))
+    });
+    store.set_epoch_deadline(1);
 
     let (bindings, _bindings) =
         match Process::instantiate_async(&mut store, &component, &linker).await {
@@ -551,15 +613,15 @@ pub async fn make_process_loop(
                 .await;
             false
         }
-        Err(_) => {
+        Err(e) => {
             let stderr = wasi_stderr.contents().into();
             let stderr = String::from_utf8(stderr)?;
             let _ = send_to_terminal
                 .send(t::Printout {
                     verbosity: 0,
                     content: format!(
-                        "\x1b[38;5;196mprocess {} ended with error:\x1b[0m\n{}",
-                        metadata.our.process, stderr,
+                        "\x1b[38;5;196mprocess {} ended with error:\x1b[0m\n{}\n{:?}",
+                        metadata.our.process, stderr, e,
                     ),
                 })
                 .await;
@@ -592,6 +654,14 @@ pub async fn make_process_loop(
             params: c.0.params.clone(),
         })
         .collect();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let _ = caps_oracle
+        .send(t::CapMessage::GetGrants {
+            on: metadata.our.process.clone(),
+            responder: tx,
+        })
+        .await;
+    let capability_grants = rx.await?;
 
     // send message to tell main kernel loop to remove handler
     send_to_loop
@@ -626,27 +696,111 @@ pub async fn make_process_loop(
         }
         // if restart, tell ourselves to init the app again, with same capabilities
         t::OnExit::Restart => {
-            if is_error {
+            let policy = metadata.restart_policy.clone();
+            if !policy.should_restart(is_error) {
                 let _ = send_to_terminal
                     .send(t::Printout {
                         verbosity: 0,
                         content: format!(
-                            "skipping OnExit::Restart for process {} due to crash",
-                            metadata.our.process
+                            "skipping OnExit::Restart for process {} due to restart mode {:?} ({})",
+                            metadata.our.process,
+                            policy.mode,
+                            if is_error { "crashed" } else { "exited" },
                         ),
                     })
                     .await;
-            } else {
+                return Ok(());
+            }
+            // count restarts within the policy window, dropping older ones
+            let now = std::time::Instant::now();
+            let window = std::time::Duration::from_millis(policy.window_ms);
+            let attempt = {
+                let mut restarts = restart_tracker
+                    .entry(metadata.our.process.clone())
+                    .or_default();
+                while let Some(oldest) = restarts.history.front() {
+                    if now.duration_since(*oldest) > window {
+                        restarts.history.pop_front();
+                    } else {
+                        break;
+                    }
+                }
+                restarts.history.push_back(now);
+                restarts.history.len() as u32
+            };
+            if attempt > policy.max_restarts {
+                restart_tracker.remove(&metadata.our.process);
                 let _ = send_to_terminal
                     .send(t::Printout {
-                        verbosity: 1,
+                        verbosity: 0,
                         content: format!(
-                            "firing OnExit::Restart for process {}",
-                            metadata.our.process
+                            "\x1b[38;5;196mprocess {} exceeded {} restarts in {}ms, giving up\x1b[0m",
+                            metadata.our.process, policy.max_restarts, policy.window_ms,
                         ),
                     })
                     .await;
-                send_to_loop
+                if let Some(supervisor) = policy.supervisor {
+                    send_to_loop
+                        .send(t::KernelMessage {
+                            id: rand::random(),
+                            source: our_kernel.clone(),
+                            target: t::Address {
+                                node: metadata.our.node.clone(),
+                                process: supervisor,
+                            },
+                            rsvp: None,
+                            message: t::Message::Request(t::Request {
+                                inherit: false,
+                                expects_response: None,
+                                body: serde_json::to_vec(
+                                    &t::SupervisorNotification::RestartLimitReached {
+                                        process: metadata.our.process.clone(),
+                                        restarts: policy.max_restarts,
+                                        window_ms: policy.window_ms,
+                                        crashed: is_error,
+                                    },
+                                )
+                                .unwrap(),
+                                metadata: None,
+                                capabilities: vec![],
+                            }),
+                            lazy_load_blob: None,
+                        })
+                        .await?;
+                }
+                return Ok(());
+            }
+            let backoff = policy.backoff(attempt);
+            let _ = send_to_terminal
+                .send(t::Printout {
+                    verbosity: 1,
+                    content: format!(
+                        "firing OnExit::Restart for process {} in {}ms (attempt {})",
+                        metadata.our.process,
+                        backoff.as_millis(),
+                        attempt,
+                    ),
+                })
+                .await;
+            // the kernel aborts this task once it handles KillProcess, so the
+            // delayed restart must be driven from a task of its own, which the
+            // kernel aborts in turn if the process is killed in the meantime
+            let initialize = t::KernelCommand::InitializeProcess {
+                id: metadata.our.process.clone(),
+                wasm_bytes_handle: metadata.wasm_bytes_handle,
+                wit_version: Some(metadata.wit_version),
+                on_exit: metadata.on_exit,
+                initial_capabilities,
+                public: metadata.public,
+                limits: metadata.limits,
+                restart_policy: policy,
+                spawned_by: metadata.spawned_by,
+                capability_grants,
+            };
+            let process_id = metadata.our.process.clone();
+            let pending = tokio::spawn(async move {
+                tokio::time::sleep(backoff).await;
+                let _ = send_to_loop
                     .send(t::KernelMessage {
                         id: rand::random(),
                         source: our_kernel.clone(),
@@ -655,15 +809,7 @@ pub async fn make_process_loop(
                         message: t::Message::Request(t::Request {
                             inherit: false,
                             expects_response: None,
-                            body: serde_json::to_vec(&t::KernelCommand::InitializeProcess {
-                                id: metadata.our.process.clone(),
-                                wasm_bytes_handle: metadata.wasm_bytes_handle,
-                                wit_version: Some(metadata.wit_version),
-                                on_exit: metadata.on_exit,
-                                initial_capabilities,
-                                public: metadata.public,
-                            })
-                            .unwrap(),
+                            body: serde_json::to_vec(&initialize).unwrap(),
                             metadata: None,
                             capabilities: vec![],
                         }),
@@ -672,8 +818,8 @@ pub async fn make_process_loop(
                             bytes: wasm_bytes,
                         }),
                     })
-                    .await?;
-                send_to_loop
+                    .await;
+                let _ = send_to_loop
                     .send(t::KernelMessage {
                         id: rand::random(),
                         source: our_kernel.clone(),
@@ -682,16 +828,19 @@ pub async fn make_process_loop(
                         message: t::Message::Request(t::Request {
                             inherit: false,
                             expects_response: None,
-                            body: serde_json::to_vec(&t::KernelCommand::RunProcess(
-                                metadata.our.process.clone(),
-                            ))
-                            .unwrap(),
+                            body: serde_json::to_vec(&t::KernelCommand::RunProcess(process_id))
+                                .unwrap(),
                             metadata: None,
                             capabilities: vec![],
                         }),
                         lazy_load_blob: None,
                     })
-                    .await?;
+                    .await;
+            });
+            // a process killed on purpose since it exited has no entry left
+            match restart_tracker.get_mut(&metadata.our.process) {
+                Some(mut restarts) => restarts.pending = Some(pending),
+                None => pending.abort(),
             }
         }
         // if requests, fire them
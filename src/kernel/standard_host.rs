@@ -266,6 +266,11 @@ impl StandardHost for process::ProcessWasi {
                         })
                         .collect(),
                     public,
+                    // children are bound by the same limits as their parent
+                    limits: self.process.metadata.limits.clone(),
+                    restart_policy: t::RestartPolicy::default(),
+                    spawned_by: Some(self.process.metadata.our.process.clone()),
+                    capability_grants: vec![],
                 })
                 .unwrap(),
                 metadata: None,
@@ -396,6 +401,24 @@ impl StandardHost for process::ProcessWasi {
         Ok(())
     }
 
+    async fn drop_capabilities(&mut self, caps: Vec<wit::Capability>) -> Result<()> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let _ = self
+            .process
+            .caps_oracle
+            .send(t::CapMessage::Drop {
+                on: self.process.metadata.our.process.clone(),
+                caps: caps
+                    .iter()
+                    .map(|cap| t::de_wit_capability(cap.clone()).0)
+                    .collect(),
+                responder: tx,
+            })
+            .await?;
+        let _ = rx.await?;
+        Ok(())
+    }
+
     async fn our_capabilities(&mut self) -> Result<Vec<wit::Capability>> {
         let (tx, rx) = tokio::sync::oneshot::channel();
         let _ = self
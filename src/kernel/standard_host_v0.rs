@@ -0,0 +1,349 @@
+use crate::kernel::process;
+use crate::kernel::process::kinode::process::standard as wit;
+use crate::kernel::process::StandardHost;
+use anyhow::Result;
+use kinode::process::standard as v0;
+use kinode::process::standard::Host as StandardHostV0;
+
+wasmtime::component::bindgen!({
+    path: "wit/v0.5.0",
+    world: "process",
+    async: true,
+});
+
+fn convert<T, U: From<T>>(items: Vec<T>) -> Vec<U> {
+    items.into_iter().map(U::from).collect()
+}
+
+impl From<v0::ProcessId> for wit::ProcessId {
+    fn from(id: v0::ProcessId) -> Self {
+        wit::ProcessId {
+            process_name: id.process_name,
+            package_name: id.package_name,
+            publisher_node: id.publisher_node,
+        }
+    }
+}
+
+impl From<wit::ProcessId> for v0::ProcessId {
+    fn from(id: wit::ProcessId) -> Self {
+        v0::ProcessId {
+            process_name: id.process_name,
+            package_name: id.package_name,
+            publisher_node: id.publisher_node,
+        }
+    }
+}
+
+impl From<v0::Address> for wit::Address {
+    fn from(address: v0::Address) -> Self {
+        wit::Address {
+            node: address.node,
+            process: address.process.into(),
+        }
+    }
+}
+
+impl From<wit::Address> for v0::Address {
+    fn from(address: wit::Address) -> Self {
+        v0::Address {
+            node: address.node,
+            process: address.process.into(),
+        }
+    }
+}
+
+impl From<v0::LazyLoadBlob> for wit::LazyLoadBlob {
+    fn from(blob: v0::LazyLoadBlob) -> Self {
+        wit::LazyLoadBlob {
+            mime: blob.mime,
+            bytes: blob.bytes,
+        }
+    }
+}
+
+impl From<wit::LazyLoadBlob> for v0::LazyLoadBlob {
+    fn from(blob: wit::LazyLoadBlob) -> Self {
+        v0::LazyLoadBlob {
+            mime: blob.mime,
+            bytes: blob.bytes,
+        }
+    }
+}
+
+impl From<v0::Capability> for wit::Capability {
+    fn from(cap: v0::Capability) -> Self {
+        wit::Capability {
+            issuer: cap.issuer.into(),
+            params: cap.params,
+        }
+    }
+}
+
+impl From<wit::Capability> for v0::Capability {
+    fn from(cap: wit::Capability) -> Self {
+        v0::Capability {
+            issuer: cap.issuer.into(),
+            params: cap.params,
+        }
+    }
+}
+
+impl From<v0::Request> for wit::Request {
+    fn from(request: v0::Request) -> Self {
+        wit::Request {
+            inherit: request.inherit,
+            expects_response: request.expects_response,
+            body: request.body,
+            metadata: request.metadata,
+            capabilities: convert(request.capabilities),
+        }
+    }
+}
+
+impl From<wit::Request> for v0::Request {
+    fn from(request: wit::Request) -> Self {
+        v0::Request {
+            inherit: request.inherit,
+            expects_response: request.expects_response,
+            body: request.body,
+            metadata: request.metadata,
+            capabilities: convert(request.capabilities),
+        }
+    }
+}
+
+impl From<v0::Response> for wit::Response {
+    fn from(response: v0::Response) -> Self {
+        wit::Response {
+            inherit: response.inherit,
+            body: response.body,
+            metadata: response.metadata,
+            capabilities: convert(response.capabilities),
+        }
+    }
+}
+
+impl From<wit::Response> for v0::Response {
+    fn from(response: wit::Response) -> Self {
+        v0::Response {
+            inherit: response.inherit,
+            body: response.body,
+            metadata: response.metadata,
+            capabilities: convert(response.capabilities),
+        }
+    }
+}
+
+impl From<wit::Message> for v0::Message {
+    fn from(message: wit::Message) -> Self {
+        match message {
+            wit::Message::Request(request) => v0::Message::Request(request.into()),
+            wit::Message::Response((response, context)) => {
+                v0::Message::Response((response.into(), context))
+            }
+        }
+    }
+}
+
+impl From<v0::OnExit> for wit::OnExit {
+    fn from(on_exit: v0::OnExit) -> Self {
+        match on_exit {
+            v0::OnExit::None => wit::OnExit::None,
+            v0::OnExit::Restart => wit::OnExit::Restart,
+            v0::OnExit::Requests(requests) => wit::OnExit::Requests(
+                requests
+                    .into_iter()
+                    .map(|(address, request, blob)| {
+                        (address.into(), request.into(), blob.map(Into::into))
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl From<wit::OnExit> for v0::OnExit {
+    fn from(on_exit: wit::OnExit) -> Self {
+        match on_exit {
+            wit::OnExit::None => v0::OnExit::None,
+            wit::OnExit::Restart => v0::OnExit::Restart,
+            wit::OnExit::Requests(requests) => v0::OnExit::Requests(
+                requests
+                    .into_iter()
+                    .map(|(address, request, blob)| {
+                        (address.into(), request.into(), blob.map(Into::into))
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// 0.5.0 can't say why the kernel dropped a message: a missing process is
+/// reported as unreachable, and a missing capability as a timeout, which is
+/// what these processes saw before the kernel answered dropped messages.
+impl From<wit::SendErrorKind> for v0::SendErrorKind {
+    fn from(kind: wit::SendErrorKind) -> Self {
+        match kind {
+            wit::SendErrorKind::Offline => v0::SendErrorKind::Offline,
+            wit::SendErrorKind::Timeout => v0::SendErrorKind::Timeout,
+            wit::SendErrorKind::NoCapability => v0::SendErrorKind::Timeout,
+            wit::SendErrorKind::ProcessNotFound => v0::SendErrorKind::Offline,
+        }
+    }
+}
+
+impl From<wit::SendError> for v0::SendError {
+    fn from(error: wit::SendError) -> Self {
+        v0::SendError {
+            kind: error.kind.into(),
+            message: error.message.into(),
+            lazy_load_blob: error.lazy_load_blob.map(Into::into),
+        }
+    }
+}
+
+impl From<wit::SpawnError> for v0::SpawnError {
+    fn from(error: wit::SpawnError) -> Self {
+        match error {
+            wit::SpawnError::NameTaken => v0::SpawnError::NameTaken,
+            wit::SpawnError::NoFileAtPath => v0::SpawnError::NoFileAtPath,
+        }
+    }
+}
+
+///
+/// serve kinode:process@0.5.0 to the processes built against it, through the
+/// 0.6.0 API: every call is translated and handed to `StandardHost`.
+///
+#[async_trait::async_trait]
+impl StandardHostV0 for process::ProcessWasi {
+    async fn print_to_terminal(&mut self, verbosity: u8, content: String) -> Result<()> {
+        StandardHost::print_to_terminal(self, verbosity, content).await
+    }
+
+    async fn set_on_exit(&mut self, on_exit: v0::OnExit) -> Result<()> {
+        StandardHost::set_on_exit(self, on_exit.into()).await
+    }
+
+    async fn get_on_exit(&mut self) -> Result<v0::OnExit> {
+        Ok(StandardHost::get_on_exit(self).await?.into())
+    }
+
+    async fn get_state(&mut self) -> Result<Option<Vec<u8>>> {
+        StandardHost::get_state(self).await
+    }
+
+    async fn set_state(&mut self, bytes: Vec<u8>) -> Result<()> {
+        StandardHost::set_state(self, bytes).await
+    }
+
+    async fn clear_state(&mut self) -> Result<()> {
+        StandardHost::clear_state(self).await
+    }
+
+    async fn spawn(
+        &mut self,
+        name: Option<String>,
+        wasm_path: String,
+        on_exit: v0::OnExit,
+        request_capabilities: Vec<v0::Capability>,
+        grant_capabilities: Vec<v0::ProcessId>,
+        public: bool,
+    ) -> Result<Result<v0::ProcessId, v0::SpawnError>> {
+        Ok(StandardHost::spawn(
+            self,
+            name,
+            wasm_path,
+            on_exit.into(),
+            convert(request_capabilities),
+            convert(grant_capabilities),
+            public,
+        )
+        .await?
+        .map(Into::into)
+        .map_err(Into::into))
+    }
+
+    async fn save_capabilities(&mut self, caps: Vec<v0::Capability>) -> Result<()> {
+        StandardHost::save_capabilities(self, convert(caps)).await
+    }
+
+    async fn our_capabilities(&mut self) -> Result<Vec<v0::Capability>> {
+        Ok(convert(StandardHost::our_capabilities(self).await?))
+    }
+
+    async fn receive(
+        &mut self,
+    ) -> Result<Result<(v0::Address, v0::Message), (v0::SendError, Option<v0::Context>)>> {
+        Ok(StandardHost::receive(self)
+            .await?
+            .map(|(source, message)| (source.into(), message.into()))
+            .map_err(|(error, context)| (error.into(), context)))
+    }
+
+    async fn get_blob(&mut self) -> Result<Option<v0::LazyLoadBlob>> {
+        Ok(StandardHost::get_blob(self).await?.map(Into::into))
+    }
+
+    async fn send_request(
+        &mut self,
+        target: v0::Address,
+        request: v0::Request,
+        context: Option<v0::Context>,
+        blob: Option<v0::LazyLoadBlob>,
+    ) -> Result<()> {
+        StandardHost::send_request(
+            self,
+            target.into(),
+            request.into(),
+            context,
+            blob.map(Into::into),
+        )
+        .await
+    }
+
+    async fn send_requests(
+        &mut self,
+        requests: Vec<(
+            v0::Address,
+            v0::Request,
+            Option<v0::Context>,
+            Option<v0::LazyLoadBlob>,
+        )>,
+    ) -> Result<()> {
+        let requests = requests
+            .into_iter()
+            .map(|(target, request, context, blob)| {
+                (target.into(), request.into(), context, blob.map(Into::into))
+            })
+            .collect();
+        StandardHost::send_requests(self, requests).await
+    }
+
+    async fn send_response(
+        &mut self,
+        response: v0::Response,
+        blob: Option<v0::LazyLoadBlob>,
+    ) -> Result<()> {
+        StandardHost::send_response(self, response.into(), blob.map(Into::into)).await
+    }
+
+    async fn send_and_await_response(
+        &mut self,
+        target: v0::Address,
+        request: v0::Request,
+        blob: Option<v0::LazyLoadBlob>,
+    ) -> Result<Result<(v0::Address, v0::Message), v0::SendError>> {
+        Ok(StandardHost::send_and_await_response(
+            self,
+            target.into(),
+            request.into(),
+            blob.map(Into::into),
+        )
+        .await?
+        .map(|(source, message)| (source.into(), message.into()))
+        .map_err(Into::into))
+    }
+}
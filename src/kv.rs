@@ -1,14 +1,50 @@
 use anyhow::Result;
 use dashmap::DashMap;
-// use rocksdb::checkpoint::Checkpoint;
-use rocksdb::OptimisticTransactionDB;
+use rocksdb::checkpoint::Checkpoint;
+use rocksdb::{
+    BoundColumnFamily, Direction, IteratorMode, MultiThreaded, OptimisticTransactionDB, Options,
+    WriteBatchWithTransaction,
+};
 use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::fs;
 use tokio::sync::Mutex;
 
+use crate::encryption::AtRest;
+use crate::quota::{QuotaExceeded, StorageQuotas};
 use crate::types::*;
 
+/// dbs are opened multi-threaded, so tables (column families) can be
+/// created and dropped while the db is shared between requests.
+pub type KvDb = OptimisticTransactionDB<MultiThreaded>;
+
+/// open dbs, shared with snapshots so that they can be checkpointed
+pub type OpenKvs = Arc<DashMap<(PackageId, String), Arc<KvDb>>>;
+
+/// rocksdb's default column family, used when a request names no table
+const DEFAULT_TABLE: &str = "default";
+
+/// internal table mapping keys set with a `ttl_ms` to their unix-ms expiry
+const TTL_TABLE: &str = "__ttl";
+
+/// how often expired keys are physically removed from open dbs
+const TTL_SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// number of keys removed for having expired in each db, since boot
+type ExpiredCounts = Arc<DashMap<(PackageId, String), u64>>;
+
+/// processes watching each db for changes, registered with `KvAction::Watch`
+type Watchers = Arc<DashMap<(PackageId, String), Vec<KvWatcher>>>;
+
+struct KvWatcher {
+    address: Address,
+    table: Option<String>,
+    prefix: Vec<u8>,
+}
+
+/// With `at_rest` enabled, values are encrypted before they are written. Keys
+/// (and their ttls) stay in plaintext, so that key ranges can still be iterated.
 pub async fn kv(
     our_node: String,
     send_to_loop: MessageSender,
@@ -16,6 +52,10 @@ pub async fn kv(
     mut recv_from_loop: MessageReceiver,
     send_to_caps_oracle: CapMessageSender,
     home_directory_path: String,
+    snapshot_lock: SnapshotLock,
+    at_rest: Arc<AtRest>,
+    quotas: Arc<StorageQuotas>,
+    open_kvs: OpenKvs,
 ) -> anyhow::Result<()> {
     let kv_path = format!("{}/kv", &home_directory_path);
 
@@ -23,9 +63,69 @@ pub async fn kv(
         panic!("failed creating kv dir! {:?}", e);
     }
 
-    let open_kvs: Arc<DashMap<(PackageId, String), OptimisticTransactionDB>> =
-        Arc::new(DashMap::new());
-    let txs: Arc<DashMap<u64, Vec<(KvAction, Option<Vec<u8>>)>>> = Arc::new(DashMap::new());
+    let txs: Arc<DashMap<u64, Vec<(Option<String>, KvAction, Option<Vec<u8>>)>>> = Arc::new(DashMap::new());
+    let watchers: Watchers = Arc::new(DashMap::new());
+    let expired: ExpiredCounts = Arc::new(DashMap::new());
+
+    // periodically remove expired keys, so that keys which are never read again
+    // don't linger forever
+    let sweep_kvs = open_kvs.clone();
+    let sweep_expired = expired.clone();
+    let sweep_watchers = watchers.clone();
+    let sweep_our_node = our_node.clone();
+    let sweep_send_to_loop = send_to_loop.clone();
+    let sweep_snapshot_lock = snapshot_lock.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(TTL_SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            // sweeps write to the dbs, so they wait out snapshots like requests do
+            let _snapshot_guard = sweep_snapshot_lock.read().await;
+            // take the dbs out of the map, so no shard stays locked during a sweep
+            let dbs: Vec<((PackageId, String), Arc<KvDb>)> = sweep_kvs
+                .iter()
+                .map(|db_ref| (db_ref.key().clone(), db_ref.value().clone()))
+                .collect();
+            for (db_key, db) in dbs {
+                let removed =
+                    match tokio::task::spawn_blocking(move || sweep_expired_keys(&db)).await {
+                        Ok(Ok(removed)) => removed,
+                        Ok(Err(e)) => {
+                            println!("kv: failed to sweep expired keys: {e}\r");
+                            continue;
+                        }
+                        Err(e) => {
+                            println!("kv: expired key sweep panicked: {e}\r");
+                            continue;
+                        }
+                    };
+                if removed.is_empty() {
+                    continue;
+                }
+                *sweep_expired.entry(db_key.clone()).or_default() += removed.len() as u64;
+                let changes = removed
+                    .into_iter()
+                    .map(|(table, key)| KvChange {
+                        package_id: db_key.0.clone(),
+                        db: db_key.1.clone(),
+                        table,
+                        key,
+                        existed: true,
+                        exists: false,
+                        tx_id: None,
+                    })
+                    .collect();
+                notify_watchers(
+                    &sweep_our_node,
+                    &sweep_watchers,
+                    &db_key,
+                    changes,
+                    &sweep_send_to_loop,
+                )
+                .await;
+            }
+        }
+    });
 
     let mut process_queues: HashMap<ProcessId, Arc<Mutex<VecDeque<KernelMessage>>>> =
         HashMap::new();
@@ -42,6 +142,20 @@ pub async fn kv(
                     continue;
                 }
 
+                if km.source.process == *KERNEL_PROCESS_ID {
+                    if let Message::Request(Request { ref body, .. }) = km.message {
+                        if let Ok(KernelNotification::ProcessExited(process)) =
+                            serde_json::from_slice(body)
+                        {
+                            watchers.retain(|_, db_watchers| {
+                                db_watchers.retain(|w| w.address.process != process);
+                                !db_watchers.is_empty()
+                            });
+                            continue;
+                        }
+                    }
+                }
+
                 let queue = process_queues
                     .entry(km.source.process.clone())
                     .or_insert_with(|| Arc::new(Mutex::new(VecDeque::new())))
@@ -59,20 +173,32 @@ pub async fn kv(
                 let send_to_loop = send_to_loop.clone();
                 let open_kvs = open_kvs.clone();
                 let txs = txs.clone();
+                let watchers = watchers.clone();
+                let expired = expired.clone();
                 let kv_path = kv_path.clone();
+                let at_rest = at_rest.clone();
+                let quotas = quotas.clone();
+
+                let snapshot_lock = snapshot_lock.clone();
 
                 tokio::spawn(async move {
                     let mut queue_lock = queue.lock().await;
                     if let Some(km) = queue_lock.pop_front() {
+                        // keep snapshots from copying the store mid-request
+                        let _snapshot_guard = snapshot_lock.read().await;
                         if let Err(e) = handle_request(
                             our_node.clone(),
                             km.clone(),
                             open_kvs.clone(),
                             txs.clone(),
+                            watchers.clone(),
+                            expired.clone(),
                             send_to_loop.clone(),
                             send_to_terminal.clone(),
                             send_to_caps_oracle.clone(),
                             kv_path.clone(),
+                            at_rest.clone(),
+                            quotas.clone(),
                         )
                         .await
                         {
@@ -90,12 +216,16 @@ pub async fn kv(
 async fn handle_request(
     our_node: String,
     km: KernelMessage,
-    open_kvs: Arc<DashMap<(PackageId, String), OptimisticTransactionDB>>,
-    txs: Arc<DashMap<u64, Vec<(KvAction, Option<Vec<u8>>)>>>,
+    open_kvs: OpenKvs,
+    txs: Arc<DashMap<u64, Vec<(Option<String>, KvAction, Option<Vec<u8>>)>>>,
+    watchers: Watchers,
+    expired: ExpiredCounts,
     send_to_loop: MessageSender,
     send_to_terminal: PrintSender,
     send_to_caps_oracle: CapMessageSender,
     kv_path: String,
+    at_rest: Arc<AtRest>,
+    quotas: Arc<StorageQuotas>,
 ) -> Result<(), KvError> {
     let KernelMessage {
         id,
@@ -136,6 +266,36 @@ async fn handle_request(
     )
     .await?;
 
+    // sets are charged against the package's quota up front; a set in a tx is
+    // charged when it is made, not when the tx is committed
+    let incoming = match &request.action {
+        KvAction::Set { key, .. } => key.len() + blob.as_ref().map_or(0, |b| b.bytes.len()),
+        KvAction::WriteBatch { ops } => {
+            let keys_len: usize = ops
+                .iter()
+                .map(|op| match op {
+                    KvBatchOp::Set { key } => key.len(),
+                    KvBatchOp::Delete { .. } => 0,
+                })
+                .sum();
+            let values_len: usize = batch_values(&blob)?.iter().map(|v| v.len()).sum();
+            keys_len + values_len
+        }
+        _ => 0,
+    };
+    quotas.reserve(&request.package_id, incoming as u64).await?;
+
+    // changes to report to watchers once the request has been handled
+    let db_key = (request.package_id.clone(), request.db.clone());
+    let mut changes: Vec<KvChange> = Vec::new();
+    let is_watched = |table: &Option<String>, key: &[u8]| {
+        watchers.get(&db_key).map_or(false, |watchers| {
+            watchers
+                .iter()
+                .any(|w| &w.table == table && key.starts_with(&w.prefix))
+        })
+    };
+
     let (body, bytes) = match &request.action {
         KvAction::Open => {
             // handled in check_caps.
@@ -143,20 +303,38 @@ async fn handle_request(
         }
         KvAction::RemoveDb => {
             // handled in check_caps.
+            watchers.remove(&db_key);
             (serde_json::to_vec(&KvResponse::Ok).unwrap(), None)
         }
         KvAction::Get { key } => {
-            let db = match open_kvs.get(&(request.package_id, request.db)) {
+            let db = match open_kvs.get(&db_key) {
                 None => {
                     return Err(KvError::NoDb);
                 }
                 Some(db) => db,
             };
 
-            match db.get(key) {
+            let cf = table_handle(&db, &request.table)?;
+            if remove_if_expired(&db, &request.table, key)? {
+                *expired.entry(db_key.clone()).or_default() += 1;
+                if is_watched(&request.table, key) {
+                    // the Get fails, so report the removal now rather than at the end
+                    let change = change(&request, key, true, false, None);
+                    let our_node = our_node.clone();
+                    let watchers = watchers.clone();
+                    let db_key = db_key.clone();
+                    let send_to_loop = send_to_loop.clone();
+                    tokio::spawn(async move {
+                        notify_watchers(&our_node, &watchers, &db_key, vec![change], &send_to_loop)
+                            .await;
+                    });
+                }
+                return Err(KvError::KeyNotFound);
+            }
+            match db.get_cf(&cf, key) {
                 Ok(Some(value)) => (
                     serde_json::to_vec(&KvResponse::Get { key: key.to_vec() }).unwrap(),
-                    Some(value),
+                    Some(at_rest.open(value)?),
                 ),
                 Ok(None) => {
                     return Err(KvError::KeyNotFound);
@@ -177,8 +355,8 @@ async fn handle_request(
                 None,
             )
         }
-        KvAction::Set { key, tx_id } => {
-            let db = match open_kvs.get(&(request.package_id, request.db)) {
+        KvAction::Set { key, tx_id, ttl_ms } => {
+            let db = match open_kvs.get(&db_key) {
                 None => {
                     return Err(KvError::NoDb);
                 }
@@ -190,9 +368,27 @@ async fn handle_request(
                 });
             };
 
+            let cf = table_handle(&db, &request.table)?;
             match tx_id {
                 None => {
-                    db.put(key, blob.bytes)?;
+                    let watched = is_watched(&request.table, key);
+                    let existed = watched && db.get_cf(&cf, key)?.is_some();
+                    // the value and its expiry (or lack thereof) are written together
+                    let ttl_cf = table_handle(&db, &Some(TTL_TABLE.into()))?;
+                    let mut batch = WriteBatchWithTransaction::<true>::default();
+                    batch.put_cf(&cf, key, at_rest.seal(&blob.bytes));
+                    match ttl_ms {
+                        Some(ttl_ms) => batch.put_cf(
+                            &ttl_cf,
+                            ttl_key(&request.table, key),
+                            expiry_after(*ttl_ms),
+                        ),
+                        None => batch.delete_cf(&ttl_cf, ttl_key(&request.table, key)),
+                    }
+                    db.write(batch)?;
+                    if watched {
+                        changes.push(change(&request, key, existed, true, None));
+                    }
                 }
                 Some(tx_id) => {
                     let mut tx = match txs.get_mut(tx_id) {
@@ -201,22 +397,36 @@ async fn handle_request(
                         }
                         Some(tx) => tx,
                     };
-                    tx.push((request.action.clone(), Some(blob.bytes)));
+                    tx.push((
+                        request.table.clone(),
+                        request.action.clone(),
+                        Some(at_rest.seal(&blob.bytes)),
+                    ));
                 }
             }
 
             (serde_json::to_vec(&KvResponse::Ok).unwrap(), None)
         }
         KvAction::Delete { key, tx_id } => {
-            let db = match open_kvs.get(&(request.package_id, request.db)) {
+            let db = match open_kvs.get(&db_key) {
                 None => {
                     return Err(KvError::NoDb);
                 }
                 Some(db) => db,
             };
+            let cf = table_handle(&db, &request.table)?;
             match tx_id {
                 None => {
-                    db.delete(key)?;
+                    let watched = is_watched(&request.table, key);
+                    let existed = watched && db.get_cf(&cf, key)?.is_some();
+                    let ttl_cf = table_handle(&db, &Some(TTL_TABLE.into()))?;
+                    let mut batch = WriteBatchWithTransaction::<true>::default();
+                    batch.delete_cf(&cf, key);
+                    batch.delete_cf(&ttl_cf, ttl_key(&request.table, key));
+                    db.write(batch)?;
+                    if watched {
+                        changes.push(change(&request, key, existed, false, None));
+                    }
                 }
                 Some(tx_id) => {
                     let mut tx = match txs.get_mut(tx_id) {
@@ -225,13 +435,13 @@ async fn handle_request(
                         }
                         Some(tx) => tx,
                     };
-                    tx.push((request.action.clone(), None));
+                    tx.push((request.table.clone(), request.action.clone(), None));
                 }
             }
             (serde_json::to_vec(&KvResponse::Ok).unwrap(), None)
         }
         KvAction::Commit { tx_id } => {
-            let db = match open_kvs.get(&(request.package_id, request.db)) {
+            let db = match open_kvs.get(&db_key) {
                 None => {
                     return Err(KvError::NoDb);
                 }
@@ -246,22 +456,58 @@ async fn handle_request(
             };
             let tx = db.transaction();
 
-            for (action, blob) in txs {
-                match action {
-                    KvAction::Set { key, .. } => {
+            // each op targets the table it was sent with
+            let mut tx_changes = Vec::new();
+            let ttl_cf = table_handle(&db, &Some(TTL_TABLE.into()))?;
+            for (table, action, blob) in txs {
+                let cf = table_handle(&db, &table)?;
+                let (key, exists) = match &action {
+                    KvAction::Set { key, .. } => (key, true),
+                    KvAction::Delete { key, .. } => (key, false),
+                    _ => continue,
+                };
+                let watched = is_watched(&table, key);
+                let existed = watched && tx.get_cf(&cf, key)?.is_some();
+                match &action {
+                    KvAction::Set { key, ttl_ms, .. } => {
                         if let Some(blob) = blob {
-                            tx.put(&key, &blob)?;
+                            tx.put_cf(&cf, key, &blob)?;
+                            // ttls count from the commit, not from the Set
+                            match ttl_ms {
+                                Some(ttl_ms) => tx.put_cf(
+                                    &ttl_cf,
+                                    ttl_key(&table, key),
+                                    expiry_after(*ttl_ms),
+                                )?,
+                                None => tx.delete_cf(&ttl_cf, ttl_key(&table, key))?,
+                            }
                         }
                     }
                     KvAction::Delete { key, .. } => {
-                        tx.delete(&key)?;
+                        tx.delete_cf(&cf, key)?;
+                        tx.delete_cf(&ttl_cf, ttl_key(&table, key))?;
                     }
                     _ => {}
                 }
+                if watched {
+                    tx_changes.push(KvChange {
+                        package_id: request.package_id.clone(),
+                        db: request.db.clone(),
+                        table: table.clone(),
+                        key: key.clone(),
+                        existed,
+                        exists,
+                        tx_id: Some(*tx_id),
+                    });
+                }
             }
 
             match tx.commit() {
-                Ok(_) => (serde_json::to_vec(&KvResponse::Ok).unwrap(), None),
+                Ok(_) => {
+                    // only report changes once they are committed
+                    changes.extend(tx_changes);
+                    (serde_json::to_vec(&KvResponse::Ok).unwrap(), None)
+                }
                 Err(e) => {
                     return Err(KvError::RocksDBError {
                         action: request.action.to_string(),
@@ -270,6 +516,217 @@ async fn handle_request(
                 }
             }
         }
+        KvAction::Iterate {
+            prefix,
+            start,
+            end,
+            limit,
+            reverse,
+        } => {
+            let db = match open_kvs.get(&db_key) {
+                None => {
+                    return Err(KvError::NoDb);
+                }
+                Some(db) => db,
+            };
+            let mut page = iterate(
+                &db,
+                &request.table,
+                prefix.as_deref(),
+                start.as_deref(),
+                end.as_deref(),
+                *limit,
+                *reverse,
+            )?;
+            for (_, value) in page.entries.iter_mut() {
+                *value = at_rest.open(std::mem::take(value))?;
+            }
+            (
+                serde_json::to_vec(&KvResponse::Iterate).unwrap(),
+                Some(serde_json::to_vec(&page).unwrap()),
+            )
+        }
+        KvAction::MultiGet { keys } => {
+            let db = match open_kvs.get(&db_key) {
+                None => {
+                    return Err(KvError::NoDb);
+                }
+                Some(db) => db,
+            };
+            let cf = table_handle(&db, &request.table)?;
+            let mut values = db
+                .multi_get_cf(keys.iter().map(|key| (&cf, key)))
+                .into_iter()
+                .collect::<Result<Vec<Option<Vec<u8>>>, _>>()?;
+            // expired keys read as missing until they are swept
+            for (key, value) in keys.iter().zip(values.iter_mut()) {
+                if value.is_some() && has_expired(&db, &request.table, key)? {
+                    *value = None;
+                }
+            }
+            let values = values
+                .into_iter()
+                .map(|value| value.map(|value| at_rest.open(value)).transpose())
+                .collect::<Result<Vec<Option<Vec<u8>>>, _>>()?;
+            (
+                serde_json::to_vec(&KvResponse::MultiGet).unwrap(),
+                Some(bincode::serialize(&values).unwrap()),
+            )
+        }
+        KvAction::WriteBatch { ops } => {
+            let db = match open_kvs.get(&db_key) {
+                None => {
+                    return Err(KvError::NoDb);
+                }
+                Some(db) => db,
+            };
+            let values = batch_values(&blob)?;
+            let cf = table_handle(&db, &request.table)?;
+            let ttl_cf = table_handle(&db, &Some(TTL_TABLE.into()))?;
+            let mut values = values.into_iter();
+            let mut batch = WriteBatchWithTransaction::<true>::default();
+            let mut batch_changes = Vec::new();
+            // whether each key written so far exists once the batch is applied,
+            // so a later op on the same key reports the earlier one's result
+            let mut written: HashMap<&[u8], bool> = HashMap::new();
+            for op in ops {
+                let (key, exists) = match op {
+                    KvBatchOp::Set { key } => {
+                        let Some(value) = values.next() else {
+                            return Err(KvError::InputError {
+                                error: "WriteBatch blob has fewer values than Set ops".into(),
+                            });
+                        };
+                        batch.put_cf(&cf, key, at_rest.seal(value));
+                        (key, true)
+                    }
+                    KvBatchOp::Delete { key } => {
+                        batch.delete_cf(&cf, key);
+                        (key, false)
+                    }
+                };
+                // batched sets never expire
+                batch.delete_cf(&ttl_cf, ttl_key(&request.table, key));
+                if is_watched(&request.table, key) {
+                    let existed = match written.get(key.as_slice()) {
+                        Some(existed) => *existed,
+                        None => db.get_cf(&cf, key)?.is_some(),
+                    };
+                    batch_changes.push(change(&request, key, existed, exists, None));
+                }
+                written.insert(key.as_slice(), exists);
+            }
+            if values.next().is_some() {
+                return Err(KvError::InputError {
+                    error: "WriteBatch blob has more values than Set ops".into(),
+                });
+            }
+            db.write(batch)?;
+            changes.extend(batch_changes);
+            (serde_json::to_vec(&KvResponse::Ok).unwrap(), None)
+        }
+        KvAction::CreateTable { name } => {
+            let db = match open_kvs.get(&db_key) {
+                None => {
+                    return Err(KvError::NoDb);
+                }
+                Some(db) => db,
+            };
+            if name.starts_with("__") {
+                return Err(KvError::InputError {
+                    error: "table names starting with __ are reserved".into(),
+                });
+            }
+            if name == DEFAULT_TABLE || db.cf_handle(name).is_some() {
+                return Err(KvError::InputError {
+                    error: format!("table {name} already exists"),
+                });
+            }
+            db.create_cf(name, &Options::default())?;
+            (serde_json::to_vec(&KvResponse::Ok).unwrap(), None)
+        }
+        KvAction::DropTable { name } => {
+            let db = match open_kvs.get(&db_key) {
+                None => {
+                    return Err(KvError::NoDb);
+                }
+                Some(db) => db,
+            };
+            if name == DEFAULT_TABLE || name.starts_with("__") {
+                return Err(KvError::InputError {
+                    error: format!("can't drop table {name}"),
+                });
+            }
+            if db.cf_handle(name).is_none() {
+                return Err(KvError::NoTable { table: name.clone() });
+            }
+            db.drop_cf(name)?;
+            (serde_json::to_vec(&KvResponse::Ok).unwrap(), None)
+        }
+        KvAction::ListTables => {
+            if !open_kvs.contains_key(&db_key) {
+                return Err(KvError::NoDb);
+            }
+            let db_path = format!("{}/{}/{}", kv_path, request.package_id, request.db);
+            let tables: Vec<String> = KvDb::list_cf(&Options::default(), &db_path)?
+                .into_iter()
+                .filter(|name| name != DEFAULT_TABLE && !name.starts_with("__"))
+                .collect();
+            (
+                serde_json::to_vec(&KvResponse::ListTables { tables }).unwrap(),
+                None,
+            )
+        }
+        KvAction::Stats => {
+            let db = match open_kvs.get(&db_key) {
+                None => {
+                    return Err(KvError::NoDb);
+                }
+                Some(db) => db,
+            };
+            let ttl_cf = table_handle(&db, &Some(TTL_TABLE.into()))?;
+            let mut ttl_keys = 0;
+            for item in db.iterator_cf(&ttl_cf, IteratorMode::Start) {
+                item?;
+                ttl_keys += 1;
+            }
+            let expired = expired.get(&db_key).map(|count| *count).unwrap_or(0);
+            (
+                serde_json::to_vec(&KvResponse::Stats { ttl_keys, expired }).unwrap(),
+                None,
+            )
+        }
+        KvAction::Watch { prefix } => {
+            if !open_kvs.contains_key(&db_key) {
+                return Err(KvError::NoDb);
+            }
+            let address = Address {
+                node: our_node.clone(),
+                process: source.process.clone(),
+            };
+            let mut db_watchers = watchers.entry(db_key.clone()).or_default();
+            if !db_watchers
+                .iter()
+                .any(|w| w.address == address && w.table == request.table && &w.prefix == prefix)
+            {
+                db_watchers.push(KvWatcher {
+                    address,
+                    table: request.table.clone(),
+                    prefix: prefix.clone(),
+                });
+            }
+            (serde_json::to_vec(&KvResponse::Ok).unwrap(), None)
+        }
+        KvAction::Unwatch { prefix } => {
+            if let Some(mut db_watchers) = watchers.get_mut(&db_key) {
+                db_watchers.retain(|w| {
+                    w.address.process != source.process
+                        || w.table != request.table
+                        || &w.prefix != prefix
+                });
+            }
+            (serde_json::to_vec(&KvResponse::Ok).unwrap(), None)
+        }
         KvAction::Backup => {
             // looping through open dbs and flushing their memtables
             for db_ref in open_kvs.iter() {
@@ -323,13 +780,80 @@ async fn handle_request(
             .unwrap();
     }
 
+    notify_watchers(&our_node, &watchers, &db_key, changes, &send_to_loop).await;
+
     Ok(())
 }
 
+fn change(
+    request: &KvRequest,
+    key: &[u8],
+    existed: bool,
+    exists: bool,
+    tx_id: Option<u64>,
+) -> KvChange {
+    KvChange {
+        package_id: request.package_id.clone(),
+        db: request.db.clone(),
+        table: request.table.clone(),
+        key: key.to_vec(),
+        existed,
+        exists,
+        tx_id,
+    }
+}
+
+/// send each change as a Request to every process watching a matching prefix
+async fn notify_watchers(
+    our_node: &str,
+    watchers: &Watchers,
+    db_key: &(PackageId, String),
+    changes: Vec<KvChange>,
+    send_to_loop: &MessageSender,
+) {
+    if changes.is_empty() {
+        return;
+    }
+    let targets: Vec<(Address, Vec<u8>)> = match watchers.get(db_key) {
+        None => return,
+        Some(watchers) => changes
+            .iter()
+            .flat_map(|change| {
+                watchers
+                    .iter()
+                    .filter(|w| w.table == change.table && change.key.starts_with(&w.prefix))
+                    .map(|w| (w.address.clone(), serde_json::to_vec(change).unwrap()))
+                    .collect::<Vec<_>>()
+            })
+            .collect(),
+    };
+    for (target, body) in targets {
+        let _ = send_to_loop
+            .send(KernelMessage {
+                id: rand::random(),
+                source: Address {
+                    node: our_node.to_string(),
+                    process: KV_PROCESS_ID.clone(),
+                },
+                target,
+                rsvp: None,
+                message: Message::Request(Request {
+                    inherit: false,
+                    expects_response: None,
+                    body,
+                    metadata: None,
+                    capabilities: vec![],
+                }),
+                lazy_load_blob: None,
+            })
+            .await;
+    }
+}
+
 async fn check_caps(
     our_node: String,
     source: Address,
-    open_kvs: Arc<DashMap<(PackageId, String), OptimisticTransactionDB>>,
+    open_kvs: OpenKvs,
     mut send_to_caps_oracle: CapMessageSender,
     request: &KvRequest,
     kv_path: String,
@@ -340,6 +864,9 @@ async fn check_caps(
     match &request.action {
         KvAction::Delete { .. }
         | KvAction::Set { .. }
+        | KvAction::WriteBatch { .. }
+        | KvAction::CreateTable { .. }
+        | KvAction::DropTable { .. }
         | KvAction::BeginTx
         | KvAction::Commit { .. } => {
             send_to_caps_oracle
@@ -367,7 +894,13 @@ async fn check_caps(
             }
             Ok(())
         }
-        KvAction::Get { .. } => {
+        KvAction::Get { .. }
+        | KvAction::Iterate { .. }
+        | KvAction::MultiGet { .. }
+        | KvAction::ListTables
+        | KvAction::Watch { .. }
+        | KvAction::Unwatch { .. }
+        | KvAction::Stats => {
             send_to_caps_oracle
                 .send(CapMessage::Has {
                     on: source.process.clone(),
@@ -424,9 +957,16 @@ async fn check_caps(
             let db_path = format!("{}/{}/{}", kv_path, request.package_id, request.db);
             fs::create_dir_all(&db_path).await?;
 
-            let db = OptimisticTransactionDB::open_default(&db_path)?;
+            // every table in the db must be opened along with it
+            let mut opts = Options::default();
+            opts.create_if_missing(true);
+            let tables = KvDb::list_cf(&opts, &db_path).unwrap_or_default();
+            let db = KvDb::open_cf(&opts, &db_path, tables)?;
+            if db.cf_handle(TTL_TABLE).is_none() {
+                db.create_cf(TTL_TABLE, &Options::default())?;
+            }
 
-            open_kvs.insert((request.package_id.clone(), request.db.clone()), db);
+            open_kvs.insert((request.package_id.clone(), request.db.clone()), Arc::new(db));
             Ok(())
         }
         KvAction::RemoveDb { .. } => {
@@ -446,6 +986,222 @@ async fn check_caps(
     }
 }
 
+/// checkpoint every open db into `dest`, under the same `{package_id}/{db}`
+/// path it has under `kv_path`, returning the paths of the dbs checkpointed.
+/// blocks, so must be run off the async runtime.
+pub fn checkpoint_open_dbs(
+    open_kvs: &OpenKvs,
+    kv_path: &Path,
+    dest: &Path,
+) -> Result<Vec<PathBuf>, KvError> {
+    let dbs: Vec<((PackageId, String), Arc<KvDb>)> = open_kvs
+        .iter()
+        .map(|db_ref| (db_ref.key().clone(), db_ref.value().clone()))
+        .collect();
+    let mut checkpointed = Vec::new();
+    for ((package_id, db_name), db) in dbs {
+        let relative = Path::new(&package_id.to_string()).join(&db_name);
+        let checkpoint_path = dest.join(&relative);
+        if let Some(parent) = checkpoint_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        Checkpoint::new(&*db)?.create_checkpoint(&checkpoint_path)?;
+        checkpointed.push(kv_path.join(relative));
+    }
+    Ok(checkpointed)
+}
+
+/// walk the db in key order (or reverse), collecting up to `limit` entries
+/// in the range given by `prefix`, `start` (inclusive) and `end` (exclusive).
+/// expired keys are skipped.
+fn iterate(
+    db: &KvDb,
+    table: &Option<String>,
+    prefix: Option<&[u8]>,
+    start: Option<&[u8]>,
+    end: Option<&[u8]>,
+    limit: Option<u64>,
+    reverse: bool,
+) -> Result<KvIteratePage, KvError> {
+    // narrow the range to the prefix
+    let lower: Option<Vec<u8>> = match (prefix, start) {
+        (Some(p), Some(s)) => Some(p.max(s).to_vec()),
+        (p, s) => p.or(s).map(|b| b.to_vec()),
+    };
+    let upper: Option<Vec<u8>> = match (prefix.and_then(prefix_upper_bound), end) {
+        (Some(p), Some(e)) => Some(p.min(e.to_vec())),
+        (p, e) => p.or(e.map(|b| b.to_vec())),
+    };
+
+    let mode = match (reverse, &lower, &upper) {
+        (false, Some(lower), _) => IteratorMode::From(lower.as_slice(), Direction::Forward),
+        (false, None, _) => IteratorMode::Start,
+        (true, _, Some(upper)) => IteratorMode::From(upper.as_slice(), Direction::Reverse),
+        (true, _, None) => IteratorMode::End,
+    };
+    let in_range = |key: &[u8]| {
+        lower.as_deref().map_or(true, |lower| key >= lower)
+            && upper.as_deref().map_or(true, |upper| key < upper)
+    };
+
+    let limit = limit.unwrap_or(u64::MAX).max(1);
+    let mut entries: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+    let mut cursor = None;
+    let cf = table_handle(db, table)?;
+    for item in db.iterator_cf(&cf, mode) {
+        let (key, value) = item?;
+        if !in_range(&key) {
+            // a reverse seek lands on `upper` itself if it exists, which is excluded
+            if reverse && upper.as_deref().map_or(false, |upper| &*key >= upper) {
+                continue;
+            }
+            break;
+        }
+        if entries.len() as u64 >= limit {
+            // forward pages resume from the next key, reverse pages end before the last one
+            cursor = Some(if reverse {
+                entries.last().map(|(k, _)| k.clone()).unwrap()
+            } else {
+                key.to_vec()
+            });
+            break;
+        }
+        if has_expired(db, table, &key)? {
+            continue;
+        }
+        entries.push((key.to_vec(), value.to_vec()));
+    }
+    Ok(KvIteratePage { entries, cursor })
+}
+
+/// the column family for `table`, or the default one if no table is given
+fn table_handle<'a>(
+    db: &'a KvDb,
+    table: &Option<String>,
+) -> Result<Arc<BoundColumnFamily<'a>>, KvError> {
+    let name = table.as_deref().unwrap_or(DEFAULT_TABLE);
+    db.cf_handle(name).ok_or_else(|| KvError::NoTable {
+        table: name.to_string(),
+    })
+}
+
+/// entries in the ttl table are keyed by table name (length-prefixed) and key
+/// the value of each `Set` op of a WriteBatch, from its bincode-serialized blob
+fn batch_values(blob: &Option<LazyLoadBlob>) -> Result<Vec<&[u8]>, KvError> {
+    match blob {
+        None => Ok(vec![]),
+        Some(blob) => bincode::deserialize(&blob.bytes).map_err(|e| KvError::InputError {
+            error: format!("WriteBatch blob must be a bincode-serialized list of values: {e}"),
+        }),
+    }
+}
+
+fn ttl_key(table: &Option<String>, key: &[u8]) -> Vec<u8> {
+    let table = table.as_deref().unwrap_or(DEFAULT_TABLE).as_bytes();
+    let mut ttl_key = (table.len() as u32).to_be_bytes().to_vec();
+    ttl_key.extend_from_slice(table);
+    ttl_key.extend_from_slice(key);
+    ttl_key
+}
+
+fn parse_ttl_key(ttl_key: &[u8]) -> Option<(String, &[u8])> {
+    let len = u32::from_be_bytes(ttl_key.get(..4)?.try_into().ok()?) as usize;
+    let table = String::from_utf8(ttl_key.get(4..4 + len)?.to_vec()).ok()?;
+    Some((table, &ttl_key[4 + len..]))
+}
+
+fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+fn expiry_after(ttl_ms: u64) -> [u8; 8] {
+    now_ms().saturating_add(ttl_ms).to_be_bytes()
+}
+
+fn is_expired(expiry: &[u8]) -> bool {
+    match <[u8; 8]>::try_from(expiry) {
+        Ok(expiry) => u64::from_be_bytes(expiry) <= now_ms(),
+        Err(_) => false,
+    }
+}
+
+/// whether `key` has a ttl which has passed
+fn has_expired(db: &KvDb, table: &Option<String>, key: &[u8]) -> Result<bool, KvError> {
+    let ttl_cf = table_handle(db, &Some(TTL_TABLE.into()))?;
+    Ok(db
+        .get_cf(&ttl_cf, ttl_key(table, key))?
+        .map_or(false, |expiry| is_expired(&expiry)))
+}
+
+/// delete `key` and its ttl if it has expired, returning whether it was removed.
+/// the expiry is read in the same transaction as the delete, so a Set that
+/// renews the key in between makes the transaction fail and the key stays.
+fn remove_if_expired(db: &KvDb, table: &Option<String>, key: &[u8]) -> Result<bool, KvError> {
+    let ttl_cf = table_handle(db, &Some(TTL_TABLE.into()))?;
+    let ttl_key = ttl_key(table, key);
+    let tx = db.transaction();
+    match tx.get_for_update_cf(&ttl_cf, &ttl_key, true)? {
+        Some(expiry) if is_expired(&expiry) => {}
+        _ => return Ok(false),
+    }
+    // the table may have been dropped since
+    if let Ok(cf) = table_handle(db, table) {
+        tx.delete_cf(&cf, key)?;
+    }
+    tx.delete_cf(&ttl_cf, &ttl_key)?;
+    match tx.commit() {
+        Ok(()) => Ok(true),
+        Err(e) if matches!(e.kind(), rocksdb::ErrorKind::Busy | rocksdb::ErrorKind::TryAgain) => {
+            Ok(false)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// delete every expired key in the db, returning the table and key of each
+/// one removed. blocks, so must be run off the async runtime.
+fn sweep_expired_keys(db: &KvDb) -> Result<Vec<(Option<String>, Vec<u8>)>, KvError> {
+    let Some(ttl_cf) = db.cf_handle(TTL_TABLE) else {
+        return Ok(vec![]);
+    };
+    let mut expired = Vec::new();
+    for item in db.iterator_cf(&ttl_cf, IteratorMode::Start) {
+        let (ttl_key, expiry) = item?;
+        if !is_expired(&expiry) {
+            continue;
+        }
+        match parse_ttl_key(&ttl_key) {
+            Some((table, key)) => {
+                let table = (table != DEFAULT_TABLE).then_some(table);
+                expired.push((table, key.to_vec()));
+            }
+            None => db.delete_cf(&ttl_cf, &ttl_key)?,
+        }
+    }
+    let mut removed = Vec::new();
+    for (table, key) in expired {
+        if remove_if_expired(db, &table, &key)? {
+            removed.push((table, key));
+        }
+    }
+    Ok(removed)
+}
+
+/// the smallest key greater than every key starting with `prefix`, if any
+fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut upper = prefix.to_vec();
+    while let Some(last) = upper.pop() {
+        if last < u8::MAX {
+            upper.push(last + 1);
+            return Some(upper);
+        }
+    }
+    None
+}
+
 async fn add_capability(
     kind: &str,
     db: &str,
@@ -519,6 +1275,15 @@ impl From<tokio::sync::mpsc::error::SendError<CapMessage>> for KvError {
     }
 }
 
+impl From<QuotaExceeded> for KvError {
+    fn from(err: QuotaExceeded) -> Self {
+        KvError::QuotaExceeded {
+            quota: err.quota,
+            usage: err.usage,
+        }
+    }
+}
+
 impl From<std::io::Error> for KvError {
     fn from(err: std::io::Error) -> Self {
         KvError::IOError {
@@ -11,6 +11,7 @@ use tokio::{fs, time::timeout};
 #[cfg(feature = "simulation-mode")]
 use ring::{rand::SystemRandom, signature, signature::KeyPair};
 
+mod encryption;
 mod eth;
 mod graphdb;
 mod http;
@@ -18,7 +19,9 @@ mod kernel;
 mod keygen;
 mod kv;
 mod net;
+mod quota;
 mod register;
+mod snapshot;
 mod sqlite;
 mod state;
 mod terminal;
@@ -108,6 +111,11 @@ async fn main() {
             arg!(--testnet "If set, use Sepolia testnet")
                 .default_value("false")
                 .value_parser(value_parser!(bool)),
+        )
+        .arg(arg!(--restore <ARCHIVE> "Restore this node from a snapshot archive"))
+        .arg(
+            arg!(--"encrypt-at-rest" "Encrypt vfs files, kv values and sqlite dbs on disk")
+                .action(clap::ArgAction::SetTrue),
         );
 
     #[cfg(not(feature = "simulation-mode"))]
@@ -136,6 +144,10 @@ async fn main() {
         None => (8080, false),
     };
     let on_testnet = *matches.get_one::<bool>("testnet").unwrap();
+    let encrypt_at_rest = matches.get_flag("encrypt-at-rest");
+    if encrypt_at_rest && !cfg!(feature = "sqlcipher") {
+        println!("built without the sqlcipher feature: sqlite dbs will not be encrypted at rest\r");
+    }
     let contract_address = if on_testnet {
         register::KNS_SEPOLIA_ADDRESS
     } else {
@@ -162,6 +174,27 @@ async fn main() {
     }
     println!("home at {}\r", home_directory_path);
 
+    // a snapshot carries the node's keyfile alongside its encrypted data: put the
+    // keyfile in place so that login can decrypt it, then restore the data once
+    // the file key is known.
+    let restore_archive: Option<String> = match matches.get_one::<String>("restore") {
+        None => None,
+        Some(archive_path) => {
+            if fs::metadata(format!("{}/kernel", home_directory_path))
+                .await
+                .is_ok()
+            {
+                panic!("refusing to restore into a home directory that already holds a node");
+            }
+            let keyfile =
+                snapshot::open_snapshot(archive_path).expect("failed to open snapshot archive");
+            fs::write(format!("{}/.keys", home_directory_path), keyfile)
+                .await
+                .unwrap();
+            Some(archive_path.clone())
+        }
+    };
+
     // kernel receives system messages via this channel, all other modules send messages
     let (kernel_message_sender, kernel_message_receiver): (MessageSender, MessageReceiver) =
         mpsc::channel(EVENT_LOOP_CHANNEL_CAPACITY);
@@ -413,6 +446,33 @@ async fn main() {
      *
      *  if any of these modules fail, the program exits with an error.
      */
+    if let Some(archive_path) = restore_archive {
+        snapshot::restore_snapshot(
+            home_directory_path,
+            &archive_path,
+            &decoded_keyfile.file_key,
+        )
+        .expect("failed to restore snapshot");
+        println!("restored node from snapshot\r");
+    }
+
+    let snapshot_lock: SnapshotLock = Arc::new(tokio::sync::RwLock::new(()));
+    let snapshot_stores = snapshot::SnapshotStores::default();
+
+    // data already encrypted at rest stays readable without the flag
+    let at_rest = |context: &[u8]| {
+        Arc::new(encryption::AtRest::new(
+            &decoded_keyfile.file_key,
+            context,
+            encrypt_at_rest,
+        ))
+    };
+    let (vfs_at_rest, kv_at_rest, sqlite_at_rest) = (
+        at_rest(b"kinode vfs"),
+        at_rest(b"kinode kv"),
+        at_rest(b"kinode sqlite"),
+    );
+
     let networking_keypair_arc = Arc::new(decoded_keyfile.networking_keypair);
 
     let (kernel_process_map, db) = state::load_state(
@@ -424,6 +484,11 @@ async fn main() {
     .await
     .expect("state load failed!");
 
+    let quotas = Arc::new(quota::StorageQuotas::new(
+        home_directory_path.clone(),
+        state::load_quotas(&db),
+    ));
+
     let mut tasks = tokio::task::JoinSet::<Result<()>>::new();
     tasks.spawn(kernel::kernel(
         our.clone(),
@@ -440,6 +505,7 @@ async fn main() {
         home_directory_path.clone(),
         contract_address.to_string(),
         runtime_extensions,
+        vfs_at_rest.clone(),
     ));
     #[cfg(not(feature = "simulation-mode"))]
     tasks.spawn(net::networking(
@@ -468,8 +534,13 @@ async fn main() {
         kernel_message_sender.clone(),
         print_sender.clone(),
         state_receiver,
+        caps_oracle_sender.clone(),
         db,
         home_directory_path.clone(),
+        decoded_keyfile.file_key.clone(),
+        snapshot_lock.clone(),
+        quotas.clone(),
+        snapshot_stores.clone(),
     ));
     tasks.spawn(kv::kv(
         our.name.clone(),
@@ -478,6 +549,10 @@ async fn main() {
         kv_receiver,
         caps_oracle_sender.clone(),
         home_directory_path.clone(),
+        snapshot_lock.clone(),
+        kv_at_rest,
+        quotas.clone(),
+        snapshot_stores.kv.clone(),
     ));
     tasks.spawn(sqlite::sqlite(
         our.name.clone(),
@@ -486,6 +561,10 @@ async fn main() {
         sqlite_receiver,
         caps_oracle_sender.clone(),
         home_directory_path.clone(),
+        snapshot_lock.clone(),
+        sqlite_at_rest,
+        quotas.clone(),
+        snapshot_stores.sqlite.clone(),
     ));
     tasks.spawn(graphdb::gdb(
         our.name.clone(),
@@ -494,7 +573,11 @@ async fn main() {
         gdb_receiver,
         caps_oracle_sender.clone(),
         home_directory_path.clone(),
+        snapshot_lock.clone(),
+        snapshot_stores.graphdb.clone(),
+        quotas.clone(),
     ));
+    tasks.spawn(quota::refresh_usage(quotas.clone()));
     tasks.spawn(http::server::http_server(
         our.name.clone(),
         http_server_port,
@@ -515,6 +598,8 @@ async fn main() {
         kernel_message_sender.clone(),
         timer_service_receiver,
         print_sender.clone(),
+        home_directory_path.clone(),
+        snapshot_lock.clone(),
     ));
     #[cfg(not(feature = "simulation-mode"))]
     tasks.spawn(eth::provider::provider(
@@ -541,6 +626,9 @@ async fn main() {
         vfs_message_receiver,
         caps_oracle_sender.clone(),
         home_directory_path.clone(),
+        snapshot_lock.clone(),
+        vfs_at_rest,
+        quotas.clone(),
     ));
     // if a runtime task exits, try to recover it,
     // unless it was terminal signaling a quit
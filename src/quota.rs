@@ -0,0 +1,306 @@
+use dashmap::DashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::types::*;
+
+/// stores that keep each package's data under `{home}/{store}/{package_id}`.
+/// graphdb keeps every package's data in one store, so its share is charged.
+const DIR_STORES: [&str; 3] = ["vfs", "kv", "sqlite"];
+
+/// where the bytes each package has written to graphdb are kept, since they
+/// can't be measured on disk: `{home}/{QUOTA_DIR}/{GRAPHDB_USAGE_FILE}`
+pub const QUOTA_DIR: &str = "quota";
+const GRAPHDB_USAGE_FILE: &str = "graphdb_usage";
+
+/// how often the usage of packages with a quota is measured again, picking up
+/// space freed by deletes and correcting what writes were charged
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A write that would take a package over its storage quota.
+pub struct QuotaExceeded {
+    pub quota: u64,
+    pub usage: u64,
+}
+
+/// a package's bytes in use, as last measured plus what has been charged since
+struct Usage {
+    bytes: u64,
+    /// charged since the measurement in progress began, so that it can be
+    /// added to the measurement once done
+    charged_since: u64,
+}
+
+/// Per-package limits on the bytes stored across vfs, kv, sqlite and graphdb,
+/// set when a package is installed and persisted by the state module.
+///
+/// A package's usage is measured on disk the first time it writes, then kept
+/// up to date by charging each write against it, so that checking a write costs
+/// no more than a map lookup. `refresh_usage` measures it again in the
+/// background, since deletes aren't credited and charges are estimates.
+///
+/// graphdb's store holds every package's namespace, so a package's share of
+/// it is the total it has been charged for graphdb writes instead, saved to
+/// `{QUOTA_DIR}/{GRAPHDB_USAGE_FILE}` by `refresh_usage`.
+pub struct StorageQuotas {
+    home_directory_path: String,
+    limits: DashMap<PackageId, u64>,
+    usage: DashMap<PackageId, Usage>,
+    graphdb: DashMap<PackageId, u64>,
+    /// whether `graphdb` changed since it was last saved
+    graphdb_dirty: AtomicBool,
+}
+
+impl StorageQuotas {
+    pub fn new(home_directory_path: String, limits: HashMap<PackageId, u64>) -> Self {
+        let graphdb: HashMap<PackageId, u64> = std::fs::read(
+            Path::new(&home_directory_path)
+                .join(QUOTA_DIR)
+                .join(GRAPHDB_USAGE_FILE),
+        )
+        .ok()
+        .and_then(|bytes| bincode::deserialize(&bytes).ok())
+        .unwrap_or_default();
+        StorageQuotas {
+            home_directory_path,
+            limits: limits.into_iter().collect(),
+            usage: DashMap::new(),
+            graphdb: graphdb.into_iter().collect(),
+            graphdb_dirty: AtomicBool::new(false),
+        }
+    }
+
+    pub fn limits(&self) -> HashMap<PackageId, u64> {
+        self.limits
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect()
+    }
+
+    /// set a package's quota in bytes, or remove it with `None`
+    pub fn set(&self, package_id: PackageId, quota: Option<u64>) {
+        match quota {
+            Some(quota) => {
+                self.limits.insert(package_id, quota);
+            }
+            None => {
+                self.usage.remove(&package_id);
+                self.limits.remove(&package_id);
+            }
+        }
+    }
+
+    /// measure a package's usage, if it has a quota and hasn't been measured yet
+    pub async fn prepare(&self, package_id: &PackageId) {
+        if !self.limits.contains_key(package_id) || self.usage.contains_key(package_id) {
+            return;
+        }
+        let bytes = self.measure(package_id, None).await.total;
+        self.usage.entry(package_id.clone()).or_insert(Usage {
+            bytes,
+            charged_since: 0,
+        });
+    }
+
+    /// a package's quota and the bytes charged against it, if it has a quota
+    pub fn usage(&self, package_id: &PackageId) -> Option<(u64, u64)> {
+        let quota = *self.limits.get(package_id)?;
+        let usage = self.usage.get(package_id).map_or(0, |usage| usage.bytes);
+        Some((quota, usage))
+    }
+
+    /// Charge `incoming` bytes against a package's quota, or fail without
+    /// charging anything if they would take it over.
+    ///
+    /// Packages not yet measured with `prepare` aren't charged.
+    pub fn charge(&self, package_id: &PackageId, incoming: u64) -> Result<(), QuotaExceeded> {
+        if incoming == 0 {
+            return Ok(());
+        }
+        let Some(quota) = self.limits.get(package_id).map(|quota| *quota) else {
+            return Ok(());
+        };
+        let Some(mut usage) = self.usage.get_mut(package_id) else {
+            return Ok(());
+        };
+        if usage.bytes.saturating_add(incoming) > quota {
+            return Err(QuotaExceeded {
+                quota,
+                usage: usage.bytes,
+            });
+        }
+        usage.bytes += incoming;
+        usage.charged_since += incoming;
+        Ok(())
+    }
+
+    /// charge `incoming` bytes against a package's quota ahead of storing them,
+    /// failing if they would take it over
+    pub async fn reserve(
+        &self,
+        package_id: &PackageId,
+        incoming: u64,
+    ) -> Result<(), QuotaExceeded> {
+        if incoming == 0 {
+            return Ok(());
+        }
+        self.prepare(package_id).await;
+        self.charge(package_id, incoming)
+    }
+
+    /// `reserve` bytes about to be written to graphdb, and count them towards
+    /// the package's share of graphdb's store whether or not it has a quota
+    pub async fn reserve_graphdb(
+        &self,
+        package_id: &PackageId,
+        incoming: u64,
+    ) -> Result<(), QuotaExceeded> {
+        if incoming == 0 {
+            return Ok(());
+        }
+        self.reserve(package_id, incoming).await?;
+        *self.graphdb.entry(package_id.clone()).or_default() += incoming;
+        self.graphdb_dirty.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// save the bytes each package has written to graphdb, if they changed
+    async fn save_graphdb_usage(&self) -> std::io::Result<()> {
+        if !self.graphdb_dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let graphdb: HashMap<PackageId, u64> = self
+            .graphdb
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        let dir = Path::new(&self.home_directory_path).join(QUOTA_DIR);
+        let result = match tokio::fs::create_dir_all(&dir).await {
+            Ok(()) => {
+                let bytes = bincode::serialize(&graphdb).unwrap();
+                tokio::fs::write(dir.join(GRAPHDB_USAGE_FILE), bytes).await
+            }
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            self.graphdb_dirty.store(true, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Bytes stored by every package with data in any store or a quota, and
+    /// the rest of graphdb's store.
+    ///
+    /// graphdb keeps all namespaces in one store under `{home}/graphdb`, so
+    /// what its files hold beyond each namespace's data is reported separately.
+    pub async fn report(&self) -> (Vec<PackageStorageUsage>, u64) {
+        let home = self.home_directory_path.clone();
+        let limits = self.limits();
+        let graphdb_packages: Vec<PackageId> = self
+            .graphdb
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+        let packages = tokio::task::spawn_blocking({
+            let limits = limits.clone();
+            move || {
+                let mut packages: BTreeMap<String, PackageId> = limits
+                    .keys()
+                    .chain(graphdb_packages.iter())
+                    .map(|package_id| (package_id.to_string(), package_id.clone()))
+                    .collect();
+                for store in DIR_STORES {
+                    let Ok(entries) = std::fs::read_dir(Path::new(&home).join(store)) else {
+                        continue;
+                    };
+                    for entry in entries.flatten() {
+                        let name = entry.file_name().to_string_lossy().to_string();
+                        if let Ok(package_id) = name.parse::<PackageId>() {
+                            packages.insert(name, package_id);
+                        }
+                    }
+                }
+                packages
+            }
+        })
+        .await
+        .unwrap_or_default();
+
+        let mut usage = Vec::with_capacity(packages.len());
+        for package_id in packages.into_values() {
+            let quota = limits.get(&package_id).copied();
+            usage.push(self.measure(&package_id, quota).await);
+        }
+        let graphdb_packages: u64 = usage.iter().map(|usage| usage.graphdb).sum();
+        let graphdb_path = Path::new(&self.home_directory_path).join("graphdb");
+        let graphdb_shared = tokio::task::spawn_blocking(move || dir_size(&graphdb_path))
+            .await
+            .unwrap_or(0)
+            .saturating_sub(graphdb_packages);
+        (usage, graphdb_shared)
+    }
+
+    async fn measure(&self, package_id: &PackageId, quota: Option<u64>) -> PackageStorageUsage {
+        let home = self.home_directory_path.clone();
+        let package = package_id.to_string();
+        let [vfs, kv, sqlite] = tokio::task::spawn_blocking(move || {
+            DIR_STORES.map(|store| dir_size(&Path::new(&home).join(store).join(&package)))
+        })
+        .await
+        .unwrap_or_default();
+        let graphdb = self.graphdb.get(package_id).map_or(0, |bytes| *bytes);
+        PackageStorageUsage {
+            package_id: package_id.clone(),
+            quota,
+            vfs,
+            kv,
+            sqlite,
+            graphdb,
+            total: vfs + kv + sqlite + graphdb,
+        }
+    }
+}
+
+/// every `REFRESH_INTERVAL`, measure again the usage of each package that has
+/// been charged, keeping what was charged while measuring, and save what each
+/// package has written to graphdb
+pub async fn refresh_usage(quotas: Arc<StorageQuotas>) -> anyhow::Result<()> {
+    let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+    loop {
+        interval.tick().await;
+        let packages: Vec<PackageId> = quotas
+            .usage
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+        for package_id in packages {
+            match quotas.usage.get_mut(&package_id) {
+                Some(mut usage) => usage.charged_since = 0,
+                None => continue,
+            }
+            let bytes = quotas.measure(&package_id, None).await.total;
+            if let Some(mut usage) = quotas.usage.get_mut(&package_id) {
+                usage.bytes = bytes.saturating_add(usage.charged_since);
+            }
+        }
+        if let Err(e) = quotas.save_graphdb_usage().await {
+            println!("quota: failed to save graphdb usage: {e}\r");
+        }
+    }
+}
+
+/// bytes on disk under `path`, or 0 if it doesn't exist
+fn dir_size(path: &Path) -> u64 {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    std::fs::read_dir(path)
+        .map(|entries| entries.flatten().map(|entry| dir_size(&entry.path())).sum())
+        .unwrap_or(0)
+}
@@ -0,0 +1,480 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use flate2::{bufread::DeflateDecoder, write::DeflateEncoder, Compression};
+use rocksdb::checkpoint::Checkpoint;
+use rocksdb::DB;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use crate::types::*;
+use crate::{graphdb, kv, quota, sqlite};
+
+/// directories under the home directory that make up a node's data. stores
+/// that are open while snapshotting are taken from checkpoints or dumps of
+/// them instead of their files.
+const SNAPSHOT_DIRS: [&str; 6] = ["vfs", "kv", "sqlite", "graphdb", "timer", quota::QUOTA_DIR];
+
+/// checkpoints and dumps of open stores, made while snapshotting
+const STAGING_DIR: &str = "snapshot_staging";
+
+/// start of every snapshot archive
+const ARCHIVE_MAGIC: &[u8; 8] = b"KNSNAP01";
+
+/// plaintext bytes sealed in each frame of an archive's data
+const FRAME_LEN: usize = 1 << 20;
+
+/// AES-GCM tag appended to each frame
+const TAG_LEN: usize = 16;
+
+/// Handles on the stores whose files can't be copied consistently while they
+/// are open. They are shared with the runtime modules that own them, so
+/// snapshots can checkpoint or dump them instead.
+#[derive(Clone, Default)]
+pub struct SnapshotStores {
+    pub kv: kv::OpenKvs,
+    pub sqlite: sqlite::OpenDbs,
+    pub graphdb: graphdb::OpenGdbs,
+}
+
+fn invalid_data(error: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// Write an encrypted archive of all node data to `{home}/snapshots`,
+/// returning its path.
+///
+/// Runtime modules hold a read guard on `snapshot_lock` while handling each
+/// request, and while sweeping or persisting in the background, so taking the
+/// write guard here quiesces every store for as long as their files are being
+/// read. The kernel state db and open kv dbs are taken from RocksDB
+/// checkpoints, open sqlite dbs have their WALs checkpointed first, and an open
+/// graphdb store is dumped, to be imported when it is next opened.
+///
+/// The archive holds the node's keyfile, which is itself encrypted with the
+/// node password, and the compressed data, encrypted with `file_key`:
+/// restoring only requires the archive and the password. The data is streamed
+/// to disk as it is read, never held in memory whole.
+pub async fn create_snapshot(
+    home_directory_path: &str,
+    file_key: &[u8],
+    state_db: &DB,
+    snapshot_lock: &SnapshotLock,
+    stores: &SnapshotStores,
+) -> Result<String, StateError> {
+    let keyfile = tokio::fs::read(format!("{}/.keys", home_directory_path)).await?;
+
+    let snapshots_dir = format!("{}/snapshots", home_directory_path);
+    tokio::fs::create_dir_all(&snapshots_dir).await?;
+    let path = format!(
+        "{}/{}.snapshot",
+        snapshots_dir,
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+    );
+    let tmp_path = format!("{}.tmp", path);
+
+    let staging = Path::new(home_directory_path).join(STAGING_DIR);
+    if staging.exists() {
+        tokio::fs::remove_dir_all(&staging).await?;
+    }
+    tokio::fs::create_dir_all(&staging).await?;
+
+    let written = {
+        let _quiesced = snapshot_lock.write().await;
+        write_snapshot(
+            home_directory_path,
+            &staging,
+            &tmp_path,
+            keyfile,
+            file_key,
+            state_db,
+            stores,
+        )
+        .await
+    };
+    let _ = tokio::fs::remove_dir_all(&staging).await;
+    if let Err(e) = written {
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+        return Err(e);
+    }
+    tokio::fs::rename(&tmp_path, &path).await?;
+    Ok(path)
+}
+
+/// checkpoint and dump the open stores into `staging`, then write the archive
+/// to `archive_path`. must be called with the stores quiesced.
+async fn write_snapshot(
+    home_directory_path: &str,
+    staging: &Path,
+    archive_path: &str,
+    keyfile: Vec<u8>,
+    file_key: &[u8],
+    state_db: &DB,
+    stores: &SnapshotStores,
+) -> Result<(), StateError> {
+    let home = Path::new(home_directory_path).to_path_buf();
+    // (where a directory is on disk, what it is called in the archive)
+    let mut sources: Vec<(PathBuf, PathBuf)> = Vec::new();
+    // live directories taken from a checkpoint or dump instead
+    let mut skip: HashSet<PathBuf> = HashSet::new();
+
+    let kernel_checkpoint = staging.join("kernel");
+    Checkpoint::new(state_db)
+        .and_then(|checkpoint| checkpoint.create_checkpoint(&kernel_checkpoint))
+        .map_err(|e| StateError::RocksDBError {
+            action: "SnapshotCheckpoint".into(),
+            error: e.to_string(),
+        })?;
+    sources.push((kernel_checkpoint, PathBuf::from("kernel")));
+
+    let kv_checkpoints = staging.join("kv");
+    let kv_stores = stores.kv.clone();
+    let (kv_path, kv_dest) = (home.join("kv"), kv_checkpoints.clone());
+    let checkpointed = tokio::task::spawn_blocking(move || {
+        kv::checkpoint_open_dbs(&kv_stores, &kv_path, &kv_dest)
+    })
+    .await
+    .map_err(|e| StateError::IOError {
+        error: e.to_string(),
+    })?
+    .map_err(|e| StateError::IOError {
+        error: e.to_string(),
+    })?;
+    if !checkpointed.is_empty() {
+        skip.extend(checkpointed);
+        sources.push((kv_checkpoints, PathBuf::from("kv")));
+    }
+
+    sqlite::checkpoint_open_dbs(&stores.sqlite).await;
+
+    let graphdb_dumps = staging.join(graphdb::RESTORE_DIR);
+    let dumped = graphdb::dump_open_store(&stores.graphdb, &graphdb_dumps)
+        .await
+        .map_err(|e| StateError::IOError {
+            error: e.to_string(),
+        })?;
+    if dumped {
+        skip.insert(home.join("graphdb"));
+        sources.push((graphdb_dumps, PathBuf::from(graphdb::RESTORE_DIR)));
+    } else if home.join(graphdb::RESTORE_DIR).exists() {
+        // restored dumps not yet imported, as the store hasn't been opened since
+        sources.push((
+            home.join(graphdb::RESTORE_DIR),
+            PathBuf::from(graphdb::RESTORE_DIR),
+        ));
+    }
+
+    for dir in SNAPSHOT_DIRS {
+        let path = home.join(dir);
+        if path.exists() && !skip.contains(&path) {
+            sources.push((path, PathBuf::from(dir)));
+        }
+    }
+
+    let key = file_key.to_vec();
+    let archive_path = archive_path.to_string();
+    tokio::task::spawn_blocking(move || {
+        write_archive(&archive_path, &keyfile, &key, &sources, &skip)
+    })
+    .await
+    .map_err(|e| StateError::IOError {
+        error: e.to_string(),
+    })??;
+    Ok(())
+}
+
+/// An archive is `ARCHIVE_MAGIC`, the keyfile's length as a little-endian u64,
+/// the keyfile, then the data: every file as its archive path's length (u32),
+/// the path, its length (u64) and its contents, ending with a zero path length;
+/// deflated, then split into frames sealed by `FrameWriter`.
+fn write_archive(
+    archive_path: &str,
+    keyfile: &[u8],
+    file_key: &[u8],
+    sources: &[(PathBuf, PathBuf)],
+    skip: &HashSet<PathBuf>,
+) -> Result<(), std::io::Error> {
+    let mut archive = BufWriter::new(File::create(archive_path)?);
+    archive.write_all(ARCHIVE_MAGIC)?;
+    archive.write_all(&(keyfile.len() as u64).to_le_bytes())?;
+    archive.write_all(keyfile)?;
+
+    let frames = FrameWriter::new(archive, file_key)?;
+    let mut data = DeflateEncoder::new(frames, Compression::default());
+    for (path, name) in sources {
+        write_dir(&mut data, path, name, skip)?;
+    }
+    data.write_all(&0u32.to_le_bytes())?;
+    let mut archive = data.finish()?.finish()?;
+    archive.flush()?;
+    archive.into_inner()?.sync_all()
+}
+
+fn write_dir(
+    data: &mut impl Write,
+    path: &Path,
+    name: &Path,
+    skip: &HashSet<PathBuf>,
+) -> Result<(), std::io::Error> {
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let entry_path = entry.path();
+        if skip.contains(&entry_path) {
+            continue;
+        }
+        let entry_name = name.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            write_dir(data, &entry_path, &entry_name, skip)?;
+            continue;
+        }
+        let entry_name = entry_name.to_string_lossy().replace('\\', "/");
+        let mut file = File::open(&entry_path)?;
+        let len = file.metadata()?.len();
+        data.write_all(&(entry_name.len() as u32).to_le_bytes())?;
+        data.write_all(entry_name.as_bytes())?;
+        data.write_all(&len.to_le_bytes())?;
+        if std::io::copy(&mut (&mut file).take(len), data)? != len {
+            return Err(invalid_data("file shrank while being snapshotted"));
+        }
+    }
+    Ok(())
+}
+
+/// Seals everything written to it in frames of up to `FRAME_LEN` plaintext
+/// bytes, each written as its ciphertext's length (u32) and the ciphertext.
+/// Each frame's nonce is the archive's random prefix followed by the frame's
+/// index, so frames can't be reordered, and the last frame is marked in its
+/// associated data, so the archive can't be truncated unnoticed.
+struct FrameWriter<W: Write> {
+    inner: W,
+    cipher: Aes256Gcm,
+    prefix: [u8; 8],
+    index: u32,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> FrameWriter<W> {
+    fn new(mut inner: W, file_key: &[u8]) -> Result<Self, std::io::Error> {
+        let prefix: [u8; 8] = rand::random();
+        inner.write_all(&prefix)?;
+        Ok(FrameWriter {
+            inner,
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(file_key)),
+            prefix,
+            index: 0,
+            buffer: Vec::with_capacity(FRAME_LEN),
+        })
+    }
+
+    fn seal(&mut self, last: bool) -> Result<(), std::io::Error> {
+        let nonce = frame_nonce(&self.prefix, self.index);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &self.buffer,
+                    aad: &[last as u8],
+                },
+            )
+            .map_err(|_| invalid_data("failed to encrypt snapshot"))?;
+        self.inner
+            .write_all(&(ciphertext.len() as u32).to_le_bytes())?;
+        self.inner.write_all(&ciphertext)?;
+        self.buffer.clear();
+        self.index = self
+            .index
+            .checked_add(1)
+            .ok_or_else(|| invalid_data("snapshot too large"))?;
+        Ok(())
+    }
+
+    /// seal the last frame, returning the underlying writer
+    fn finish(mut self) -> Result<W, std::io::Error> {
+        self.seal(true)?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for FrameWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(FRAME_LEN - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == FRAME_LEN {
+            self.seal(false)?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        // a partial frame is only sealed by `finish`
+        self.inner.flush()
+    }
+}
+
+/// Opens the frames sealed by `FrameWriter`, failing if any is missing,
+/// reordered or altered, or if the stream ends before the last frame.
+struct FrameReader<R: Read> {
+    inner: R,
+    cipher: Aes256Gcm,
+    prefix: [u8; 8],
+    index: u32,
+    plaintext: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> FrameReader<R> {
+    fn new(mut inner: R, file_key: &[u8]) -> Result<Self, std::io::Error> {
+        let mut prefix = [0u8; 8];
+        inner.read_exact(&mut prefix)?;
+        Ok(FrameReader {
+            inner,
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(file_key)),
+            prefix,
+            index: 0,
+            plaintext: Vec::new(),
+            pos: 0,
+            done: false,
+        })
+    }
+
+    fn open_next(&mut self) -> Result<(), std::io::Error> {
+        let mut len = [0u8; 4];
+        self.inner
+            .read_exact(&mut len)
+            .map_err(|_| invalid_data("snapshot data is truncated"))?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len < TAG_LEN || len > FRAME_LEN + TAG_LEN {
+            return Err(invalid_data("snapshot data is corrupt"));
+        }
+        let mut ciphertext = vec![0u8; len];
+        self.inner
+            .read_exact(&mut ciphertext)
+            .map_err(|_| invalid_data("snapshot data is truncated"))?;
+        let nonce = frame_nonce(&self.prefix, self.index);
+        let open = |last: bool| {
+            self.cipher.decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: &[last as u8],
+                },
+            )
+        };
+        self.plaintext = match open(false) {
+            Ok(plaintext) => plaintext,
+            Err(_) => {
+                let plaintext = open(true)
+                    .map_err(|_| invalid_data("failed to decrypt snapshot: wrong keyfile?"))?;
+                self.done = true;
+                plaintext
+            }
+        };
+        self.pos = 0;
+        self.index = self
+            .index
+            .checked_add(1)
+            .ok_or_else(|| invalid_data("snapshot data is corrupt"))?;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for FrameReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.plaintext.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.open_next()?;
+        }
+        let len = buf.len().min(self.plaintext.len() - self.pos);
+        buf[..len].copy_from_slice(&self.plaintext[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+fn frame_nonce(prefix: &[u8; 8], index: u32) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(prefix);
+    nonce[8..].copy_from_slice(&index.to_be_bytes());
+    nonce
+}
+
+/// read an archive up to its data, returning the keyfile
+fn read_header(archive: &mut impl Read) -> Result<Vec<u8>, std::io::Error> {
+    let mut magic = [0u8; 8];
+    archive.read_exact(&mut magic)?;
+    if &magic != ARCHIVE_MAGIC {
+        return Err(invalid_data("not a snapshot archive"));
+    }
+    let mut len = [0u8; 8];
+    archive.read_exact(&mut len)?;
+    let mut keyfile = Vec::new();
+    archive
+        .take(u64::from_le_bytes(len))
+        .read_to_end(&mut keyfile)?;
+    if keyfile.len() as u64 != u64::from_le_bytes(len) {
+        return Err(invalid_data("snapshot archive is truncated"));
+    }
+    Ok(keyfile)
+}
+
+/// Read the node's keyfile out of a snapshot archive.
+pub fn open_snapshot(archive_path: &str) -> Result<Vec<u8>, std::io::Error> {
+    read_header(&mut BufReader::new(File::open(archive_path)?))
+}
+
+/// Decrypt a snapshot's data with `file_key` and unpack it into the home
+/// directory, streaming it from the archive.
+pub fn restore_snapshot(
+    home_directory_path: &str,
+    archive_path: &str,
+    file_key: &[u8],
+) -> Result<(), std::io::Error> {
+    let mut archive = BufReader::new(File::open(archive_path)?);
+    read_header(&mut archive)?;
+    let mut data = DeflateDecoder::new(BufReader::new(FrameReader::new(archive, file_key)?));
+
+    let home = Path::new(home_directory_path);
+    loop {
+        let mut len = [0u8; 4];
+        data.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len == 0 {
+            break;
+        }
+        let mut name = vec![0u8; len];
+        data.read_exact(&mut name)?;
+        let name = String::from_utf8(name).map_err(|_| invalid_data("bad path in snapshot"))?;
+        // only ever unpack beneath the home directory
+        let name = Path::new(&name);
+        if !name.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(invalid_data("bad path in snapshot"));
+        }
+        let mut file_len = [0u8; 8];
+        data.read_exact(&mut file_len)?;
+        let file_len = u64::from_le_bytes(file_len);
+
+        let path = home.join(name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = File::create(&path)?;
+        if std::io::copy(&mut (&mut data).take(file_len), &mut file)? != file_len {
+            return Err(invalid_data("snapshot data is truncated"));
+        }
+    }
+    // the data must end with the last frame, which reading to its end verifies
+    if data.read(&mut [0u8; 1])? != 0 || data.into_inner().read(&mut [0u8; 1])? != 0 {
+        return Err(invalid_data("snapshot data is corrupt"));
+    }
+    Ok(())
+}
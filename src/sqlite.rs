@@ -1,12 +1,16 @@
 use anyhow::Result;
 use dashmap::DashMap;
+use rusqlite::hooks::Action;
 use rusqlite::types::{FromSql, FromSqlError, ToSql, ValueRef};
-use rusqlite::Connection;
+use rusqlite::{Connection, TransactionBehavior};
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::fs;
 use tokio::sync::Mutex;
 
+use crate::encryption::AtRest;
+use crate::quota::{QuotaExceeded, StorageQuotas};
 use crate::types::*;
 
 lazy_static::lazy_static! {
@@ -29,6 +33,90 @@ lazy_static::lazy_static! {
     };
 }
 
+/// statements that would end or nest the transaction they're issued in
+const TX_CONTROL_KEYWORDS: [&str; 4] = ["BEGIN", "COMMIT", "END", "ROLLBACK"];
+
+/// statements that open or close a savepoint, which a write can't be wrapped
+/// in a savepoint of its own around without releasing it early
+const SAVEPOINT_KEYWORDS: [&str; 2] = ["SAVEPOINT", "RELEASE"];
+
+/// how long a transaction may stay open before it is rolled back
+const TX_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// how often open transactions are checked against their deadline
+const TX_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// how long a statement waits for another connection's lock on the db before
+/// failing as busy. a transaction holds the write lock on its own connection,
+/// so writes on the shared connection wait for it rather than fail at once,
+/// on the blocking pool so as not to stall the runtime.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// how long a paged read's cursor stays open without its next page being read
+const CURSOR_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// how many paged reads a process may have open at once. each holds a
+/// connection and a blocking thread until it is read to the end or times out.
+const MAX_CURSORS_PER_PROCESS: usize = 16;
+
+/// open transactions, by tx_id
+type Txs = Arc<DashMap<u64, Arc<SqliteTx>>>;
+
+/// open cursors of paged reads, by cursor id
+type Cursors = Arc<DashMap<u64, SqliteCursor>>;
+
+/// a request for up to `limit` more rows of a cursor, answered with the rows
+/// and whether any remain after them
+type CursorPage = (
+    u64,
+    tokio::sync::oneshot::Sender<Result<(SqliteRows, bool), SqliteError>>,
+);
+
+/// row changes committed on any connection, waiting to be sent to subscribers
+type ChangeLog = Arc<std::sync::Mutex<Vec<SqliteChange>>>;
+
+/// row changes made on one connection by a transaction that hasn't committed yet
+type PendingChanges = Arc<std::sync::Mutex<Vec<SqliteChange>>>;
+
+/// processes subscribed to each db's changes, registered with `SqliteAction::Subscribe`
+type Subscribers = Arc<DashMap<(PackageId, String), Vec<SqliteSubscriber>>>;
+
+struct SqliteSubscriber {
+    address: Address,
+    /// empty to receive changes to every table
+    tables: Vec<String>,
+}
+
+/// open dbs, shared with snapshots so that their WALs can be checkpointed
+pub type OpenDbs = Arc<DashMap<(PackageId, String), SqliteDb>>;
+
+/// a db's shared connection, used by requests outside of a transaction
+pub struct SqliteDb {
+    conn: Arc<Mutex<Connection>>,
+    pending: PendingChanges,
+}
+
+/// a transaction held open on its own connection to the db file, so reads
+/// inside it see its pending writes while other requests on the shared
+/// connection don't.
+struct SqliteTx {
+    db_key: (PackageId, String),
+    conn: Mutex<Connection>,
+    pending: PendingChanges,
+    deadline: Instant,
+}
+
+/// a paged read's statement, held open by a blocking task on its own connection
+/// to the db file, so each page picks up where the last ended. dropping it ends
+/// the task, closing the statement.
+struct SqliteCursor {
+    db_key: (PackageId, String),
+    owner: ProcessId,
+    query: String,
+    pages: tokio::sync::mpsc::Sender<CursorPage>,
+    deadline: Instant,
+}
+
 pub async fn sqlite(
     our_node: String,
     send_to_loop: MessageSender,
@@ -36,6 +124,10 @@ pub async fn sqlite(
     mut recv_from_loop: MessageReceiver,
     send_to_caps_oracle: CapMessageSender,
     home_directory_path: String,
+    snapshot_lock: SnapshotLock,
+    at_rest: Arc<AtRest>,
+    quotas: Arc<StorageQuotas>,
+    open_dbs: OpenDbs,
 ) -> anyhow::Result<()> {
     let sqlite_path = format!("{}/sqlite", &home_directory_path);
 
@@ -43,8 +135,37 @@ pub async fn sqlite(
         panic!("failed creating sqlite dir! {:?}", e);
     }
 
-    let open_dbs: Arc<DashMap<(PackageId, String), Mutex<Connection>>> = Arc::new(DashMap::new());
-    let txs: Arc<DashMap<u64, Vec<(String, Vec<SqlValue>)>>> = Arc::new(DashMap::new());
+    let txs: Txs = Arc::new(DashMap::new());
+    let cursors: Cursors = Arc::new(DashMap::new());
+    let change_log: ChangeLog = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let subscribers: Subscribers = Arc::new(DashMap::new());
+
+    // roll back transactions that outlive their deadline, so that abandoned
+    // tx_ids don't hold the db's write lock forever, and close abandoned cursors
+    let sweep_txs = txs.clone();
+    let sweep_cursors = cursors.clone();
+    let sweep_snapshot_lock = snapshot_lock.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(TX_SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            // rollbacks write to the dbs, so they wait out snapshots like requests do
+            let _snapshot_guard = sweep_snapshot_lock.read().await;
+            let now = Instant::now();
+            sweep_cursors.retain(|_, cursor| cursor.deadline > now);
+            let expired: Vec<u64> = sweep_txs
+                .iter()
+                .filter(|tx| tx.deadline <= now)
+                .map(|tx| *tx.key())
+                .collect();
+            for tx_id in expired {
+                if let Some((_, tx)) = sweep_txs.remove(&tx_id) {
+                    let conn = tx.conn.lock().await;
+                    let _ = conn.execute_batch("ROLLBACK");
+                }
+            }
+        }
+    });
 
     let mut process_queues: HashMap<ProcessId, Arc<Mutex<VecDeque<KernelMessage>>>> =
         HashMap::new();
@@ -61,6 +182,21 @@ pub async fn sqlite(
                     continue;
                 }
 
+                if km.source.process == *KERNEL_PROCESS_ID {
+                    if let Message::Request(Request { ref body, .. }) = km.message {
+                        if let Ok(KernelNotification::ProcessExited(process)) =
+                            serde_json::from_slice(body)
+                        {
+                            cursors.retain(|_, cursor| cursor.owner != process);
+                            subscribers.retain(|_, db_subscribers| {
+                                db_subscribers.retain(|s| s.address.process != process);
+                                !db_subscribers.is_empty()
+                            });
+                            continue;
+                        }
+                    }
+                }
+
                 let queue = process_queues
                     .entry(km.source.process.clone())
                     .or_insert_with(|| Arc::new(Mutex::new(VecDeque::new())))
@@ -79,20 +215,34 @@ pub async fn sqlite(
                 let open_dbs = open_dbs.clone();
 
                 let txs = txs.clone();
+                let cursors = cursors.clone();
+                let change_log = change_log.clone();
+                let subscribers = subscribers.clone();
                 let sqlite_path = sqlite_path.clone();
+                let at_rest = at_rest.clone();
+                let quotas = quotas.clone();
+
+                let snapshot_lock = snapshot_lock.clone();
 
                 tokio::spawn(async move {
                     let mut queue_lock = queue.lock().await;
                     if let Some(km) = queue_lock.pop_front() {
+                        // keep snapshots from copying the store mid-request
+                        let _snapshot_guard = snapshot_lock.read().await;
                         if let Err(e) = handle_request(
                             our_node.clone(),
                             km.clone(),
                             open_dbs.clone(),
                             txs.clone(),
+                            cursors.clone(),
+                            change_log.clone(),
+                            subscribers.clone(),
                             send_to_loop.clone(),
                             send_to_terminal.clone(),
                             send_to_caps_oracle.clone(),
                             sqlite_path.clone(),
+                            at_rest.clone(),
+                            quotas.clone(),
                         )
                         .await
                         {
@@ -110,12 +260,17 @@ pub async fn sqlite(
 async fn handle_request(
     our_node: String,
     km: KernelMessage,
-    open_dbs: Arc<DashMap<(PackageId, String), Mutex<Connection>>>,
-    txs: Arc<DashMap<u64, Vec<(String, Vec<SqlValue>)>>>,
+    open_dbs: OpenDbs,
+    txs: Txs,
+    cursors: Cursors,
+    change_log: ChangeLog,
+    subscribers: Subscribers,
     send_to_loop: MessageSender,
     send_to_terminal: PrintSender,
     send_to_caps_oracle: CapMessageSender,
     sqlite_path: String,
+    at_rest: Arc<AtRest>,
+    quotas: Arc<StorageQuotas>,
 ) -> Result<(), SqliteError> {
     let KernelMessage {
         id,
@@ -150,12 +305,22 @@ async fn handle_request(
         our_node.clone(),
         source.clone(),
         open_dbs.clone(),
+        txs.clone(),
+        subscribers.clone(),
         send_to_caps_oracle.clone(),
         &request,
         sqlite_path.clone(),
+        &at_rest,
     )
     .await?;
 
+    // writes are charged as they run, which can't wait on measuring usage
+    if let SqliteAction::Write { .. } | SqliteAction::Migrate { .. } = &request.action {
+        quotas.prepare(&request.package_id).await;
+    }
+
+    let db_key = (request.package_id, request.db);
+
     let (body, bytes) = match request.action {
         SqliteAction::Open => {
             // handled in check_caps
@@ -163,16 +328,15 @@ async fn handle_request(
         }
         SqliteAction::RemoveDb => {
             // handled in check_caps
+            cursors.retain(|_, cursor| cursor.db_key != db_key);
             (serde_json::to_vec(&SqliteResponse::Ok).unwrap(), None)
         }
-        SqliteAction::Read { query } => {
-            let db = match open_dbs.get(&(request.package_id, request.db)) {
-                Some(db) => db,
-                None => {
-                    return Err(SqliteError::NoDb);
-                }
-            };
-            let db = db.lock().await;
+        SqliteAction::Read {
+            query,
+            tx_id,
+            limit,
+            cursor,
+        } => {
             let first_word = query
                 .split_whitespace()
                 .next()
@@ -182,52 +346,114 @@ async fn handle_request(
                 return Err(SqliteError::NotAReadKeyword);
             }
 
-            let parameters = get_json_params(blob)?;
-
-            let mut statement = db.prepare(&query)?;
-            let column_names: Vec<String> = statement
-                .column_names()
-                .iter()
-                .map(|c| c.to_string())
-                .collect();
+            let parameters = get_params(blob, request.encoding)?;
 
-            let results: Vec<HashMap<String, serde_json::Value>> = statement
-                .query_map(rusqlite::params_from_iter(parameters.iter()), |row| {
-                    let mut map = HashMap::new();
-                    for (i, column_name) in column_names.iter().enumerate() {
-                        let value: SqlValue = row.get(i)?;
-                        let value_json = match value {
-                            SqlValue::Integer(int) => serde_json::Value::Number(int.into()),
-                            SqlValue::Real(real) => serde_json::Value::Number(
-                                serde_json::Number::from_f64(real).unwrap(),
-                            ),
-                            SqlValue::Text(text) => serde_json::Value::String(text),
-                            SqlValue::Blob(blob) => serde_json::Value::String(base64::encode(blob)), // or another representation if you prefer
-                            _ => serde_json::Value::Null,
-                        };
-                        map.insert(column_name.clone(), value_json);
+            let (rows, body) = match (tx_id, limit) {
+                (Some(tx_id), None) => {
+                    if TX_CONTROL_KEYWORDS.contains(&first_word.as_str()) {
+                        return Err(SqliteError::TxControlStatement);
                     }
-                    Ok(map)
-                })?
-                .collect::<Result<Vec<_>, _>>()?;
-
-            let results = serde_json::json!(results).to_string();
-            let results_bytes = results.as_bytes().to_vec();
-
-            (
-                serde_json::to_vec(&SqliteResponse::Read).unwrap(),
-                Some(results_bytes),
-            )
-        }
-        SqliteAction::Write { statement, tx_id } => {
-            let db = match open_dbs.get(&(request.package_id, request.db)) {
-                Some(db) => db,
-                None => {
-                    return Err(SqliteError::NoDb);
+                    let tx = get_tx(&txs, tx_id, &db_key)?;
+                    let conn = tx.conn.lock().await;
+                    (read_rows(&conn, &query, &parameters)?, SqliteResponse::Read)
+                }
+                (Some(_), Some(_)) => {
+                    return Err(SqliteError::InputError {
+                        error: "paged reads can't be made inside a transaction".into(),
+                    });
+                }
+                (None, None) => {
+                    let db = match open_dbs.get(&db_key) {
+                        Some(db) => db,
+                        None => {
+                            return Err(SqliteError::NoDb);
+                        }
+                    };
+                    let conn = db.conn.lock().await;
+                    (read_rows(&conn, &query, &parameters)?, SqliteResponse::Read)
+                }
+                (None, Some(limit)) => {
+                    let cursor_id = match cursor {
+                        Some(cursor_id) => cursor_id,
+                        None => {
+                            if !open_dbs.contains_key(&db_key) {
+                                return Err(SqliteError::NoDb);
+                            }
+                            let open = cursors
+                                .iter()
+                                .filter(|cursor| cursor.owner == source.process)
+                                .count();
+                            if open >= MAX_CURSORS_PER_PROCESS {
+                                return Err(SqliteError::TooManyCursors {
+                                    max: MAX_CURSORS_PER_PROCESS,
+                                });
+                            }
+                            let conn =
+                                open_connection(&sqlite_path, &db_key.0, &db_key.1, &at_rest)?;
+                            let (pages, requests) = tokio::sync::mpsc::channel(1);
+                            let cursor_query = query.clone();
+                            tokio::task::spawn_blocking(move || {
+                                run_cursor(conn, cursor_query, parameters, requests)
+                            });
+                            let cursor_id = rand::random::<u64>();
+                            cursors.insert(
+                                cursor_id,
+                                SqliteCursor {
+                                    db_key: db_key.clone(),
+                                    owner: source.process.clone(),
+                                    query: query.clone(),
+                                    pages,
+                                    deadline: Instant::now() + CURSOR_TIMEOUT,
+                                },
+                            );
+                            cursor_id
+                        }
+                    };
+                    let pages = match cursors.get_mut(&cursor_id) {
+                        Some(mut cursor)
+                            if cursor.db_key == db_key
+                                && cursor.owner == source.process
+                                && cursor.query == query =>
+                        {
+                            cursor.deadline = Instant::now() + CURSOR_TIMEOUT;
+                            cursor.pages.clone()
+                        }
+                        _ => return Err(SqliteError::NoCursor),
+                    };
+                    let (reply, page) = tokio::sync::oneshot::channel();
+                    if pages.send((limit, reply)).await.is_err() {
+                        cursors.remove(&cursor_id);
+                        return Err(SqliteError::NoCursor);
+                    }
+                    let page = page.await.map_err(|_| SqliteError::NoCursor);
+                    let (rows, more) = match page.and_then(|page| page) {
+                        Ok(page) => page,
+                        Err(e) => {
+                            cursors.remove(&cursor_id);
+                            return Err(e);
+                        }
+                    };
+                    if !more {
+                        cursors.remove(&cursor_id);
+                    }
+                    let body = SqliteResponse::ReadPage {
+                        cursor: more.then_some(cursor_id),
+                    };
+                    (rows, body)
+                }
+            };
+            let results_bytes = match request.encoding {
+                SqliteEncoding::Json => rows_to_json(rows).to_string().into_bytes(),
+                SqliteEncoding::Msgpack => {
+                    rmp_serde::to_vec(&rows).map_err(|e| SqliteError::InputError {
+                        error: e.to_string(),
+                    })?
                 }
             };
-            let db = db.lock().await;
 
+            (serde_json::to_vec(&body).unwrap(), Some(results_bytes))
+        }
+        SqliteAction::Write { statement, tx_id } => {
             let first_word = statement
                 .split_whitespace()
                 .next()
@@ -238,24 +464,78 @@ async fn handle_request(
                 return Err(SqliteError::NotAWriteKeyword);
             }
 
-            let parameters = get_json_params(blob)?;
+            let parameters = get_params(blob, request.encoding)?;
 
             match tx_id {
                 Some(tx_id) => {
-                    txs.entry(tx_id)
-                        .or_default()
-                        .push((statement.clone(), parameters));
+                    if TX_CONTROL_KEYWORDS.contains(&first_word.as_str()) {
+                        return Err(SqliteError::TxControlStatement);
+                    }
+                    let tx = get_tx(&txs, tx_id, &db_key)?;
+                    let conn = tx.conn.lock().await;
+                    let write = |conn: &Connection| {
+                        conn.execute(&statement, rusqlite::params_from_iter(parameters.iter()))
+                    };
+                    if SAVEPOINT_KEYWORDS.contains(&first_word.as_str()) {
+                        write(&conn)?;
+                    } else {
+                        write_within_quota(&conn, &tx.pending, &quotas, &db_key.0, write)?;
+                    }
                 }
                 None => {
-                    let mut stmt = db.prepare(&statement)?;
-                    stmt.execute(rusqlite::params_from_iter(parameters.iter()))?;
+                    let quotas = quotas.clone();
+                    let change_log = change_log.clone();
+                    let package_id = db_key.0.clone();
+                    with_shared_connection(&open_dbs, &db_key, move |conn, pending| {
+                        let write = |conn: &Connection| {
+                            conn.prepare(&statement).and_then(|mut stmt| {
+                                stmt.execute(rusqlite::params_from_iter(parameters.iter()))
+                            })
+                        };
+                        // a quota's savepoint can't wrap statements that begin,
+                        // end or nest transactions
+                        let result = if TX_CONTROL_KEYWORDS.contains(&first_word.as_str())
+                            || SAVEPOINT_KEYWORDS.contains(&first_word.as_str())
+                        {
+                            write(conn).map_err(SqliteError::from)
+                        } else {
+                            write_within_quota(conn, pending, &quotas, &package_id, write)
+                        };
+                        // a statement outside of BEGIN and COMMIT commits on its own
+                        if conn.is_autocommit() {
+                            match &result {
+                                Ok(_) => publish_changes(pending, &change_log),
+                                Err(_) => pending.lock().unwrap().clear(),
+                            }
+                        }
+                        result.map(|_| ())
+                    })
+                    .await?;
                 }
             };
             (serde_json::to_vec(&SqliteResponse::Ok).unwrap(), None)
         }
         SqliteAction::BeginTx => {
+            if !open_dbs.contains_key(&db_key) {
+                return Err(SqliteError::NoDb);
+            }
+
+            // take the write lock up front, so the tx can't fail to upgrade
+            // its read snapshot after a concurrent write
+            let conn = open_connection(&sqlite_path, &db_key.0, &db_key.1, &at_rest)?;
+            let pending = install_change_hooks(&conn, &db_key);
+            conn.execute_batch("BEGIN IMMEDIATE")?;
+
             let tx_id = rand::random::<u64>();
-            txs.insert(tx_id, Vec::new());
+            txs.insert(
+                tx_id,
+                Arc::new(SqliteTx {
+                    db_key,
+                    conn: Mutex::new(conn),
+                    pending,
+                    deadline: Instant::now() + TX_TIMEOUT,
+                }),
+            );
 
             (
                 serde_json::to_vec(&SqliteResponse::BeginTx { tx_id }).unwrap(),
@@ -263,33 +543,86 @@ async fn handle_request(
             )
         }
         SqliteAction::Commit { tx_id } => {
-            let db = match open_dbs.get(&(request.package_id, request.db)) {
-                Some(db) => db,
-                None => {
-                    return Err(SqliteError::NoDb);
-                }
-            };
-            let mut db = db.lock().await;
+            let tx = take_tx(&txs, tx_id, &db_key)?;
+            let conn = tx.conn.lock().await;
+            conn.execute_batch("COMMIT")?;
+            publish_changes(&tx.pending, &change_log);
+            (serde_json::to_vec(&SqliteResponse::Ok).unwrap(), None)
+        }
+        SqliteAction::Rollback { tx_id } => {
+            let tx = take_tx(&txs, tx_id, &db_key)?;
+            let conn = tx.conn.lock().await;
+            conn.execute_batch("ROLLBACK")?;
+            (serde_json::to_vec(&SqliteResponse::Ok).unwrap(), None)
+        }
+        SqliteAction::Migrate { mut migrations } => {
+            migrations.sort_by_key(|(version, _)| *version);
+            // user_version is a signed 32-bit integer that starts at 0
+            if migrations.first().is_some_and(|(version, _)| *version <= 0) {
+                return Err(SqliteError::InputError {
+                    error: "migration versions must be positive".into(),
+                });
+            }
+            if migrations.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+                return Err(SqliteError::InputError {
+                    error: "duplicate migration version".into(),
+                });
+            }
 
-            let txs = match txs.remove(&tx_id).map(|(_, tx)| tx) {
-                None => {
-                    return Err(SqliteError::NoTx);
-                }
-                Some(tx) => tx,
-            };
+            let quotas = quotas.clone();
+            let change_log = change_log.clone();
+            let package_id = db_key.0.clone();
+            let (from, to) = with_shared_connection(&open_dbs, &db_key, move |conn, pending| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let from: i32 = tx.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+                let to = write_within_quota(&tx, pending, &quotas, &package_id, |tx| {
+                    let mut to = from;
+                    for (version, sql) in migrations {
+                        if version <= from {
+                            continue;
+                        }
+                        tx.execute_batch(&sql)?;
+                        to = version;
+                    }
+                    if to != from {
+                        tx.pragma_update(None, "user_version", to)?;
+                    }
+                    Ok(to)
+                })?;
+                tx.commit()?;
+                publish_changes(pending, &change_log);
+                Ok((from, to))
+            })
+            .await?;
 
-            let tx = db.transaction()?;
-            for (query, params) in txs {
-                tx.execute(&query, rusqlite::params_from_iter(params.iter()))?;
+            (
+                serde_json::to_vec(&SqliteResponse::Migrate { from, to }).unwrap(),
+                None,
+            )
+        }
+        SqliteAction::Subscribe { tables } => {
+            if !open_dbs.contains_key(&db_key) {
+                return Err(SqliteError::NoDb);
+            }
+            let address = Address {
+                node: our_node.clone(),
+                process: source.process.clone(),
+            };
+            let mut db_subscribers = subscribers.entry(db_key.clone()).or_default();
+            db_subscribers.retain(|s| s.address != address);
+            db_subscribers.push(SqliteSubscriber { address, tables });
+            (serde_json::to_vec(&SqliteResponse::Ok).unwrap(), None)
+        }
+        SqliteAction::Unsubscribe => {
+            if let Some(mut db_subscribers) = subscribers.get_mut(&db_key) {
+                db_subscribers.retain(|s| s.address.process != source.process);
             }
-
-            tx.commit()?;
             (serde_json::to_vec(&SqliteResponse::Ok).unwrap(), None)
         }
         SqliteAction::Backup => {
             for db_ref in open_dbs.iter() {
-                let db = db_ref.value().lock().await;
-                let result: rusqlite::Result<()> = db
+                let conn = db_ref.value().conn.lock().await;
+                let result: rusqlite::Result<()> = conn
                     .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
                     .map(|_| ());
                 if let Err(e) = result {
@@ -345,22 +678,216 @@ async fn handle_request(
             .unwrap();
     }
 
+    notify_subscribers(&our_node, &change_log, &subscribers, &send_to_loop).await;
+
     Ok(())
 }
 
+/// Run a write on `conn`, holding it to the package's remaining quota.
+///
+/// Run `f` on a db's shared connection on the blocking pool, since a write may
+/// wait up to `BUSY_TIMEOUT` for another connection's lock on the db.
+async fn with_shared_connection<T: Send + 'static>(
+    open_dbs: &OpenDbs,
+    db_key: &(PackageId, String),
+    f: impl FnOnce(&mut Connection, &PendingChanges) -> Result<T, SqliteError> + Send + 'static,
+) -> Result<T, SqliteError> {
+    let (conn, pending) = match open_dbs.get(db_key) {
+        Some(db) => (db.conn.clone(), db.pending.clone()),
+        None => return Err(SqliteError::NoDb),
+    };
+    tokio::task::spawn_blocking(move || f(&mut conn.blocking_lock(), &pending))
+        .await
+        .unwrap_or_else(|e| {
+            Err(SqliteError::IOError {
+                error: e.to_string(),
+            })
+        })
+}
+
+/// How much a statement stores isn't known until it has run, so the db is
+/// capped at the pages the quota leaves room for while it runs, and the pages
+/// it adds are charged once it is done. A write that doesn't fit is rolled back
+/// on its own, along with its pending changes, leaving any transaction it ran
+/// in open.
+fn write_within_quota<T>(
+    conn: &Connection,
+    pending: &PendingChanges,
+    quotas: &StorageQuotas,
+    package_id: &PackageId,
+    write: impl FnOnce(&Connection) -> rusqlite::Result<T>,
+) -> Result<T, SqliteError> {
+    let Some((quota, usage)) = quotas.usage(package_id) else {
+        return Ok(write(conn)?);
+    };
+    let page_count =
+        |conn: &Connection| conn.pragma_query_value(None, "page_count", |row| row.get::<_, i64>(0));
+    let page_size: i64 = conn.pragma_query_value(None, "page_size", |row| row.get(0))?;
+    let max_pages: i64 = conn.pragma_query_value(None, "max_page_count", |row| row.get(0))?;
+    conn.execute_batch("SAVEPOINT quota")?;
+    let pending_before = pending.lock().unwrap().len();
+
+    let result = (|| -> Result<T, SqliteError> {
+        let pages_before = page_count(conn)?;
+        let room = i64::try_from(quota.saturating_sub(usage)).unwrap_or(i64::MAX) / page_size;
+        let _: i64 = conn.pragma_update_and_check(
+            None,
+            "max_page_count",
+            pages_before.saturating_add(room).min(max_pages),
+            |row| row.get(0),
+        )?;
+        let value = write(conn).map_err(|e| match e.sqlite_error_code() {
+            Some(rusqlite::ErrorCode::DiskFull) => SqliteError::QuotaExceeded { quota, usage },
+            _ => SqliteError::from(e),
+        })?;
+        let growth = (page_count(conn)? - pages_before).max(0) * page_size;
+        quotas.charge(package_id, growth as u64)?;
+        Ok(value)
+    })();
+    let _: rusqlite::Result<i64> =
+        conn.pragma_update_and_check(None, "max_page_count", max_pages, |row| row.get(0));
+
+    let result = result.and_then(|value| {
+        conn.execute_batch("RELEASE quota")?;
+        Ok(value)
+    });
+    // ROLLBACK TO doesn't fire the rollback hook, so the write's changes are
+    // dropped from those pending here
+    if result.is_err() {
+        let _ = conn.execute_batch("ROLLBACK TO quota; RELEASE quota");
+        pending.lock().unwrap().truncate(pending_before);
+    }
+    result
+}
+
+/// move the committed contents of each open db's WAL into its db file, so a
+/// snapshot taken while requests are held off copies a consistent db. a WAL
+/// that can't be fully checkpointed, while a cursor or transaction is open, is
+/// copied along with the db file: sqlite recovers its committed frames when
+/// the copy is next opened.
+pub async fn checkpoint_open_dbs(open_dbs: &OpenDbs) {
+    for db_ref in open_dbs.iter() {
+        let conn = db_ref.value().conn.lock().await;
+        if let Err(e) = conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(())) {
+            println!("sqlite: failed to checkpoint WAL for snapshot: {e}\r");
+        }
+    }
+}
+
+/// record the rows changed on `conn` into the returned buffer, which is
+/// cleared if its transaction rolls back. once a COMMIT on `conn` has returned
+/// successfully, the buffer is moved to the change log by `publish_changes`:
+/// sqlite's commit hook runs before the commit is durable, and it can still fail.
+fn install_change_hooks(conn: &Connection, db_key: &(PackageId, String)) -> PendingChanges {
+    let pending: PendingChanges = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+    let (package_id, db) = db_key.clone();
+    let update_pending = pending.clone();
+    conn.update_hook(Some(
+        move |action: Action, db_name: &str, table: &str, rowid: i64| {
+            // skip temp tables and attached dbs
+            if db_name != "main" {
+                return;
+            }
+            let kind = match action {
+                Action::SQLITE_INSERT => SqliteChangeKind::Insert,
+                Action::SQLITE_UPDATE => SqliteChangeKind::Update,
+                Action::SQLITE_DELETE => SqliteChangeKind::Delete,
+                _ => return,
+            };
+            update_pending.lock().unwrap().push(SqliteChange {
+                package_id: package_id.clone(),
+                db: db.clone(),
+                table: table.to_string(),
+                rowid,
+                kind,
+            });
+        },
+    ));
+
+    let rollback_pending = pending.clone();
+    conn.rollback_hook(Some(move || {
+        rollback_pending.lock().unwrap().clear();
+    }));
+
+    pending
+}
+
+/// queue the changes of a transaction that has committed to be sent to subscribers
+fn publish_changes(pending: &PendingChanges, change_log: &ChangeLog) {
+    change_log
+        .lock()
+        .unwrap()
+        .append(&mut pending.lock().unwrap());
+}
+
+async fn notify_subscribers(
+    our_node: &str,
+    change_log: &ChangeLog,
+    subscribers: &Subscribers,
+    send_to_loop: &MessageSender,
+) {
+    let changes: Vec<SqliteChange> = std::mem::take(&mut *change_log.lock().unwrap());
+    if changes.is_empty() {
+        return;
+    }
+    let targets: Vec<(Address, Vec<u8>)> = changes
+        .iter()
+        .flat_map(|change| {
+            let db_key = (change.package_id.clone(), change.db.clone());
+            match subscribers.get(&db_key) {
+                None => vec![],
+                Some(db_subscribers) => db_subscribers
+                    .iter()
+                    .filter(|s| s.tables.is_empty() || s.tables.contains(&change.table))
+                    .map(|s| (s.address.clone(), serde_json::to_vec(change).unwrap()))
+                    .collect(),
+            }
+        })
+        .collect();
+    for (target, body) in targets {
+        let _ = send_to_loop
+            .send(KernelMessage {
+                id: rand::random(),
+                source: Address {
+                    node: our_node.to_string(),
+                    process: SQLITE_PROCESS_ID.clone(),
+                },
+                target,
+                rsvp: None,
+                message: Message::Request(Request {
+                    inherit: false,
+                    expects_response: None,
+                    body,
+                    metadata: None,
+                    capabilities: vec![],
+                }),
+                lazy_load_blob: None,
+            })
+            .await;
+    }
+}
+
 async fn check_caps(
     our_node: String,
     source: Address,
-    open_dbs: Arc<DashMap<(PackageId, String), Mutex<Connection>>>,
+    open_dbs: OpenDbs,
+    txs: Txs,
+    subscribers: Subscribers,
     mut send_to_caps_oracle: CapMessageSender,
     request: &SqliteRequest,
     sqlite_path: String,
+    at_rest: &AtRest,
 ) -> Result<(), SqliteError> {
     let (send_cap_bool, recv_cap_bool) = tokio::sync::oneshot::channel();
     let src_package_id = PackageId::new(source.process.package(), source.process.publisher());
 
     match &request.action {
-        SqliteAction::Write { .. } | SqliteAction::BeginTx | SqliteAction::Commit { .. } => {
+        SqliteAction::Write { .. }
+        | SqliteAction::BeginTx
+        | SqliteAction::Commit { .. }
+        | SqliteAction::Rollback { .. }
+        | SqliteAction::Migrate { .. } => {
             send_to_caps_oracle
                 .send(CapMessage::Has {
                     on: source.process.clone(),
@@ -386,7 +913,7 @@ async fn check_caps(
             }
             Ok(())
         }
-        SqliteAction::Read { .. } => {
+        SqliteAction::Read { .. } | SqliteAction::Subscribe { .. } | SqliteAction::Unsubscribe => {
             send_to_caps_oracle
                 .send(CapMessage::Has {
                     on: source.process.clone(),
@@ -443,14 +970,17 @@ async fn check_caps(
             let db_path = format!("{}/{}/{}", sqlite_path, request.package_id, request.db);
             fs::create_dir_all(&db_path).await?;
 
-            let db_file_path = format!("{}/{}.db", db_path, request.db);
-
-            let db = Connection::open(db_file_path)?;
+            let db = open_connection(&sqlite_path, &request.package_id, &request.db, at_rest)?;
             let _ = db.execute("PRAGMA journal_mode=WAL", []);
+            let pending =
+                install_change_hooks(&db, &(request.package_id.clone(), request.db.clone()));
 
             open_dbs.insert(
                 (request.package_id.clone(), request.db.clone()),
-                Mutex::new(db),
+                SqliteDb {
+                    conn: Arc::new(Mutex::new(db)),
+                    pending,
+                },
             );
             Ok(())
         }
@@ -462,7 +992,10 @@ async fn check_caps(
             }
 
             let db_path = format!("{}/{}/{}", sqlite_path, request.package_id, request.db);
-            open_dbs.remove(&(request.package_id.clone(), request.db.clone()));
+            let db_key = (request.package_id.clone(), request.db.clone());
+            open_dbs.remove(&db_key);
+            txs.retain(|_, tx| tx.db_key != db_key);
+            subscribers.remove(&db_key);
 
             fs::remove_dir_all(&db_path).await?;
             Ok(())
@@ -500,6 +1033,196 @@ async fn add_capability(
     Ok(())
 }
 
+fn db_file_path(sqlite_path: &str, package_id: &PackageId, db: &str) -> String {
+    format!("{}/{}/{}/{}.db", sqlite_path, package_id, db, db)
+}
+
+/// Open a connection to a db's file. With encryption at rest enabled, and the
+/// `sqlcipher` feature built in, new dbs are created as keyed SQLCipher
+/// databases; dbs created before it was enabled are still opened unkeyed.
+fn open_connection(
+    sqlite_path: &str,
+    package_id: &PackageId,
+    db: &str,
+    at_rest: &AtRest,
+) -> Result<Connection, SqliteError> {
+    let path = db_file_path(sqlite_path, package_id, db);
+    // a raw key, so SQLCipher skips its own key derivation
+    let key = format!("x'{}'", hex::encode(at_rest.key()));
+    let open = || -> Result<Connection, SqliteError> {
+        let conn = Connection::open(&path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        Ok(conn)
+    };
+    if !cfg!(feature = "sqlcipher") {
+        return open();
+    }
+    if !std::path::Path::new(&path).exists() {
+        let conn = open()?;
+        if at_rest.enabled {
+            conn.pragma_update(None, "key", &key)?;
+        }
+        return Ok(conn);
+    }
+    let conn = open()?;
+    if conn
+        .query_row("SELECT count(*) FROM sqlite_master", [], |_| Ok(()))
+        .is_ok()
+    {
+        return Ok(conn);
+    }
+    let conn = open()?;
+    conn.pragma_update(None, "key", &key)?;
+    Ok(conn)
+}
+
+/// look up an open, unexpired transaction on the given db
+fn get_tx(
+    txs: &Txs,
+    tx_id: u64,
+    db_key: &(PackageId, String),
+) -> Result<Arc<SqliteTx>, SqliteError> {
+    match txs.get(&tx_id) {
+        Some(tx) if &tx.db_key == db_key && tx.deadline > Instant::now() => Ok(tx.clone()),
+        _ => Err(SqliteError::NoTx),
+    }
+}
+
+/// remove a transaction so it can be committed or rolled back.
+/// an expired tx is dropped instead: closing its connection rolls it back.
+fn take_tx(
+    txs: &Txs,
+    tx_id: u64,
+    db_key: &(PackageId, String),
+) -> Result<Arc<SqliteTx>, SqliteError> {
+    match txs.remove_if(&tx_id, |_, tx| &tx.db_key == db_key) {
+        Some((_, tx)) if tx.deadline > Instant::now() => Ok(tx),
+        _ => Err(SqliteError::NoTx),
+    }
+}
+
+/// run a query, returning all of its rows
+fn read_rows(
+    db: &Connection,
+    query: &str,
+    parameters: &[SqlValue],
+) -> Result<SqliteRows, SqliteError> {
+    let mut statement = db.prepare(query)?;
+    let columns = column_names(&statement);
+    let mut query_rows = statement.query(rusqlite::params_from_iter(parameters.iter()))?;
+    let mut rows = Vec::new();
+    while let Some(row) = next_row(&mut query_rows, columns.len())? {
+        rows.push(row);
+    }
+    Ok(SqliteRows { columns, rows })
+}
+
+/// serve a paged read: run `query` on `conn` and answer each page request with
+/// the rows after the last page's, until every row is read or the cursor is
+/// dropped. the statement stays open throughout, so every page comes from
+/// the same snapshot of the db.
+fn run_cursor(
+    conn: Connection,
+    query: String,
+    parameters: Vec<SqlValue>,
+    mut pages: tokio::sync::mpsc::Receiver<CursorPage>,
+) {
+    let Some((limit, reply)) = pages.blocking_recv() else {
+        return;
+    };
+    let mut statement = match conn.prepare(&query) {
+        Ok(statement) => statement,
+        Err(e) => {
+            let _ = reply.send(Err(e.into()));
+            return;
+        }
+    };
+    let columns = column_names(&statement);
+    let mut query_rows = match statement.query(rusqlite::params_from_iter(parameters.iter())) {
+        Ok(query_rows) => query_rows,
+        Err(e) => {
+            let _ = reply.send(Err(e.into()));
+            return;
+        }
+    };
+    // the row read past the end of the last page, to tell whether any remained
+    let mut peeked: Option<Vec<SqlValue>> = None;
+    let mut next_page = Some((limit, reply));
+    while let Some((limit, reply)) = next_page.take().or_else(|| pages.blocking_recv()) {
+        let mut read_page = || -> Result<(SqliteRows, bool), SqliteError> {
+            let mut rows: Vec<Vec<SqlValue>> = peeked.take().into_iter().collect();
+            while (rows.len() as u64) < limit.max(1) {
+                match next_row(&mut query_rows, columns.len())? {
+                    Some(row) => rows.push(row),
+                    None => {
+                        let columns = columns.clone();
+                        return Ok((SqliteRows { columns, rows }, false));
+                    }
+                }
+            }
+            peeked = next_row(&mut query_rows, columns.len())?;
+            let more = peeked.is_some();
+            let columns = columns.clone();
+            Ok((SqliteRows { columns, rows }, more))
+        };
+        let page = read_page();
+        let done = !matches!(page, Ok((_, true)));
+        let _ = reply.send(page);
+        if done {
+            return;
+        }
+    }
+}
+
+fn column_names(statement: &rusqlite::Statement) -> Vec<String> {
+    statement
+        .column_names()
+        .iter()
+        .map(|c| c.to_string())
+        .collect()
+}
+
+fn next_row(
+    query_rows: &mut rusqlite::Rows,
+    column_count: usize,
+) -> Result<Option<Vec<SqlValue>>, SqliteError> {
+    let Some(row) = query_rows.next()? else {
+        return Ok(None);
+    };
+    Ok(Some(
+        (0..column_count)
+            .map(|i| row.get::<_, SqlValue>(i))
+            .collect::<Result<Vec<_>, _>>()?,
+    ))
+}
+
+fn rows_to_json(rows: SqliteRows) -> serde_json::Value {
+    let SqliteRows { columns, rows } = rows;
+    rows.into_iter()
+        .map(|row| {
+            columns
+                .iter()
+                .cloned()
+                .zip(row.into_iter().map(sqlite_to_json))
+                .collect::<serde_json::Map<_, _>>()
+        })
+        .collect()
+}
+
+fn sqlite_to_json(value: SqlValue) -> serde_json::Value {
+    match value {
+        SqlValue::Integer(int) => serde_json::Value::Number(int.into()),
+        // NaN and infinities have no JSON representation
+        SqlValue::Real(real) => serde_json::Number::from_f64(real)
+            .map(serde_json::Value::Number)
+            .unwrap_or(serde_json::Value::Null),
+        SqlValue::Text(text) => serde_json::Value::String(text),
+        SqlValue::Blob(blob) => serde_json::Value::String(base64::encode(blob)),
+        SqlValue::Boolean(b) => serde_json::Value::Bool(b),
+        SqlValue::Null => serde_json::Value::Null,
+    }
+}
+
 fn json_to_sqlite(value: &serde_json::Value) -> Result<SqlValue, SqliteError> {
     match value {
         serde_json::Value::Number(n) => {
@@ -529,16 +1252,24 @@ fn json_to_sqlite(value: &serde_json::Value) -> Result<SqlValue, SqliteError> {
     }
 }
 
-fn get_json_params(blob: Option<LazyLoadBlob>) -> Result<Vec<SqlValue>, SqliteError> {
-    match blob {
-        None => Ok(vec![]),
-        Some(blob) => match serde_json::from_slice::<serde_json::Value>(&blob.bytes) {
-            Ok(serde_json::Value::Array(vec)) => vec
-                .iter()
-                .map(json_to_sqlite)
-                .collect::<Result<Vec<_>, _>>(),
-            _ => Err(SqliteError::InvalidParameters),
-        },
+fn get_params(
+    blob: Option<LazyLoadBlob>,
+    encoding: SqliteEncoding,
+) -> Result<Vec<SqlValue>, SqliteError> {
+    match (blob, encoding) {
+        (None, _) => Ok(vec![]),
+        (Some(blob), SqliteEncoding::Msgpack) => {
+            rmp_serde::from_slice(&blob.bytes).map_err(|_| SqliteError::InvalidParameters)
+        }
+        (Some(blob), SqliteEncoding::Json) => {
+            match serde_json::from_slice::<serde_json::Value>(&blob.bytes) {
+                Ok(serde_json::Value::Array(vec)) => vec
+                    .iter()
+                    .map(json_to_sqlite)
+                    .collect::<Result<Vec<_>, _>>(),
+                _ => Err(SqliteError::InvalidParameters),
+            }
+        }
     }
 }
 
@@ -591,7 +1322,7 @@ impl FromSql for SqlValue {
                 Ok(SqlValue::Text(text_str.to_string()))
             }
             ValueRef::Blob(b) => Ok(SqlValue::Blob(b.to_vec())),
-            _ => Err(FromSqlError::InvalidType),
+            ValueRef::Null => Ok(SqlValue::Null),
         }
     }
 }
@@ -602,6 +1333,15 @@ impl std::fmt::Display for SqliteAction {
     }
 }
 
+impl From<QuotaExceeded> for SqliteError {
+    fn from(err: QuotaExceeded) -> Self {
+        SqliteError::QuotaExceeded {
+            quota: err.quota,
+            usage: err.usage,
+        }
+    }
+}
+
 impl From<std::io::Error> for SqliteError {
     fn from(err: std::io::Error) -> Self {
         SqliteError::IOError {
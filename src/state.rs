@@ -1,7 +1,8 @@
 use anyhow::Result;
 use ring::signature;
 use rocksdb::checkpoint::Checkpoint;
-use rocksdb::{Options, DB};
+use rocksdb::{Options, WriteBatch, DB};
+use serde::Deserialize;
 use std::collections::{HashMap, VecDeque};
 use std::io::Read;
 use std::path::Path;
@@ -10,10 +11,53 @@ use tokio::fs;
 use tokio::io::AsyncWriteExt;
 use tokio::sync::Mutex;
 
+use crate::quota::StorageQuotas;
+use crate::snapshot::{create_snapshot, SnapshotStores};
 use crate::types::*;
 
 include!("bootstrapped_processes.rs");
 
+/// number of past versions of each process's state kept for `RestoreVersion`
+const STATE_HISTORY_LEN: usize = 10;
+
+/// key of the packages' storage quotas, which can't collide with a process's
+const QUOTAS_KEY: &[u8] = b"\0quotas";
+
+/// key of the layout version of the kernel's persisted `ProcessMap`
+const PROCESS_MAP_VERSION_KEY: &[u8] = b"\0process_map_version";
+
+/// the current layout of `PersistedProcess`. bump this, and add a migration to
+/// `decode_process_map`, whenever a field is added to it: `#[serde(default)]`
+/// does nothing for bincode.
+const PROCESS_MAP_VERSION: u32 = 1;
+
+/// `PersistedProcess` as stored before process map versions were recorded,
+/// i.e. before resource limits, restart policies, parents and capability grants.
+#[derive(Deserialize)]
+struct PersistedProcessV0 {
+    wasm_bytes_handle: String,
+    wit_version: Option<u32>,
+    on_exit: OnExit,
+    capabilities: HashMap<Capability, Vec<u8>>,
+    public: bool,
+}
+
+impl From<PersistedProcessV0> for PersistedProcess {
+    fn from(old: PersistedProcessV0) -> Self {
+        PersistedProcess {
+            wasm_bytes_handle: old.wasm_bytes_handle,
+            wit_version: old.wit_version,
+            on_exit: old.on_exit,
+            capabilities: old.capabilities,
+            capability_grants: HashMap::new(),
+            public: old.public,
+            limits: ProcessLimits::default(),
+            restart_policy: RestartPolicy::default(),
+            spawned_by: None,
+        }
+    }
+}
+
 pub async fn load_state(
     our_name: String,
     keypair: Arc<signature::Ed25519KeyPair>,
@@ -41,7 +85,27 @@ pub async fn load_state(
     let kernel_id = process_to_vec(KERNEL_PROCESS_ID.clone());
     match db.get(&kernel_id) {
         Ok(Some(value)) => {
-            process_map = bincode::deserialize::<ProcessMap>(&value).unwrap();
+            let version = db
+                .get(PROCESS_MAP_VERSION_KEY)
+                .ok()
+                .flatten()
+                .and_then(|version| bincode::deserialize::<u32>(&version).ok());
+            process_map = match decode_process_map(&value, version) {
+                Some(process_map) => process_map,
+                None => panic!(
+                    "failed to decode kernel state (process map version {:?})",
+                    version
+                ),
+            };
+            if version != Some(PROCESS_MAP_VERSION) {
+                db.put(&kernel_id, bincode::serialize(&process_map).unwrap())
+                    .unwrap();
+                db.put(
+                    PROCESS_MAP_VERSION_KEY,
+                    bincode::serialize(&PROCESS_MAP_VERSION).unwrap(),
+                )
+                .unwrap();
+            }
             // if our networking key changed, we need to re-sign all local caps
             process_map.iter_mut().for_each(|(_id, process)| {
                 process.capabilities.iter_mut().for_each(|(cap, sig)| {
@@ -57,6 +121,11 @@ pub async fn load_state(
         Ok(None) => {
             db.put(&kernel_id, bincode::serialize(&process_map).unwrap())
                 .unwrap();
+            db.put(
+                PROCESS_MAP_VERSION_KEY,
+                bincode::serialize(&PROCESS_MAP_VERSION).unwrap(),
+            )
+            .unwrap();
         }
         Err(e) => {
             panic!("failed to load kernel state from db: {:?}", e);
@@ -80,15 +149,58 @@ pub async fn load_state(
     Ok((process_map, db))
 }
 
+/// Decode the kernel's persisted process map, migrating it to the current
+/// layout if it was written by an older node.
+///
+/// Maps written before versions were recorded are tried in the current layout
+/// first, then in the original one. Decoding rejects trailing bytes, so a map
+/// can't be misread in the wrong layout.
+fn decode_process_map(value: &[u8], version: Option<u32>) -> Option<ProcessMap> {
+    use bincode::Options;
+    let options = || {
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .reject_trailing_bytes()
+    };
+    if let Ok(process_map) = options().deserialize::<ProcessMap>(value) {
+        return Some(process_map);
+    }
+    if version.is_some() {
+        return None;
+    }
+    options()
+        .deserialize::<HashMap<ProcessId, PersistedProcessV0>>(value)
+        .ok()
+        .map(|old| {
+            old.into_iter()
+                .map(|(process_id, process)| (process_id, process.into()))
+                .collect()
+        })
+}
+
+/// the packages' storage quotas, as last set with `StateAction::SetQuota`
+pub fn load_quotas(db: &DB) -> HashMap<PackageId, u64> {
+    match db.get(QUOTAS_KEY) {
+        Ok(Some(value)) => bincode::deserialize(&value).unwrap_or_default(),
+        _ => HashMap::new(),
+    }
+}
+
 pub async fn state_sender(
     our_name: String,
     send_to_loop: MessageSender,
     send_to_terminal: PrintSender,
     mut recv_state: MessageReceiver,
+    send_to_caps_oracle: CapMessageSender,
     db: DB,
     home_directory_path: String,
+    file_key: Vec<u8>,
+    snapshot_lock: SnapshotLock,
+    quotas: Arc<StorageQuotas>,
+    snapshot_stores: SnapshotStores,
 ) -> Result<(), anyhow::Error> {
     let db = Arc::new(db);
+    let file_key = Arc::new(file_key);
 
     let mut process_queues: HashMap<ProcessId, Arc<Mutex<VecDeque<KernelMessage>>>> =
         HashMap::new();
@@ -117,8 +229,13 @@ pub async fn state_sender(
                 let db_clone = db.clone();
                 let send_to_loop = send_to_loop.clone();
                 let send_to_terminal = send_to_terminal.clone();
+                let send_to_caps_oracle = send_to_caps_oracle.clone();
                 let our_name = our_name.clone();
                 let home_directory_path = home_directory_path.clone();
+                let file_key = file_key.clone();
+                let snapshot_lock = snapshot_lock.clone();
+                let quotas = quotas.clone();
+                let snapshot_stores = snapshot_stores.clone();
 
                 tokio::spawn(async move {
                     let mut queue_lock = queue.lock().await;
@@ -129,7 +246,12 @@ pub async fn state_sender(
                                 db_clone,
                                 send_to_loop.clone(),
                                 send_to_terminal,
+                                send_to_caps_oracle,
                                 home_directory_path,
+                                file_key,
+                                snapshot_lock,
+                                quotas,
+                                snapshot_stores,
                             )
                             .await
                             {
@@ -150,7 +272,12 @@ async fn handle_request(
     db: Arc<DB>,
     send_to_loop: MessageSender,
     _send_to_terminal: PrintSender,
+    send_to_caps_oracle: CapMessageSender,
     home_directory_path: String,
+    file_key: Arc<Vec<u8>>,
+    snapshot_lock: SnapshotLock,
+    quotas: Arc<StorageQuotas>,
+    snapshot_stores: SnapshotStores,
 ) -> Result<(), StateError> {
     let KernelMessage {
         id,
@@ -181,21 +308,26 @@ async fn handle_request(
         }
     };
 
+    check_caps(&our_name, &source, &send_to_caps_oracle, &action).await?;
+
     let (body, bytes) = match action {
         StateAction::SetState(process_id) => {
-            let key = process_to_vec(process_id);
-
             let Some(ref blob) = blob else {
                 return Err(StateError::BadBytes {
                     action: "SetState".into(),
                 });
             };
 
-            db.put(key, &blob.bytes)
-                .map_err(|e| StateError::RocksDBError {
-                    action: "SetState".into(),
-                    error: e.to_string(),
-                })?;
+            let mut batch = WriteBatch::default();
+            batch.put(process_to_vec(process_id.clone()), &blob.bytes);
+            // the kernel persists its process map on every change: not worth keeping
+            if process_id != *KERNEL_PROCESS_ID {
+                record_version(&db, &mut batch, &process_id, &blob.bytes)?;
+            }
+            db.write(batch).map_err(|e| StateError::RocksDBError {
+                action: "SetState".into(),
+                error: e.to_string(),
+            })?;
 
             (serde_json::to_vec(&StateResponse::SetState).unwrap(), None)
         }
@@ -221,8 +353,12 @@ async fn handle_request(
             }
         }
         StateAction::DeleteState(process_id) => {
-            let key = process_to_vec(process_id);
-            match db.delete(key) {
+            // past versions go along with the state, in the same write
+            let prefix = history_prefix(&process_id);
+            let mut batch = WriteBatch::default();
+            batch.delete(process_to_vec(process_id));
+            batch.delete_range(&prefix, &history_prefix_end(&prefix));
+            match db.write(batch) {
                 Ok(_) => (
                     serde_json::to_vec(&StateResponse::DeleteState).unwrap(),
                     None,
@@ -256,6 +392,87 @@ async fn handle_request(
 
             (serde_json::to_vec(&StateResponse::Backup).unwrap(), None)
         }
+        StateAction::Snapshot => {
+            let path = create_snapshot(
+                &home_directory_path,
+                &file_key,
+                &db,
+                &snapshot_lock,
+                &snapshot_stores,
+            )
+            .await?;
+            (
+                serde_json::to_vec(&StateResponse::Snapshot { path }).unwrap(),
+                None,
+            )
+        }
+        StateAction::ListVersions(process_id) => {
+            let versions = list_versions(&db, &process_id)?
+                .into_iter()
+                .map(|(version, _)| version)
+                .collect();
+            (
+                serde_json::to_vec(&StateResponse::ListVersions { versions }).unwrap(),
+                None,
+            )
+        }
+        StateAction::RestoreVersion {
+            process_id,
+            version,
+        } => {
+            let Some((_, key)) = list_versions(&db, &process_id)?
+                .into_iter()
+                .find(|(v, _)| v.version == version)
+            else {
+                return Err(StateError::VersionNotFound {
+                    process_id,
+                    version,
+                });
+            };
+            let bytes = db
+                .get(key)
+                .map_err(|e| StateError::RocksDBError {
+                    action: "RestoreVersion".into(),
+                    error: e.to_string(),
+                })?
+                .ok_or(StateError::VersionNotFound {
+                    process_id: process_id.clone(),
+                    version,
+                })?;
+
+            let mut batch = WriteBatch::default();
+            batch.put(process_to_vec(process_id.clone()), &bytes);
+            record_version(&db, &mut batch, &process_id, &bytes)?;
+            db.write(batch).map_err(|e| StateError::RocksDBError {
+                action: "RestoreVersion".into(),
+                error: e.to_string(),
+            })?;
+
+            (
+                serde_json::to_vec(&StateResponse::RestoreVersion).unwrap(),
+                None,
+            )
+        }
+        StateAction::SetQuota { package_id, quota } => {
+            quotas.set(package_id, quota);
+            db.put(QUOTAS_KEY, bincode::serialize(&quotas.limits()).unwrap())
+                .map_err(|e| StateError::RocksDBError {
+                    action: "SetQuota".into(),
+                    error: e.to_string(),
+                })?;
+            (serde_json::to_vec(&StateResponse::SetQuota).unwrap(), None)
+        }
+        StateAction::StorageUsage => {
+            let (packages, graphdb_shared) = quotas.report().await;
+            (
+                serde_json::to_vec(&StateResponse::StorageUsage {
+                    packages,
+                    graphdb_shared,
+                })
+                .unwrap(),
+                None,
+            )
+        }
     };
 
     if let Some(target) = rsvp.or_else(|| {
@@ -293,6 +510,53 @@ async fn handle_request(
     Ok(())
 }
 
+/// Processes' own state is read and written through the kernel, which any
+/// process with messaging access may also ask of us. Setting quotas, rolling
+/// back state and taking snapshots reach across packages, so other processes
+/// need a capability from us for those: `"quota"`, `"rollback"` or `"snapshot"`.
+async fn check_caps(
+    our_name: &str,
+    source: &Address,
+    send_to_caps_oracle: &CapMessageSender,
+    action: &StateAction,
+) -> Result<(), StateError> {
+    if source.process == *KERNEL_PROCESS_ID {
+        return Ok(());
+    }
+    let params = match action {
+        StateAction::SetQuota { .. } | StateAction::StorageUsage => "quota",
+        StateAction::ListVersions(_) | StateAction::RestoreVersion { .. } => "rollback",
+        StateAction::Snapshot => "snapshot",
+        _ => return Ok(()),
+    };
+    let (send_cap_bool, recv_cap_bool) = tokio::sync::oneshot::channel();
+    send_to_caps_oracle
+        .send(CapMessage::Has {
+            on: source.process.clone(),
+            cap: Capability {
+                issuer: Address {
+                    node: our_name.to_string(),
+                    process: STATE_PROCESS_ID.clone(),
+                },
+                params: serde_json::to_string(params).unwrap(),
+            },
+            responder: send_cap_bool,
+        })
+        .await
+        .map_err(|e| StateError::NoCap {
+            error: e.to_string(),
+        })?;
+    let has_cap = recv_cap_bool.await.map_err(|e| StateError::NoCap {
+        error: e.to_string(),
+    })?;
+    if !has_cap {
+        return Err(StateError::NoCap {
+            error: format!("{:?}", action),
+        });
+    }
+    Ok(())
+}
+
 /// function run only upon fresh boot.
 ///
 /// for each folder in /modules, looks for a package.zip file, extracts the contents,
@@ -358,7 +622,11 @@ async fn bootstrap(
             wit_version: None,
             on_exit: OnExit::Restart,
             capabilities: runtime_caps.clone(),
+            capability_grants: HashMap::new(),
             public: false,
+            limits: ProcessLimits::default(),
+            restart_policy: RestartPolicy::default(),
+            spawned_by: None,
         });
     current_kernel.capabilities.extend(runtime_caps.clone());
     let current_net = process_map
@@ -368,7 +636,11 @@ async fn bootstrap(
             wit_version: None,
             on_exit: OnExit::Restart,
             capabilities: runtime_caps.clone(),
+            capability_grants: HashMap::new(),
             public: false,
+            limits: ProcessLimits::default(),
+            restart_policy: RestartPolicy::default(),
+            spawned_by: None,
         });
     current_net.capabilities.extend(runtime_caps.clone());
     for runtime_module in runtime_extensions {
@@ -379,7 +651,11 @@ async fn bootstrap(
                 wit_version: None,
                 on_exit: OnExit::Restart,
                 capabilities: runtime_caps.clone(),
+                capability_grants: HashMap::new(),
                 public: runtime_module.2,
+                limits: ProcessLimits::default(),
+                restart_policy: RestartPolicy::default(),
+                spawned_by: None,
             });
         current.capabilities.extend(runtime_caps.clone());
     }
@@ -619,7 +895,11 @@ async fn bootstrap(
                         wit_version: None,
                         on_exit: entry.on_exit,
                         capabilities: requested_caps,
+                        capability_grants: HashMap::new(),
                         public: public_process,
+                        limits: ProcessLimits::default(),
+                        restart_policy: RestartPolicy::default(),
+                        spawned_by: None,
                     });
                 }
             }
@@ -795,3 +1075,97 @@ fn make_error_message(our_name: String, km: &KernelMessage, error: StateError) -
 fn process_to_vec(process: ProcessId) -> Vec<u8> {
     process.to_string().as_bytes().to_vec()
 }
+
+/// past versions of a process's state live under this prefix, followed by the
+/// version number, then its timestamp and length, so that versions can be
+/// listed without reading their blobs. a process id never contains a NUL, so
+/// these keys can't collide with a process's own key, nor the prefix of one
+/// process with another's.
+fn history_prefix(process_id: &ProcessId) -> Vec<u8> {
+    [
+        b"\0history\0".as_slice(),
+        process_id.to_string().as_bytes(),
+        b"\0",
+    ]
+    .concat()
+}
+
+/// the first key past every key under a history prefix, which ends in a NUL
+fn history_prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    *end.last_mut().unwrap() = 1;
+    end
+}
+
+/// the kept versions of a process's state and their keys, oldest first
+fn list_versions(
+    db: &DB,
+    process_id: &ProcessId,
+) -> Result<Vec<(StateVersion, Vec<u8>)>, StateError> {
+    let prefix = history_prefix(process_id);
+    let mut versions = Vec::new();
+    let mut iter = db.raw_iterator();
+    iter.seek(&prefix);
+    while let Some(key) = iter.key() {
+        let Some(fields) = key.strip_prefix(prefix.as_slice()) else {
+            break;
+        };
+        let field = |i: usize| {
+            fields
+                .get(i * 8..(i + 1) * 8)
+                .map(|field| u64::from_be_bytes(field.try_into().unwrap()))
+        };
+        let (Some(version), Some(timestamp), Some(len), 24) =
+            (field(0), field(1), field(2), fields.len())
+        else {
+            return Err(StateError::BadBytes {
+                action: "ListVersions".into(),
+            });
+        };
+        versions.push((
+            StateVersion {
+                version,
+                timestamp,
+                len,
+            },
+            key.to_vec(),
+        ));
+        iter.next();
+    }
+    iter.status().map_err(|e| StateError::RocksDBError {
+        action: "ListVersions".into(),
+        error: e.to_string(),
+    })?;
+    Ok(versions)
+}
+
+/// add `bytes` to the batch as the newest version of a process's state,
+/// dropping the oldest versions beyond `STATE_HISTORY_LEN`
+fn record_version(
+    db: &DB,
+    batch: &mut WriteBatch,
+    process_id: &ProcessId,
+    bytes: &[u8],
+) -> Result<(), StateError> {
+    let versions = list_versions(db, process_id)?;
+
+    let next = versions.last().map_or(1, |(v, _)| v.version + 1);
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let key = [
+        history_prefix(process_id).as_slice(),
+        &next.to_be_bytes(),
+        &timestamp.to_be_bytes(),
+        &(bytes.len() as u64).to_be_bytes(),
+    ]
+    .concat();
+    batch.put(key, bytes);
+
+    let excess = (versions.len() + 1).saturating_sub(STATE_HISTORY_LEN);
+    for (_, key) in versions.iter().take(excess) {
+        batch.delete(key);
+    }
+    Ok(())
+}
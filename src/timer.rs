@@ -1,9 +1,12 @@
 use crate::types::{
-    Address, KernelMessage, Message, MessageReceiver, MessageSender, PrintSender, Printout,
-    Response, TIMER_PROCESS_ID,
+    Address, KernelMessage, KernelNotification, Message, MessageReceiver, MessageSender,
+    PrintSender, Printout, Response, SnapshotLock, TimerAction, TimerError, TimerResponse,
+    KERNEL_PROCESS_ID, TIMER_PROCESS_ID,
 };
 use anyhow::Result;
+use chrono::{Datelike, NaiveDateTime, Timelike};
 use serde::{Deserialize, Serialize};
+use tokio::fs;
 
 /// A runtime module that allows processes to set timers. Interacting with the
 /// timer is done with a simple Request/Response pattern, and the timer module
@@ -11,38 +14,92 @@ use serde::{Deserialize, Serialize};
 /// requests made by other nodes.
 ///
 /// The interface of the timer module is as follows:
-/// One kind of request is accepted: the IPC must be a little-endian byte-representation
-/// of an unsigned 64-bit integer, in milliseconds. This request should always expect a Response.
-/// If the request does not expect a Response, the timer will not be set.
+/// The IPC must be a JSON-serialized `TimerAction`, or, for backwards compatibility,
+/// a little-endian byte-representation of an unsigned 64-bit integer, in milliseconds.
+/// This request should always expect a Response.
 ///
-/// A proper Request will trigger the timer module to send a Response. The Response will be
-/// empty, so the user should either `send_and_await` the Request, or attach a `context` so
-/// they can match the Response with their purpose.
+/// A proper Request will trigger the timer module to send a Response when the timer pops.
+/// For the u64 form the Response will be empty, so the user should either `send_and_await`
+/// the Request, or attach a `context` so they can match the Response with their purpose.
+/// For a `TimerAction` the Response is a `TimerResponse::Pop`. Recurring and cron timers
+/// send a Response with the same id each time they pop: after the first, these will
+/// arrive without context.
+///
+/// Pending timers are persisted to disk, so they survive a reboot. Timers that
+/// came due while the node was off pop as soon as the kernel has booted, so that
+/// the processes they go to are running. Their Responses go to the original
+/// Request id and address, but any context or `send_and_await` the process had
+/// for them will have been lost with the process.
 ///
 pub async fn timer_service(
     our: String,
     kernel_message_sender: MessageSender,
     mut timer_message_receiver: MessageReceiver,
     print_tx: PrintSender,
+    home_directory_path: String,
+    snapshot_lock: SnapshotLock,
 ) -> Result<()> {
-    // if we have a persisted state file, load it
+    let timer_path = format!("{}/timer", &home_directory_path);
+    if let Err(e) = fs::create_dir_all(&timer_path).await {
+        panic!("failed creating timer dir! {:?}", e);
+    }
+    let timer_map_path = format!("{}/timers", &timer_path);
+
+    // if we have a persisted state file, load it. one that can't be read is
+    // kept aside rather than overwritten, so its timers can still be recovered.
     let mut timer_map = TimerMap {
         timers: nohash_hasher::IntMap::default(),
     };
+    if let Ok(bytes) = fs::read(&timer_map_path).await {
+        match TimerMap::decode(&bytes) {
+            Ok(persisted) => timer_map = persisted,
+            Err(e) => {
+                let corrupt_path = format!("{}.corrupt", timer_map_path);
+                let _ = fs::rename(&timer_map_path, &corrupt_path).await;
+                let _ = print_tx
+                    .send(Printout {
+                        verbosity: 0,
+                        content: format!(
+                            "timer: couldn't read persisted timers, moved them to {}: {}",
+                            corrupt_path, e
+                        ),
+                    })
+                    .await;
+            }
+        }
+    }
     // joinset holds 1 active timer per expiration-time
     let mut timer_tasks = tokio::task::JoinSet::<u64>::new();
+    // persisted timers are armed once the kernel has booted: until then, the
+    // processes they pop for aren't running and would never get their Responses
+    let mut booted = false;
+
     loop {
         tokio::select! {
             Some(km) = timer_message_receiver.recv() => {
                 // ignore Requests sent from other nodes
                 if km.source.node != our { continue };
-                // we only handle Requests which contain a little-endian u64 as IPC,
-                // except for a special "debug" message, which prints the current state
+                if km.source.process == *KERNEL_PROCESS_ID && !booted {
+                    if let Message::Request(ref req) = km.message {
+                        if let Ok(KernelNotification::Booted) = serde_json::from_slice(&req.body) {
+                            booted = true;
+                            let sender = &kernel_message_sender;
+                            pop_overdue(&our, &mut timer_map, &mut timer_tasks, sender).await;
+                            timer_map.persist(&timer_map_path, &snapshot_lock).await;
+                            continue
+                        }
+                    }
+                }
+                // we handle Requests which contain a TimerAction or a little-endian u64 as IPC,
+                // as well as a special "debug" message, which prints the current state
                 let Message::Request(req) = km.message else { continue };
                 if req.body == "debug".as_bytes() {
                     let _ = print_tx.send(Printout {
                         verbosity: 0,
-                        content: format!("timer service active timers ({}):", timer_map.timers.len()),
+                        content: format!(
+                            "timer service active timers ({}):",
+                            timer_map.timers.len()
+                        ),
                     }).await;
                     for (k, v) in timer_map.timers.iter() {
                         let _ = print_tx.send(Printout {
@@ -52,67 +109,411 @@ pub async fn timer_service(
                     }
                     continue
                 }
-                let Ok(bytes): Result<[u8; 8], _> = req.body.try_into() else { continue };
-                let timer_millis = u64::from_le_bytes(bytes);
-                // if the timer is set to pop in 0 millis, we immediately respond
+                let target = km.rsvp.unwrap_or(km.source);
+                let (schedule, action) = match parse_request(&req.body) {
+                    Ok(parsed) => parsed,
+                    Err(error) => {
+                        let body = timer_response(&TimerResponse::Err(error));
+                        send_response(&our, km.id, target, body, &kernel_message_sender).await;
+                        continue
+                    }
+                };
+                if let TimerAction::Cancel { id } = action {
+                    let response = if timer_map.cancel(id, &target) {
+                        timer_map.persist(&timer_map_path, &snapshot_lock).await;
+                        TimerResponse::Cancelled { id }
+                    } else {
+                        TimerResponse::Err(TimerError::NoSuchTimer { id })
+                    };
+                    if req.expects_response.is_some() {
+                        let body = timer_response(&response);
+                        send_response(&our, km.id, target, body, &kernel_message_sender).await;
+                    }
+                    continue
+                }
+                let now = now_ms();
+                let pop_time = match first_pop(action, now) {
+                    Ok(pop_time) => pop_time,
+                    Err(error) => {
+                        let body = timer_response(&TimerResponse::Err(error));
+                        send_response(&our, km.id, target, body, &kernel_message_sender).await;
+                        continue
+                    }
+                };
+                let timer = Timer { id: km.id, target, schedule };
+                // if the timer is already due, we immediately respond
                 // otherwise, store in our persisted map, and spawn a task that
                 // sleeps for the given time, then sends the response
-                let now = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis() as u64;
-                let pop_time = now + timer_millis;
-                if timer_millis == 0 {
-                    send_response(&our, km.id, km.rsvp.unwrap_or(km.source), &kernel_message_sender).await;
+                if pop_time <= now {
+                    let body = timer.pop_body();
+                    send_response(&our, timer.id, timer.target, body, &kernel_message_sender).await;
                     continue
                 }
                 let _ = print_tx.send(Printout {
                     verbosity: 1,
-                    content: format!("set timer to pop in {}ms", timer_millis),
+                    content: format!("set timer to pop in {}ms", pop_time - now),
                 }).await;
                 if !timer_map.contains(pop_time) {
-                    timer_tasks.spawn(async move {
-                        tokio::time::sleep(std::time::Duration::from_millis(timer_millis - 1)).await;
-                        pop_time
-                    });
+                    arm(&mut timer_tasks, pop_time, now);
                 }
-                timer_map.insert(pop_time, km.id, km.rsvp.unwrap_or(km.source));
+                timer_map.insert(pop_time, timer);
+                timer_map.persist(&timer_map_path, &snapshot_lock).await;
             }
             Some(Ok(time)) = timer_tasks.join_next() => {
                 // when a timer pops, we send the response to the process(es) that set
-                // the timer(s), and then remove it from our persisted map
-                let Some(timers) = timer_map.remove(time) else { continue };
-                for (id, addr) in timers {
-                    send_response(&our, id, addr, &kernel_message_sender).await;
-                }
+                // the timer(s), re-arm the recurring ones, and update our persisted map
+                if !timer_map.contains(time) { continue };
+                let sender = &kernel_message_sender;
+                pop_timers(&our, time, &mut timer_map, &mut timer_tasks, sender).await;
+                timer_map.persist(&timer_map_path, &snapshot_lock).await;
             }
         }
     }
 }
 
+/// a Request's `TimerAction`, and how the timer it sets recurs. a raw u64
+/// body is a `SetOnce` in the legacy format.
+fn parse_request(body: &[u8]) -> Result<(Schedule, TimerAction), TimerError> {
+    if let Ok(bytes) = <[u8; 8]>::try_from(body) {
+        let millis = u64::from_le_bytes(bytes);
+        return Ok((Schedule::Legacy, TimerAction::SetOnce { millis }));
+    }
+    let action =
+        serde_json::from_slice::<TimerAction>(body).map_err(|e| TimerError::InputError {
+            error: e.to_string(),
+        })?;
+    let schedule = match &action {
+        TimerAction::Recurring { interval } => Schedule::Interval(*interval),
+        TimerAction::Cron { expr } => Schedule::Cron(expr.clone()),
+        _ => Schedule::Once,
+    };
+    Ok((schedule, action))
+}
+
+/// when a timer set at `now` first pops. a time too far off to represent
+/// saturates, and so never pops.
+fn first_pop(action: TimerAction, now: u64) -> Result<u64, TimerError> {
+    match action {
+        TimerAction::SetOnce { millis } => Ok(now.saturating_add(millis)),
+        TimerAction::SetAt { unix_ms } => Ok(unix_ms),
+        TimerAction::Recurring { interval: 0 } => Err(TimerError::InvalidInterval),
+        TimerAction::Recurring { interval } => Ok(now.saturating_add(interval)),
+        TimerAction::Cron { expr } => CronSchedule::parse(&expr)
+            .and_then(|cron| cron.next_after(now))
+            .ok_or(TimerError::InvalidCron { expr }),
+        TimerAction::Cancel { .. } => Err(TimerError::InputError {
+            error: "Cancel doesn't set a timer".into(),
+        }),
+    }
+}
+
+fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// pop every timer that came due while the node was off, and arm the rest
+async fn pop_overdue(
+    our: &str,
+    timer_map: &mut TimerMap,
+    timer_tasks: &mut tokio::task::JoinSet<u64>,
+    send_to_loop: &MessageSender,
+) {
+    let now = now_ms();
+    let pop_times: Vec<u64> = timer_map.timers.keys().cloned().collect();
+    for pop_time in pop_times {
+        if pop_time <= now {
+            pop_timers(our, pop_time, timer_map, timer_tasks, send_to_loop).await;
+        } else {
+            arm(timer_tasks, pop_time, now);
+        }
+    }
+}
+
+/// spawn a task that finishes when `pop_time` is reached
+fn arm(timer_tasks: &mut tokio::task::JoinSet<u64>, pop_time: u64, now: u64) {
+    timer_tasks.spawn(async move {
+        tokio::time::sleep(std::time::Duration::from_millis(
+            pop_time.saturating_sub(now).saturating_sub(1),
+        ))
+        .await;
+        pop_time
+    });
+}
+
+/// send responses for every timer at `pop_time`, and re-insert the ones that recur
+async fn pop_timers(
+    our: &str,
+    pop_time: u64,
+    timer_map: &mut TimerMap,
+    timer_tasks: &mut tokio::task::JoinSet<u64>,
+    send_to_loop: &MessageSender,
+) {
+    let Some(timers) = timer_map.remove(pop_time) else {
+        return;
+    };
+    let now = now_ms();
+    for timer in timers {
+        send_response(
+            our,
+            timer.id,
+            timer.target.clone(),
+            timer.pop_body(),
+            send_to_loop,
+        )
+        .await;
+        let Some(next) = timer.next_pop(pop_time, now) else {
+            continue;
+        };
+        if !timer_map.contains(next) {
+            arm(timer_tasks, next, now);
+        }
+        timer_map.insert(next, timer);
+    }
+}
+
+fn timer_response(response: &TimerResponse) -> Vec<u8> {
+    serde_json::to_vec(response).unwrap()
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct TimerMap {
     // key: the unix timestamp in milliseconds at which the timer pops
-    // value: a vector of KernelMessage ids and who to send Response to
+    // value: a vector of timers, holding KernelMessage ids and who to send Response to
     // this is because multiple processes can set timers for the same time
+    timers: nohash_hasher::IntMap<u64, Vec<Timer>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Timer {
+    id: u64,
+    target: Address,
+    schedule: Schedule,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Schedule {
+    /// set with a raw u64: pops once, with an empty body
+    Legacy,
+    Once,
+    Interval(u64),
+    Cron(String),
+}
+
+impl Timer {
+    fn pop_body(&self) -> Vec<u8> {
+        match self.schedule {
+            Schedule::Legacy => vec![],
+            _ => timer_response(&TimerResponse::Pop { id: self.id }),
+        }
+    }
+
+    /// the next time a recurring timer that popped at `popped` should pop.
+    /// missed pops (e.g. while the node was off) are skipped, not replayed.
+    fn next_pop(&self, popped: u64, now: u64) -> Option<u64> {
+        match &self.schedule {
+            Schedule::Legacy | Schedule::Once => None,
+            Schedule::Interval(interval) => {
+                let behind = now.saturating_sub(popped) / interval;
+                behind
+                    .checked_add(1)?
+                    .checked_mul(*interval)?
+                    .checked_add(popped)
+            }
+            Schedule::Cron(expr) => CronSchedule::parse(expr)?.next_after(now.max(popped)),
+        }
+    }
+}
+
+/// `TimerMap` as persisted before recurring, cron and cancellable timers: each
+/// timer a raw-u64 one, held as its Request id and who to send the Response to
+#[derive(Deserialize)]
+struct LegacyTimerMap {
     timers: nohash_hasher::IntMap<u64, Vec<(u64, Address)>>,
 }
 
 impl TimerMap {
-    fn insert(&mut self, pop_time: u64, id: u64, addr: Address) {
-        self.timers.entry(pop_time).or_default().push((id, addr));
+    /// read a persisted map, in the current format or the legacy one. decoding
+    /// rejects trailing bytes, so neither format can be misread as the other.
+    fn decode(bytes: &[u8]) -> bincode::Result<Self> {
+        use bincode::Options;
+        let options = || {
+            bincode::DefaultOptions::new()
+                .with_fixint_encoding()
+                .reject_trailing_bytes()
+        };
+        options().deserialize::<TimerMap>(bytes).or_else(|e| {
+            let legacy = options()
+                .deserialize::<LegacyTimerMap>(bytes)
+                .map_err(|_| e)?;
+            Ok(TimerMap {
+                timers: legacy
+                    .timers
+                    .into_iter()
+                    .map(|(pop_time, timers)| {
+                        let timers = timers
+                            .into_iter()
+                            .map(|(id, target)| Timer {
+                                id,
+                                target,
+                                schedule: Schedule::Legacy,
+                            })
+                            .collect();
+                        (pop_time, timers)
+                    })
+                    .collect(),
+            })
+        })
+    }
+
+    fn insert(&mut self, pop_time: u64, timer: Timer) {
+        self.timers.entry(pop_time).or_default().push(timer);
     }
 
     fn contains(&mut self, pop_time: u64) -> bool {
         self.timers.contains_key(&pop_time)
     }
 
-    fn remove(&mut self, pop_time: u64) -> Option<Vec<(u64, Address)>> {
+    fn remove(&mut self, pop_time: u64) -> Option<Vec<Timer>> {
         self.timers.remove(&pop_time)
     }
+
+    /// remove the timer with this id, if it was set by `owner`
+    fn cancel(&mut self, id: u64, owner: &Address) -> bool {
+        let mut cancelled = false;
+        self.timers.retain(|_, timers| {
+            timers.retain(|timer| {
+                let matches = timer.id == id && &timer.target == owner;
+                cancelled |= matches;
+                !matches
+            });
+            !timers.is_empty()
+        });
+        cancelled
+    }
+
+    /// write the map to disk through a temporary file, so a crash mid-write
+    /// can't corrupt it, waiting out any snapshot being taken
+    async fn persist(&self, path: &str, snapshot_lock: &SnapshotLock) {
+        let Ok(bytes) = bincode::serialize(self) else {
+            return;
+        };
+        let _snapshot_guard = snapshot_lock.read().await;
+        let tmp_path = format!("{}.tmp", path);
+        if fs::write(&tmp_path, bytes).await.is_ok() {
+            let _ = fs::rename(&tmp_path, path).await;
+        }
+    }
+}
+
+/// A parsed 5-field cron expression: minute, hour, day of month, month, day of week.
+/// Each field accepts `*`, single values, ranges `a-b`, steps `*/n` or `a-b/n`,
+/// and comma-separated lists of these. As in standard cron, if both day fields are
+/// restricted, a day matching either one matches.
+struct CronSchedule {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days_of_month: Vec<bool>,
+    months: Vec<bool>,
+    days_of_week: Vec<bool>,
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+impl CronSchedule {
+    fn parse(expr: &str) -> Option<Self> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minutes, hours, days_of_month, months, days_of_week] = fields.as_slice() else {
+            return None;
+        };
+        let mut days_of_week_parsed = parse_cron_field(days_of_week, 0, 7)?;
+        // both 0 and 7 mean sunday
+        if days_of_week_parsed[7] {
+            days_of_week_parsed[0] = true;
+        }
+        Some(CronSchedule {
+            minutes: parse_cron_field(minutes, 0, 59)?,
+            hours: parse_cron_field(hours, 0, 23)?,
+            days_of_month: parse_cron_field(days_of_month, 1, 31)?,
+            months: parse_cron_field(months, 1, 12)?,
+            days_of_week: days_of_week_parsed,
+            any_day_of_month: *days_of_month == "*",
+            any_day_of_week: *days_of_week == "*",
+        })
+    }
+
+    fn day_matches(&self, time: &NaiveDateTime) -> bool {
+        let dom = self.days_of_month[time.day() as usize];
+        let dow = self.days_of_week[time.weekday().num_days_from_sunday() as usize];
+        match (self.any_day_of_month, self.any_day_of_week) {
+            (true, true) => true,
+            (false, true) => dom,
+            (true, false) => dow,
+            (false, false) => dom || dow,
+        }
+    }
+
+    /// the first whole minute strictly after `unix_ms` matching the schedule,
+    /// searching up to four years ahead
+    fn next_after(&self, unix_ms: u64) -> Option<u64> {
+        const MINUTES_PER_DAY: u64 = 24 * 60;
+        let mut minute = unix_ms / 60_000 + 1;
+        let limit = minute + 4 * 366 * MINUTES_PER_DAY;
+        while minute < limit {
+            let time = NaiveDateTime::from_timestamp_opt((minute * 60) as i64, 0)?;
+            if !self.months[time.month() as usize] || !self.day_matches(&time) {
+                minute = (minute / MINUTES_PER_DAY + 1) * MINUTES_PER_DAY;
+                continue;
+            }
+            if !self.hours[time.hour() as usize] {
+                minute = (minute / 60 + 1) * 60;
+                continue;
+            }
+            if self.minutes[time.minute() as usize] {
+                return Some(minute * 60_000);
+            }
+            minute += 1;
+        }
+        None
+    }
+}
+
+/// parse one cron field into a table indexed by value, of which values match
+fn parse_cron_field(field: &str, min: u32, max: u32) -> Option<Vec<bool>> {
+    let mut allowed = vec![false; max as usize + 1];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|s| *s > 0)?),
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (start.parse().ok()?, end.parse().ok()?)
+        } else {
+            let value = range.parse().ok()?;
+            // `a/n` means every n starting from a
+            (value, if step > 1 { max } else { value })
+        };
+        if start < min || end > max || start > end {
+            return None;
+        }
+        for value in (start..=end).step_by(step as usize) {
+            allowed[value as usize] = true;
+        }
+    }
+    Some(allowed)
 }
 
-async fn send_response(our_node: &str, id: u64, target: Address, send_to_loop: &MessageSender) {
+async fn send_response(
+    our_node: &str,
+    id: u64,
+    target: Address,
+    body: Vec<u8>,
+    send_to_loop: &MessageSender,
+) {
     let _ = send_to_loop
         .send(KernelMessage {
             id,
@@ -125,7 +526,7 @@ async fn send_response(our_node: &str, id: u64, target: Address, send_to_loop: &
             message: Message::Response((
                 Response {
                     inherit: false,
-                    body: vec![],
+                    body,
                     metadata: None,
                     capabilities: vec![],
                 },
@@ -135,3 +536,162 @@ async fn send_response(our_node: &str, id: u64, target: Address, send_to_loop: &
         })
         .await;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// unix time in milliseconds of a UTC date and time
+    fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> u64 {
+        chrono::NaiveDate::from_ymd_opt(year, month, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+            .timestamp_millis() as u64
+    }
+
+    fn values(field: &[bool]) -> Vec<usize> {
+        (0..field.len()).filter(|i| field[*i]).collect()
+    }
+
+    #[test]
+    fn cron_field_values_ranges_steps_and_lists() {
+        assert_eq!(values(&parse_cron_field("7", 0, 59).unwrap()), vec![7]);
+        assert_eq!(
+            values(&parse_cron_field("1,5-7,*/20", 0, 59).unwrap()),
+            vec![0, 1, 5, 6, 7, 20, 40]
+        );
+        assert_eq!(
+            values(&parse_cron_field("10-30/10", 0, 59).unwrap()),
+            vec![10, 20, 30]
+        );
+        assert_eq!(
+            values(&parse_cron_field("5/15", 0, 59).unwrap()),
+            vec![5, 20, 35, 50]
+        );
+        assert_eq!(
+            values(&parse_cron_field("*", 1, 12).unwrap()),
+            (1..=12).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn cron_rejects_malformed_expressions() {
+        assert!(parse_cron_field("60", 0, 59).is_none());
+        assert!(parse_cron_field("0", 1, 31).is_none());
+        assert!(parse_cron_field("5-1", 0, 59).is_none());
+        assert!(parse_cron_field("*/0", 0, 59).is_none());
+        assert!(parse_cron_field("a", 0, 59).is_none());
+        assert!(parse_cron_field("1,", 0, 59).is_none());
+        assert!(CronSchedule::parse("* * * *").is_none());
+        assert!(CronSchedule::parse("* * * * * *").is_none());
+    }
+
+    #[test]
+    fn cron_next_after_is_the_next_whole_minute() {
+        let cron = CronSchedule::parse("* * * * *").unwrap();
+        assert_eq!(
+            cron.next_after(at(2024, 1, 1, 0, 0) + 30_000),
+            Some(at(2024, 1, 1, 0, 1))
+        );
+        assert_eq!(
+            cron.next_after(at(2024, 1, 1, 0, 0)),
+            Some(at(2024, 1, 1, 0, 1))
+        );
+    }
+
+    #[test]
+    fn cron_next_after_rolls_over_hours_days_months_and_years() {
+        let cron = CronSchedule::parse("30 9 * * *").unwrap();
+        assert_eq!(
+            cron.next_after(at(2024, 1, 31, 10, 0)),
+            Some(at(2024, 2, 1, 9, 30))
+        );
+        let cron = CronSchedule::parse("0 0 1 1 *").unwrap();
+        assert_eq!(
+            cron.next_after(at(2024, 6, 1, 0, 0)),
+            Some(at(2025, 1, 1, 0, 0))
+        );
+        let cron = CronSchedule::parse("0 0 29 2 *").unwrap();
+        assert_eq!(
+            cron.next_after(at(2024, 3, 1, 0, 0)),
+            Some(at(2028, 2, 29, 0, 0))
+        );
+        assert!(CronSchedule::parse("0 0 31 2 *")
+            .unwrap()
+            .next_after(at(2024, 1, 1, 0, 0))
+            .is_none());
+    }
+
+    #[test]
+    fn cron_day_fields() {
+        // 2024-01-01 was a monday
+        let sunday_noon = Some(at(2024, 1, 7, 12, 0));
+        let cron = CronSchedule::parse("0 12 * * 0").unwrap();
+        assert_eq!(cron.next_after(at(2024, 1, 1, 0, 0)), sunday_noon);
+        let cron = CronSchedule::parse("0 12 * * 7").unwrap();
+        assert_eq!(cron.next_after(at(2024, 1, 1, 0, 0)), sunday_noon);
+        // with both day fields restricted, either one matching is enough
+        let cron = CronSchedule::parse("0 0 15 * 1").unwrap();
+        assert_eq!(
+            cron.next_after(at(2024, 1, 2, 0, 0)),
+            Some(at(2024, 1, 8, 0, 0))
+        );
+        assert_eq!(
+            cron.next_after(at(2024, 1, 9, 0, 0)),
+            Some(at(2024, 1, 15, 0, 0))
+        );
+    }
+
+    #[test]
+    fn intervals_skip_missed_pops_and_stop_on_overflow() {
+        let timer = |interval| Timer {
+            id: 0,
+            target: Address {
+                node: "our".into(),
+                process: TIMER_PROCESS_ID.clone(),
+            },
+            schedule: Schedule::Interval(interval),
+        };
+        assert_eq!(timer(1_000).next_pop(1_000, 1_500), Some(2_000));
+        assert_eq!(timer(1_000).next_pop(1_000, 3_500), Some(4_000));
+        assert_eq!(timer(100).next_pop(u64::MAX - 10, u64::MAX - 10), None);
+        assert_eq!(
+            first_pop(TimerAction::SetOnce { millis: u64::MAX }, 5).unwrap(),
+            u64::MAX
+        );
+    }
+
+    #[test]
+    fn decodes_legacy_timer_maps() {
+        let target = Address {
+            node: "our".into(),
+            process: TIMER_PROCESS_ID.clone(),
+        };
+        let mut legacy: nohash_hasher::IntMap<u64, Vec<(u64, Address)>> = Default::default();
+        legacy.insert(1_000, vec![(7, target.clone())]);
+        let decoded = TimerMap::decode(&bincode::serialize(&legacy).unwrap()).unwrap();
+        let timers = &decoded.timers[&1_000];
+        assert_eq!(timers.len(), 1);
+        assert_eq!(timers[0].id, 7);
+        assert_eq!(timers[0].target, target);
+        assert!(matches!(timers[0].schedule, Schedule::Legacy));
+
+        let mut current = TimerMap {
+            timers: Default::default(),
+        };
+        current.insert(
+            2_000,
+            Timer {
+                id: 8,
+                target,
+                schedule: Schedule::Cron("* * * * *".into()),
+            },
+        );
+        let decoded = TimerMap::decode(&bincode::serialize(&current).unwrap()).unwrap();
+        assert!(matches!(
+            decoded.timers[&2_000][0].schedule,
+            Schedule::Cron(ref expr) if expr == "* * * * *"
+        ));
+    }
+}
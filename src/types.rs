@@ -16,6 +16,10 @@ lazy_static::lazy_static! {
     pub static ref KV_PROCESS_ID: ProcessId = ProcessId::new(Some("kv"), "distro", "sys");
     pub static ref SQLITE_PROCESS_ID: ProcessId = ProcessId::new(Some("sqlite"), "distro", "sys");
     pub static ref GRAPHDB_PROCESS_ID: ProcessId = ProcessId::new(Some("graphdb"), "distro", "sys");
+    /// runtime modules sent a `KernelNotification::ProcessExited` whenever a process is killed
+    pub static ref PROCESS_EXIT_LISTENERS: Vec<ProcessId> = vec![GRAPHDB_PROCESS_ID.clone(), KV_PROCESS_ID.clone(), SQLITE_PROCESS_ID.clone(), VFS_PROCESS_ID.clone()];
+    /// runtime modules sent a `KernelNotification::Booted` once every process is running
+    pub static ref BOOT_LISTENERS: Vec<ProcessId> = vec![TIMER_PROCESS_ID.clone()];
 }
 
 //
@@ -453,6 +457,36 @@ impl std::fmt::Display for Capability {
     }
 }
 
+/// Restrictions placed on a capability held by a process, stored alongside its
+/// signature. The signature of a restricted capability covers both the capability
+/// and its grant, so neither can be altered. Capabilities without a grant are
+/// unrestricted and never expire.
+///
+/// Restricted capabilities cannot be attached to outgoing messages: they are
+/// shared by delegation instead, which keeps the chain back to the original.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CapabilityGrant {
+    /// Unix time in milliseconds at which the capability stops being valid.
+    pub expires: Option<u64>,
+    /// Set if the capability was minted by a holder from one of its own.
+    pub delegation: Option<Delegation>,
+}
+
+impl CapabilityGrant {
+    pub fn is_expired(&self, now_ms: u64) -> bool {
+        self.expires.map(|expires| now_ms >= expires).unwrap_or(false)
+    }
+}
+
+/// Link in a delegation chain: a delegated capability is only valid for as long
+/// as `delegator` holds `parent` with the same (valid) signature.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Delegation {
+    pub delegator: ProcessId,
+    pub parent: Capability,
+    pub parent_signature: Vec<u8>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SendError {
     pub kind: SendErrorKind,
@@ -465,6 +499,11 @@ pub struct SendError {
 pub enum SendErrorKind {
     Offline,
     Timeout,
+    /// the kernel dropped the message because the source lacks the
+    /// `"messaging"` or `"network"` capability it needed to send it
+    NoCapability,
+    /// the kernel dropped the message because the target process does not exist
+    ProcessNotFound,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -722,6 +761,8 @@ pub fn en_wit_send_error_kind(kind: SendErrorKind) -> wit::SendErrorKind {
     match kind {
         SendErrorKind::Offline => wit::SendErrorKind::Offline,
         SendErrorKind::Timeout => wit::SendErrorKind::Timeout,
+        SendErrorKind::NoCapability => wit::SendErrorKind::NoCapability,
+        SendErrorKind::ProcessNotFound => wit::SendErrorKind::ProcessNotFound,
     }
 }
 
@@ -749,6 +790,10 @@ pub type DebugReceiver = tokio::sync::mpsc::Receiver<DebugCommand>;
 pub type CapMessageSender = tokio::sync::mpsc::Sender<CapMessage>;
 pub type CapMessageReceiver = tokio::sync::mpsc::Receiver<CapMessage>;
 
+/// runtime modules with on-disk stores hold a read guard while handling each
+/// request; a snapshot holds the write guard while it copies their files.
+pub type SnapshotLock = std::sync::Arc<tokio::sync::RwLock<()>>;
+
 //
 // types used for onchain identity system
 //
@@ -842,6 +887,121 @@ pub struct ProcessMetadata {
     pub wit_version: u32,
     pub on_exit: OnExit,
     pub public: bool,
+    pub limits: ProcessLimits,
+    pub restart_policy: RestartPolicy,
+    pub spawned_by: Option<ProcessId>,
+}
+
+/// Resource limits enforced by the kernel on a single process.
+/// A limit of `None` means the resource is unlimited.
+/// A process that exceeds any of its limits is killed, and its `OnExit` behavior fired.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProcessLimits {
+    /// maximum size, in bytes, that the linear memory of the process may grow to
+    pub max_memory_bytes: Option<usize>,
+    /// maximum number of table elements the process may allocate
+    pub max_table_elements: Option<u32>,
+    /// maximum time, in milliseconds, the process may spend executing between
+    /// two received messages. measured in kernel epoch ticks, so this is only
+    /// accurate to within `EPOCH_TICK_MS`.
+    pub max_cpu_ms_per_message: Option<u64>,
+}
+
+impl std::fmt::Display for ProcessLimits {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn show<T: std::fmt::Display>(limit: &Option<T>) -> String {
+            match limit {
+                Some(limit) => limit.to_string(),
+                None => "unlimited".into(),
+            }
+        }
+        write!(
+            f,
+            "{{ max_memory_bytes: {}, max_table_elements: {}, max_cpu_ms_per_message: {} }}",
+            show(&self.max_memory_bytes),
+            show(&self.max_table_elements),
+            show(&self.max_cpu_ms_per_message),
+        )
+    }
+}
+
+/// Governs when a process with `OnExit::Restart` is restarted, and how often.
+/// Restarts are delayed with exponential backoff: the nth restart within the
+/// window waits `initial_backoff_ms * 2^(n-1)`, capped at `max_backoff_ms`.
+/// If more than `max_restarts` restarts happen within `window_ms`, the process
+/// is left dead and the kernel notifies the `supervisor`, if one is set.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RestartPolicy {
+    pub mode: RestartMode,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub max_restarts: u32,
+    pub window_ms: u64,
+    pub supervisor: Option<ProcessId>,
+}
+
+/// By default a process is restarted whether it exited or crashed, after 1s,
+/// doubling up to 30s, and left dead after 5 restarts within 5 minutes, so
+/// that a process failing at startup can't spin.
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy {
+            mode: RestartMode::Always,
+            initial_backoff_ms: 1_000,
+            max_backoff_ms: 30_000,
+            max_restarts: 5,
+            window_ms: 300_000,
+            supervisor: None,
+        }
+    }
+}
+
+impl RestartPolicy {
+    pub fn should_restart(&self, crashed: bool) -> bool {
+        match self.mode {
+            RestartMode::Always => true,
+            RestartMode::OnSuccess => !crashed,
+            RestartMode::OnCrash => crashed,
+        }
+    }
+
+    /// delay before the `attempt`th restart within the current window (1-indexed)
+    pub fn backoff(&self, attempt: u32) -> std::time::Duration {
+        let factor = 2u64.saturating_pow(attempt.saturating_sub(1));
+        std::time::Duration::from_millis(
+            self.initial_backoff_ms
+                .saturating_mul(factor)
+                .min(self.max_backoff_ms),
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RestartMode {
+    /// restart whether the process returned or crashed
+    Always,
+    /// restart only if the process returned without error
+    OnSuccess,
+    /// restart only if the process crashed
+    OnCrash,
+}
+
+impl std::fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{{ mode: {:?}, backoff: {}-{}ms, max_restarts: {} per {}ms, supervisor: {} }}",
+            self.mode,
+            self.initial_backoff_ms,
+            self.max_backoff_ms,
+            self.max_restarts,
+            self.window_ms,
+            match &self.supervisor {
+                Some(supervisor) => supervisor.to_string(),
+                None => "none".into(),
+            },
+        )
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -915,6 +1075,10 @@ pub enum KernelCommand {
     ///
     /// All capabilities passed into initial_capabilities must be held by the source
     /// of this message, or the kernel will discard them (silently for now).
+    ///
+    /// If `limits` are not given, the process will run without resource limits.
+    /// If `restart_policy` is not given, an `OnExit::Restart` process gets
+    /// `RestartPolicy::default()`.
     InitializeProcess {
         id: ProcessId,
         wasm_bytes_handle: String,
@@ -922,16 +1086,63 @@ pub enum KernelCommand {
         on_exit: OnExit,
         initial_capabilities: HashSet<Capability>,
         public: bool,
+        #[serde(default)]
+        limits: ProcessLimits,
+        #[serde(default)]
+        restart_policy: RestartPolicy,
+        /// Only honored when sent by the kernel itself, on behalf of a process
+        /// spawning a child or to keep track of parents across restarts.
+        /// Processes initialized by anyone else, e.g. installed by app_store,
+        /// have no parent.
+        #[serde(default)]
+        spawned_by: Option<ProcessId>,
+        /// Only honored when sent by the kernel itself: restrictions on
+        /// `initial_capabilities` to carry over across a restart.
+        #[serde(default)]
+        capability_grants: Vec<(Capability, CapabilityGrant)>,
     },
     /// Create an arbitrary capability and grant it to a process.
+    ///
+    /// If `expires` (unix time in milliseconds) is given, the capabilities
+    /// are only valid until then.
     GrantCapabilities {
         target: ProcessId,
         capabilities: Vec<Capability>,
+        #[serde(default)]
+        expires: Option<u64>,
+    },
+    /// Mint an attenuated copy of a capability held by the source of this message
+    /// and give it to `target`. The new capability must have the same issuer as
+    /// `parent`, and `params` that permit no more than the parent's: JSON arrays
+    /// may drop alternatives, JSON objects must keep the same fields, each
+    /// narrowed in turn, and anything else must be equal.
+    /// The new capability expires no later than `parent`, and is revoked along with it.
+    /// Refused if `target` already holds the capability unrestricted.
+    /// Answered with `KernelResponse::DelegatedCapability` if a response is expected.
+    DelegateCapability {
+        target: ProcessId,
+        parent: Capability,
+        params: String,
+        expires: Option<u64>,
     },
     /// Tell the kernel to run a process that has already been installed.
-    /// TODO: in the future, this command could be extended to allow for
-    /// resource provision.
+    /// Resources are provisioned with `SetProcessLimits`.
     RunProcess(ProcessId),
+    /// Set the resource limits of an installed process. The limits are persisted
+    /// and take effect the next time the process is started.
+    SetProcessLimits {
+        target: ProcessId,
+        limits: ProcessLimits,
+    },
+    /// Get the resource limits of an installed process.
+    GetProcessLimits(ProcessId),
+    /// Take capabilities away from a process. Only the issuer of a capability
+    /// (or the kernel) may revoke it; other capabilities are ignored (silently for now).
+    /// Revocation cascades to every process spawned by the target, and their children.
+    RevokeCapabilities {
+        target: ProcessId,
+        capabilities: Vec<Capability>,
+    },
     /// Kill a running process immediately. This may result in the dropping / mishandling of messages!
     KillProcess(ProcessId),
     /// RUNTIME ONLY: notify the kernel that the runtime is shutting down and it
@@ -948,6 +1159,18 @@ pub enum KernelPrint {
     HasCap { on: ProcessId, cap: Capability },
 }
 
+/// Requests sent by the kernel to a process's `RestartPolicy::supervisor`.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SupervisorNotification {
+    /// The process exceeded `max_restarts` within `window_ms` and was not restarted.
+    RestartLimitReached {
+        process: ProcessId,
+        restarts: u32,
+        window_ms: u64,
+        crashed: bool,
+    },
+}
+
 /// IPC format for all KernelCommand responses
 #[derive(Debug, Serialize, Deserialize)]
 pub enum KernelResponse {
@@ -956,6 +1179,25 @@ pub enum KernelResponse {
     StartedProcess,
     RunProcessError,
     KilledProcess(ProcessId),
+    SetProcessLimits,
+    SetProcessLimitsError,
+    GetProcessLimits(ProcessLimits),
+    GetProcessLimitsError,
+    RevokedCapabilities,
+    RevokeCapabilitiesError,
+    DelegatedCapability,
+    DelegateCapabilityError,
+}
+
+/// Sent as a Request by the kernel to runtime modules that keep per-process
+/// state (see `PROCESS_EXIT_LISTENERS`), so they can release it, or that need
+/// to wait for processes to be running (see `BOOT_LISTENERS`).
+#[derive(Debug, Serialize, Deserialize)]
+pub enum KernelNotification {
+    /// the process was killed, or exited and is about to be restarted
+    ProcessExited(ProcessId),
+    /// every process persisted across the reboot has been started
+    Booted,
 }
 
 #[derive(Debug)]
@@ -966,10 +1208,10 @@ pub enum CapMessage {
         caps: Vec<Capability>,
         responder: tokio::sync::oneshot::Sender<bool>,
     },
-    _Drop {
-        // not used yet!
+    /// remove all `caps` from `on`'s store. does not cascade to processes `on` spawned.
+    Drop {
         on: ProcessId,
-        cap: Capability,
+        caps: Vec<Capability>,
         responder: tokio::sync::oneshot::Sender<bool>,
     },
     /// does `on` have `cap` in its store?
@@ -979,6 +1221,11 @@ pub enum CapMessage {
         cap: Capability,
         responder: tokio::sync::oneshot::Sender<bool>,
     },
+    /// return the restrictions on all restricted caps in `on`'s store
+    GetGrants {
+        on: ProcessId,
+        responder: tokio::sync::oneshot::Sender<Vec<(Capability, CapabilityGrant)>>,
+    },
     /// return all caps in `on`'s store
     GetAll {
         on: ProcessId,
@@ -1001,14 +1248,18 @@ pub struct PersistedProcess {
     pub wit_version: Option<u32>,
     pub on_exit: OnExit,
     pub capabilities: HashMap<Capability, Vec<u8>>,
+    pub capability_grants: HashMap<Capability, CapabilityGrant>,
     pub public: bool, // marks if a process allows messages from any process
+    pub limits: ProcessLimits,
+    pub restart_policy: RestartPolicy,
+    pub spawned_by: Option<ProcessId>, // parent, if this process was spawned by another
 }
 
 impl std::fmt::Display for PersistedProcess {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "Process {{\n    wasm_bytes_handle: {},\n    wit_version: {},\n    on_exit: {:?},\n    public: {}\n    capabilities: {}\n}}",
+            "Process {{\n    wasm_bytes_handle: {},\n    wit_version: {},\n    on_exit: {:?},\n    public: {}\n    limits: {}\n    restart_policy: {}\n    capabilities: {}\n}}",
             {
                 if &self.wasm_bytes_handle == "" {
                     "(none, this is a runtime process)"
@@ -1019,6 +1270,8 @@ impl std::fmt::Display for PersistedProcess {
             self.wit_version.unwrap_or_default(),
             self.on_exit,
             self.public,
+            self.limits,
+            self.restart_policy,
             {
                 let mut caps_string = "[".to_string();
                 for cap in self.capabilities.keys() {
@@ -1067,8 +1320,59 @@ pub struct PackageManifestEntry {
 pub enum StateAction {
     GetState(ProcessId),
     SetState(ProcessId),
+    /// Delete a process's current state, along with its past versions.
     DeleteState(ProcessId),
     Backup,
+    /// Write an archive of all node data, encrypted with the keyfile's `file_key`,
+    /// to `snapshots/` in the home directory. Boot with `--restore <archive>` to
+    /// rehydrate a node from it. Requires the `"snapshot"` capability from state.
+    Snapshot,
+    /// List the past versions of a process's state that are kept, oldest first.
+    /// Requires the `"rollback"` capability from state.
+    ListVersions(ProcessId),
+    /// Make a past version of a process's state its current state. The restore
+    /// is itself recorded as a new version, so it can be undone in turn. A running
+    /// process only sees the restored state once it next reads its state,
+    /// usually on restart. Requires the `"rollback"` capability from state.
+    RestoreVersion {
+        process_id: ProcessId,
+        version: u64,
+    },
+    /// Limit the bytes a package may store, counted across all stores, or lift
+    /// its limit with `None`. vfs, kv, sqlite and graphdb writes past the limit
+    /// fail with `QuotaExceeded`. Requires the `"quota"` capability from state.
+    SetQuota {
+        package_id: PackageId,
+        quota: Option<u64>,
+    },
+    /// Report the bytes each package stores in vfs, kv, sqlite and graphdb.
+    /// Requires the `"quota"` capability from state.
+    StorageUsage,
+}
+
+/// Bytes a package stores on disk in each runtime store.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PackageStorageUsage {
+    pub package_id: PackageId,
+    pub quota: Option<u64>,
+    pub vfs: u64,
+    pub kv: u64,
+    pub sqlite: u64,
+    /// the bytes the package has written to graphdb, since graphdb keeps every
+    /// package's data in one store that can't be measured per package
+    pub graphdb: u64,
+    pub total: u64,
+}
+
+/// One past version of a process's state.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StateVersion {
+    /// increases by one with every write of the process's state
+    pub version: u64,
+    /// unix ms at which this version was written
+    pub timestamp: u64,
+    /// length of the state blob in bytes
+    pub len: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -1077,6 +1381,16 @@ pub enum StateResponse {
     SetState,
     DeleteState,
     Backup,
+    Snapshot { path: String },
+    ListVersions { versions: Vec<StateVersion> },
+    RestoreVersion,
+    SetQuota,
+    /// `graphdb_shared` is the rest of graphdb's store on disk, beyond what each
+    /// package has written to it, which can't be attributed to any one of them.
+    StorageUsage {
+        packages: Vec<PackageStorageUsage>,
+        graphdb_shared: u64,
+    },
     Err(StateError),
 }
 
@@ -1094,8 +1408,12 @@ pub enum StateError {
     BadJson { error: String },
     #[error("kernel_state: state not found for ProcessId {process_id}")]
     NotFound { process_id: ProcessId },
+    #[error("kernel_state: version {version} not found for ProcessId {process_id}")]
+    VersionNotFound { process_id: ProcessId, version: u64 },
     #[error("kernel_state: IO error: {error}")]
     IOError { error: String },
+    #[error("kernel_state: No capability: {error}")]
+    NoCap { error: String },
 }
 
 #[allow(dead_code)]
@@ -1108,7 +1426,9 @@ impl StateError {
             StateError::BadRequest { .. } => "BadRequest",
             StateError::BadJson { .. } => "NoJson",
             StateError::NotFound { .. } => "NotFound",
+            StateError::VersionNotFound { .. } => "VersionNotFound",
             StateError::IOError { .. } => "IOError",
+            StateError::NoCap { .. } => "NoCap",
         }
     }
 }
@@ -1147,6 +1467,70 @@ pub enum VfsAction {
     Len,
     SetLen(u64),
     Hash,
+    /// Receive a `VfsChange` Request whenever the file or directory at this path
+    /// is changed through the vfs. A watch on a directory also covers its direct
+    /// children, or, if `recursive`, everything beneath it.
+    Watch {
+        #[serde(default)]
+        recursive: bool,
+    },
+    /// Stop receiving `VfsChange`s for this path.
+    Unwatch,
+    /// Open a handle on the file at this path for `ReadAt` and `WriteAt`, which
+    /// only the requesting process can use. Handles are closed by `CloseHandle`,
+    /// or when the process exits.
+    OpenHandle { create: bool },
+    CloseHandle { handle: u64 },
+    /// Read up to `len` bytes from `offset`, leaving every cursor where it is.
+    /// Reads through `handle` if given, else through a fresh file descriptor.
+    ReadAt {
+        offset: u64,
+        len: u64,
+        #[serde(default)]
+        handle: Option<u64>,
+    },
+    /// Write the blob at `offset`, extending the file if needed, leaving every
+    /// cursor where it is. Writes through `handle` if given.
+    WriteAt {
+        offset: u64,
+        #[serde(default)]
+        handle: Option<u64>,
+    },
+    /// List everything below this directory, depth first with siblings in name
+    /// order, down to `max_depth` levels (every level if `None`).
+    ///
+    /// With `glob`, only entries whose path relative to this directory matches
+    /// are listed: `*` and `?` match within one path segment, `**` matches any
+    /// number of segments. At most `limit` entries (1000 if `None`) are
+    /// returned, along with a `cursor` to pass back for the next page.
+    Walk {
+        #[serde(default)]
+        max_depth: Option<u64>,
+        #[serde(default)]
+        glob: Option<String>,
+        #[serde(default)]
+        include_metadata: bool,
+        #[serde(default)]
+        limit: Option<u64>,
+        #[serde(default)]
+        cursor: Option<String>,
+    },
+}
+
+/// Sent as a Request by vfs to processes watching a path that changed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VfsChange {
+    pub path: String,
+    pub kind: VfsChangeKind,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum VfsChangeKind {
+    Create,
+    Modify,
+    Remove,
+    /// `path` was moved to `new_path`
+    Rename { new_path: String },
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -1168,6 +1552,9 @@ pub enum FileType {
 pub struct FileMetadata {
     pub file_type: FileType,
     pub len: u64,
+    /// last modification, in milliseconds since the unix epoch
+    #[serde(default)]
+    pub modified: Option<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -1176,6 +1563,14 @@ pub struct DirEntry {
     pub file_type: FileType,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WalkEntry {
+    pub path: String,
+    pub file_type: FileType,
+    /// present if the `Walk` asked for `include_metadata`
+    pub metadata: Option<FileMetadata>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum VfsResponse {
     Ok,
@@ -1187,6 +1582,12 @@ pub enum VfsResponse {
     Metadata(FileMetadata),
     Len(u64),
     Hash([u8; 32]),
+    Handle(u64),
+    /// `cursor` is `None` once the walk is complete
+    Walk {
+        entries: Vec<WalkEntry>,
+        cursor: Option<String>,
+    },
 }
 
 #[derive(Error, Debug, Serialize, Deserialize)]
@@ -1209,6 +1610,10 @@ pub enum VfsError {
     NotFound { path: String },
     #[error("vfs: Creating directory failed at path: {path}: {error}")]
     CreateDirError { path: String, error: String },
+    #[error("vfs: storage quota of {quota} bytes exceeded, {usage} bytes in use")]
+    QuotaExceeded { quota: u64, usage: u64 },
+    #[error("vfs: no file handle {handle} open on this path")]
+    NoHandle { handle: u64 },
 }
 
 #[allow(dead_code)]
@@ -1224,6 +1629,8 @@ impl VfsError {
             VfsError::BadJson { .. } => "NoJson",
             VfsError::NotFound { .. } => "NotFound",
             VfsError::CreateDirError { .. } => "CreateDirError",
+            VfsError::QuotaExceeded { .. } => "QuotaExceeded",
+            VfsError::NoHandle { .. } => "NoHandle",
         }
     }
 }
@@ -1232,6 +1639,9 @@ impl VfsError {
 pub struct KvRequest {
     pub package_id: PackageId,
     pub db: String,
+    /// the table (column family) within `db` to act on. if not given, acts on the default table.
+    #[serde(default)]
+    pub table: Option<String>,
     pub action: KvAction,
 }
 
@@ -1239,12 +1649,69 @@ pub struct KvRequest {
 pub enum KvAction {
     Open,
     RemoveDb,
-    Set { key: Vec<u8>, tx_id: Option<u64> },
+    /// If `ttl_ms` is given, the key expires that many milliseconds after it is set
+    /// (or, in a transaction, committed): `Get` will no longer return it, and it
+    /// is removed in the background.
+    Set {
+        key: Vec<u8>,
+        tx_id: Option<u64>,
+        #[serde(default)]
+        ttl_ms: Option<u64>,
+    },
     Delete { key: Vec<u8>, tx_id: Option<u64> },
     Get { key: Vec<u8> },
     BeginTx,
     Commit { tx_id: u64 },
     Backup,
+    /// List entries in key order, restricted to keys starting with `prefix` and
+    /// within `start` (inclusive) to `end` (exclusive), at most `limit` at a time.
+    /// Entries come back in the blob as a `KvIteratePage`: to get the next page,
+    /// send the same request with `cursor` as `start`, or as `end` if `reverse`.
+    Iterate {
+        prefix: Option<Vec<u8>>,
+        start: Option<Vec<u8>>,
+        end: Option<Vec<u8>>,
+        limit: Option<u64>,
+        reverse: bool,
+    },
+    /// Get many keys at once. Values come back in the blob as a bincode-serialized
+    /// `Vec<Option<Vec<u8>>>`, in the same order as `keys`.
+    MultiGet { keys: Vec<Vec<u8>> },
+    /// Apply many sets and deletes atomically. The blob must be a bincode-serialized
+    /// `Vec<Vec<u8>>` holding the value of each `Set` op, in order. The keys and
+    /// values are charged against the package's storage quota.
+    WriteBatch { ops: Vec<KvBatchOp> },
+    CreateTable { name: String },
+    DropTable { name: String },
+    ListTables,
+    /// Receive a `KvChange` Request whenever a key in this table starting with
+    /// `prefix` is set or deleted, including by a committed transaction, until
+    /// `Unwatch` or the process exits.
+    Watch { prefix: Vec<u8> },
+    /// Stop receiving `KvChange`s from the `Watch` on this table and `prefix`.
+    Unwatch { prefix: Vec<u8> },
+    Stats,
+}
+
+/// Sent as a Request by kv to processes watching a key that changed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KvChange {
+    pub package_id: PackageId,
+    pub db: String,
+    pub table: Option<String>,
+    pub key: Vec<u8>,
+    /// whether the key held a value before the change
+    pub existed: bool,
+    /// whether the key holds a value after the change
+    pub exists: bool,
+    /// set if the change was part of a committed transaction
+    pub tx_id: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum KvBatchOp {
+    Set { key: Vec<u8> },
+    Delete { key: Vec<u8> },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -1252,9 +1719,27 @@ pub enum KvResponse {
     Ok,
     BeginTx { tx_id: u64 },
     Get { key: Vec<u8> },
+    /// the blob is a JSON-serialized `KvIteratePage`
+    Iterate,
+    /// the blob is a bincode-serialized `Vec<Option<Vec<u8>>>`
+    MultiGet,
+    ListTables { tables: Vec<String> },
+    Stats {
+        /// keys that currently have a ttl, expired or not
+        ttl_keys: u64,
+        /// keys removed for having expired since boot
+        expired: u64,
+    },
     Err { error: KvError },
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KvIteratePage {
+    pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+    /// set if there are more entries in the range after this page
+    pub cursor: Option<Vec<u8>>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Error)]
 pub enum KvError {
     #[error("kv: DbDoesNotExist")]
@@ -1263,6 +1748,8 @@ pub enum KvError {
     KeyNotFound,
     #[error("kv: no Tx found")]
     NoTx,
+    #[error("kv: no table {table}")]
+    NoTable { table: String },
     #[error("kv: No capability: {error}")]
     NoCap { error: String },
     #[error("kv: rocksdb internal error: {error}")]
@@ -1271,6 +1758,8 @@ pub enum KvError {
     InputError { error: String },
     #[error("kv: IO error: {error}")]
     IOError { error: String },
+    #[error("kv: storage quota of {quota} bytes exceeded, {usage} bytes in use")]
+    QuotaExceeded { quota: u64, usage: u64 },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -1278,6 +1767,28 @@ pub struct SqliteRequest {
     pub package_id: PackageId,
     pub db: String,
     pub action: SqliteAction,
+    #[serde(default)]
+    pub encoding: SqliteEncoding,
+}
+
+/// How parameter blobs and `Read` result blobs are encoded.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub enum SqliteEncoding {
+    /// Parameters are a JSON array and rows are JSON objects keyed by column
+    /// name, with blobs as base64 strings.
+    #[default]
+    Json,
+    /// Parameters are a msgpack-serialized `Vec<SqlValue>` and results are a
+    /// msgpack-serialized `SqliteRows`, so values keep their exact types.
+    Msgpack,
+}
+
+/// `Read` results in the `Msgpack` encoding: the column names in query order,
+/// and each row's values in that same order.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SqliteRows {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<SqlValue>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -1288,21 +1799,72 @@ pub enum SqliteAction {
         statement: String,
         tx_id: Option<u64>,
     },
+    /// Run a query. With a `limit`, at most that many rows are returned, and the
+    /// `cursor` in the `ReadPage` response continues the query where it ended,
+    /// reading from the same snapshot of the db. A cursor is closed once all its
+    /// rows are read, after going unread for 30 seconds, or when the process
+    /// that opened it exits. A process may have at most 16 cursors open. Paged
+    /// reads can't be made inside a transaction.
     Read {
         query: String,
+        #[serde(default)]
+        tx_id: Option<u64>,
+        #[serde(default)]
+        limit: Option<u64>,
+        #[serde(default)]
+        cursor: Option<u64>,
     },
     BeginTx,
     Commit {
         tx_id: u64,
     },
+    Rollback {
+        tx_id: u64,
+    },
     Backup,
+    /// Bring the db's schema up to date. Each migration is a `(version, sql)`
+    /// step; those with a version above the db's `PRAGMA user_version` are
+    /// applied in version order, in a single transaction. Versions must be
+    /// positive, since `user_version` is an `i32` that starts at 0.
+    Migrate {
+        migrations: Vec<(i32, String)>,
+    },
+    /// Receive a `SqliteChange` Request for every row inserted, updated or
+    /// deleted in these tables (or in any table, if empty), once committed,
+    /// until `Unsubscribe` or the process exits.
+    Subscribe {
+        tables: Vec<String>,
+    },
+    /// Stop receiving `SqliteChange`s for this db.
+    Unsubscribe,
+}
+
+/// Sent as a Request by sqlite to processes subscribed to a table whose rows changed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SqliteChange {
+    pub package_id: PackageId,
+    pub db: String,
+    pub table: String,
+    pub rowid: i64,
+    pub kind: SqliteChangeKind,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum SqliteChangeKind {
+    Insert,
+    Update,
+    Delete,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum SqliteResponse {
     Ok,
     Read,
+    /// response to a `Read` with a `limit`: `cursor` continues the query, and is
+    /// `None` once every row has been returned
+    ReadPage { cursor: Option<u64> },
     BeginTx { tx_id: u64 },
+    Migrate { from: i32, to: i32 },
     Err { error: SqliteError },
 }
 
@@ -1322,6 +1884,12 @@ pub enum SqliteError {
     NoDb,
     #[error("sqlite: NoTx")]
     NoTx,
+    #[error("sqlite: NoCursor")]
+    NoCursor,
+    #[error("sqlite: a process may have at most {max} cursors open")]
+    TooManyCursors { max: usize },
+    #[error("sqlite: transaction statements can't be issued inside a tx")]
+    TxControlStatement,
     #[error("sqlite: No capability: {error}")]
     NoCap { error: String },
     #[error("sqlite: UnexpectedResponse")]
@@ -1338,6 +1906,8 @@ pub enum SqliteError {
     RusqliteError { error: String },
     #[error("sqlite: input bytes/json/key error: {error}")]
     InputError { error: String },
+    #[error("sqlite: storage quota of {quota} bytes exceeded, {usage} bytes in use")]
+    QuotaExceeded { quota: u64, usage: u64 },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -1347,41 +1917,303 @@ pub struct GraphDbRequest {
     pub action: GraphDbAction,
 }
 
+/// A resource to `DEFINE`. Statements are generated from these rather than
+/// from caller-provided SurrealQL, so no input can change the shape of the
+/// statement: literals are serialized, namespace, database and table names have
+/// every character but letters and digits stripped (as they always have), and
+/// other identifiers must be plain (letters, digits and underscores, not
+/// starting with a digit).
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum DefineResourceType {
-    Namespace { name: String },
-    Database { name: String },
-    Table { name: String },
+    Namespace {
+        name: String,
+    },
+    Database {
+        name: String,
+    },
+    /// A table is schemaless unless `schemafull` is set, in which case only
+    /// its defined fields can be written.
+    Table {
+        name: String,
+        #[serde(default)]
+        schemafull: bool,
+    },
+    /// `name` may be a dotted path into nested objects, with `*` for every
+    /// element of an array, e.g. `address.city` or `tags.*`.
+    Field {
+        table: String,
+        name: String,
+        #[serde(default)]
+        kind: Option<GraphDbFieldType>,
+        #[serde(default)]
+        assertions: Vec<GraphDbAssertion>,
+        #[serde(default)]
+        default: Option<serde_json::Value>,
+    },
+    Index {
+        table: String,
+        name: String,
+        fields: Vec<String>,
+        #[serde(default)]
+        unique: bool,
+    },
+    /// Run `then` whenever a record in `table` is changed in one of the ways in `when`
+    /// (or in any way, if empty).
+    Event {
+        table: String,
+        name: String,
+        #[serde(default)]
+        when: Vec<GraphDbLiveAction>,
+        then: Vec<GraphDbEventAction>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum GraphDbFieldType {
+    Any,
+    Bool,
+    Int,
+    Float,
+    Decimal,
+    Number,
+    String,
+    Datetime,
+    Duration,
+    Uuid,
+    Bytes,
+    Object,
+    /// a link to a record, optionally restricted to one table
+    Record(Option<String>),
+    Array(Box<GraphDbFieldType>),
+    /// the field may be absent
+    Option(Box<GraphDbFieldType>),
+}
+
+/// Conditions on a field's `$value`, all of which must hold for a write to succeed.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum GraphDbAssertion {
+    NotNone,
+    Min(f64),
+    Max(f64),
+    MinLength(u64),
+    MaxLength(u64),
+    OneOf(Vec<serde_json::Value>),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum GraphDbEventAction {
+    /// create a record in `table` holding the `event` kind, the changed
+    /// `record`'s state after the change (before it, for deletes), and the `time`
+    Log { table: String },
+    /// delete the records in `table` whose `field` links to the deleted record
+    CascadeDelete { table: String, field: String },
 }
 
 impl DefineResourceType {
-    pub fn query(&self) -> String {
+    pub fn query(&self) -> Result<String, GraphDbError> {
         match self {
             DefineResourceType::Namespace { name } => {
-                // strip all whitespace and special characters
-                let name = name
-                    .chars()
-                    .filter(|c| c.is_alphanumeric())
-                    .collect::<String>();
-
-                format!("DEFINE NAMESPACE {};", name)
+                Ok(format!("DEFINE NAMESPACE {};", define_ident(name)?))
             }
             DefineResourceType::Database { name } => {
-                let name = name
-                    .chars()
-                    .filter(|c| c.is_alphanumeric())
-                    .collect::<String>();
-                format!("DEFINE DATABASE {};", name)
+                Ok(format!("DEFINE DATABASE {};", define_ident(name)?))
             }
-            DefineResourceType::Table { name } => {
-                let name = name
-                    .chars()
-                    .filter(|c| c.is_alphanumeric())
-                    .collect::<String>();
-                format!("DEFINE TABLE {} SCHEMALESS;", name)
+            DefineResourceType::Table { name, schemafull } => Ok(format!(
+                "DEFINE TABLE {} {};",
+                define_ident(name)?,
+                if *schemafull { "SCHEMAFULL" } else { "SCHEMALESS" },
+            )),
+            DefineResourceType::Field {
+                table,
+                name,
+                kind,
+                assertions,
+                default,
+            } => {
+                let mut query = format!(
+                    "DEFINE FIELD {} ON TABLE {}",
+                    define_field_path(name)?,
+                    define_ident(table)?
+                );
+                if let Some(kind) = kind {
+                    query.push_str(&format!(" TYPE {}", kind.query()?));
+                }
+                if !assertions.is_empty() {
+                    let assertions = assertions
+                        .iter()
+                        .map(|assertion| assertion.query())
+                        .collect::<Result<Vec<_>, _>>()?;
+                    query.push_str(&format!(" ASSERT {}", assertions.join(" AND ")));
+                }
+                if let Some(default) = default {
+                    query.push_str(&format!(" DEFAULT {}", default));
+                }
+                query.push(';');
+                Ok(query)
             }
+            DefineResourceType::Index {
+                table,
+                name,
+                fields,
+                unique,
+            } => {
+                if fields.is_empty() {
+                    return Err(GraphDbError::InputError {
+                        error: "index needs at least one field".into(),
+                    });
+                }
+                let fields = fields
+                    .iter()
+                    .map(|field| define_field_path(field))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(format!(
+                    "DEFINE INDEX {} ON TABLE {} FIELDS {}{};",
+                    define_name(name)?,
+                    define_ident(table)?,
+                    fields.join(", "),
+                    if *unique { " UNIQUE" } else { "" },
+                ))
+            }
+            DefineResourceType::Event {
+                table,
+                name,
+                when,
+                then,
+            } => {
+                if then.is_empty() {
+                    return Err(GraphDbError::InputError {
+                        error: "event needs at least one action".into(),
+                    });
+                }
+                let when = if when.is_empty() {
+                    "true".to_string()
+                } else {
+                    when.iter()
+                        .map(|action| match action {
+                            GraphDbLiveAction::Create => "$event = \"CREATE\"",
+                            GraphDbLiveAction::Update => "$event = \"UPDATE\"",
+                            GraphDbLiveAction::Delete => "$event = \"DELETE\"",
+                        })
+                        .collect::<Vec<_>>()
+                        .join(" OR ")
+                };
+                let then = then
+                    .iter()
+                    .map(|action| action.query())
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(format!(
+                    "DEFINE EVENT {} ON TABLE {} WHEN {} THEN {{ {} }};",
+                    define_name(name)?,
+                    define_ident(table)?,
+                    when,
+                    then.join("; "),
+                ))
+            }
+        }
+    }
+}
+
+impl GraphDbFieldType {
+    fn query(&self) -> Result<String, GraphDbError> {
+        Ok(match self {
+            GraphDbFieldType::Any => "any".into(),
+            GraphDbFieldType::Bool => "bool".into(),
+            GraphDbFieldType::Int => "int".into(),
+            GraphDbFieldType::Float => "float".into(),
+            GraphDbFieldType::Decimal => "decimal".into(),
+            GraphDbFieldType::Number => "number".into(),
+            GraphDbFieldType::String => "string".into(),
+            GraphDbFieldType::Datetime => "datetime".into(),
+            GraphDbFieldType::Duration => "duration".into(),
+            GraphDbFieldType::Uuid => "uuid".into(),
+            GraphDbFieldType::Bytes => "bytes".into(),
+            GraphDbFieldType::Object => "object".into(),
+            GraphDbFieldType::Record(None) => "record".into(),
+            GraphDbFieldType::Record(Some(table)) => format!("record<{}>", define_ident(table)?),
+            GraphDbFieldType::Array(kind) => format!("array<{}>", kind.query()?),
+            GraphDbFieldType::Option(kind) => format!("option<{}>", kind.query()?),
+        })
+    }
+}
+
+impl GraphDbAssertion {
+    fn query(&self) -> Result<String, GraphDbError> {
+        Ok(match self {
+            GraphDbAssertion::NotNone => "$value != NONE".into(),
+            GraphDbAssertion::Min(min) => format!("$value >= {}", define_number(*min)?),
+            GraphDbAssertion::Max(max) => format!("$value <= {}", define_number(*max)?),
+            GraphDbAssertion::MinLength(len) => format!("string::len($value) >= {}", len),
+            GraphDbAssertion::MaxLength(len) => format!("string::len($value) <= {}", len),
+            GraphDbAssertion::OneOf(values) => {
+                format!("$value INSIDE {}", serde_json::Value::Array(values.clone()))
+            }
+        })
+    }
+}
+
+impl GraphDbEventAction {
+    fn query(&self) -> Result<String, GraphDbError> {
+        Ok(match self {
+            GraphDbEventAction::Log { table } => format!(
+                "CREATE {} SET event = $event, record = IF $event = \"DELETE\" THEN $before ELSE $after END, time = time::now()",
+                define_ident(table)?,
+            ),
+            GraphDbEventAction::CascadeDelete { table, field } => format!(
+                "IF $event = \"DELETE\" THEN (DELETE {} WHERE {} = $before.id) END",
+                define_ident(table)?,
+                define_field_path(field)?,
+            ),
+        })
+    }
+}
+
+/// a namespace, database or table name, stripped of everything but letters and
+/// digits. tables defined before the other resources existed were named this
+/// way, so references to a table must be normalized the same way to match.
+fn define_ident(name: &str) -> Result<String, GraphDbError> {
+    let ident = name
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .collect::<String>();
+    if ident.is_empty() {
+        return Err(GraphDbError::InputError {
+            error: format!("invalid identifier: {:?}", name),
+        });
+    }
+    Ok(ident)
+}
+
+/// a field, index or event name, which must already be a plain identifier
+fn define_name(name: &str) -> Result<&str, GraphDbError> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(GraphDbError::InputError {
+            error: format!("invalid identifier: {:?}", name),
+        });
+    }
+    Ok(name)
+}
+
+fn define_field_path(path: &str) -> Result<&str, GraphDbError> {
+    for segment in path.split('.') {
+        if segment != "*" {
+            define_name(segment)?;
         }
     }
+    Ok(path)
+}
+
+fn define_number(n: f64) -> Result<String, GraphDbError> {
+    if !n.is_finite() {
+        return Err(GraphDbError::InputError {
+            error: format!("invalid number: {}", n),
+        });
+    }
+    Ok(n.to_string())
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -1390,14 +2222,36 @@ pub enum GraphDbAction {
     Define { resource: DefineResourceType },
     Write { statement: String },
     Read { statement: String },
+    /// Run a `LIVE SELECT` statement and receive a `GraphDbLiveNotification`
+    /// Request for every change it matches, until `Kill`ed.
+    Live { statement: String },
+    Kill { live_id: u64 },
     Backup,
     RemoveDb,
 }
 
+/// Sent as a Request by graphdb to the process that started a live query. The
+/// blob holds the changed record as JSON.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GraphDbLiveNotification {
+    pub package_id: PackageId,
+    pub db: String,
+    pub live_id: u64,
+    pub action: GraphDbLiveAction,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum GraphDbLiveAction {
+    Create,
+    Update,
+    Delete,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GraphDbResponse {
     Ok,
     Data,
+    Live { live_id: u64 },
     Err { error: GraphDbError },
 }
 
@@ -1409,6 +2263,8 @@ pub enum GraphDbError {
     KeyNotFound,
     #[error("graphdb: no Tx found")]
     NoTx,
+    #[error("graphdb: no live query found")]
+    NoLiveQuery,
     #[error("graphdb: No capability: {error}")]
     NoCap { error: String },
     #[error("sqlite: NotAWriteKeyword")]
@@ -1421,4 +2277,191 @@ pub enum GraphDbError {
     InputError { error: String },
     #[error("graphdb: IO error: {error}")]
     IOError { error: String },
+    #[error("graphdb: storage quota of {quota} bytes exceeded, {usage} bytes in use")]
+    QuotaExceeded { quota: u64, usage: u64 },
+}
+
+/// IPC format for requests sent to the timer module. For backwards compatibility,
+/// the timer also accepts a little-endian u64 of milliseconds, which acts like
+/// `SetOnce` but pops with an empty Response body.
+///
+/// Timers are identified by the id of the Request that set them. Every pop of a
+/// timer set with a `TimerAction` carries that id in a `TimerResponse::Pop`,
+/// so recurring timers can be cancelled after their first pop.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TimerAction {
+    /// pop once, in `millis` milliseconds
+    SetOnce { millis: u64 },
+    /// pop once, at the given unix time in milliseconds
+    SetAt { unix_ms: u64 },
+    /// stop a timer set by the same process
+    Cancel { id: u64 },
+    /// pop every `interval` milliseconds, until cancelled
+    Recurring { interval: u64 },
+    /// pop on a 5-field cron schedule (minute hour day-of-month month day-of-week),
+    /// evaluated in UTC, until cancelled
+    Cron { expr: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TimerResponse {
+    Pop { id: u64 },
+    Cancelled { id: u64 },
+    Err(TimerError),
+}
+
+#[derive(Debug, Serialize, Deserialize, Error)]
+pub enum TimerError {
+    #[error("timer: no such timer {id}")]
+    NoSuchTimer { id: u64 },
+    #[error("timer: invalid interval, must be greater than 0")]
+    InvalidInterval,
+    #[error("timer: invalid cron expression: {expr}")]
+    InvalidCron { expr: String },
+    #[error("timer: input bytes/json error: {error}")]
+    InputError { error: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(resource: DefineResourceType) -> Result<String, GraphDbError> {
+        resource.query()
+    }
+
+    #[test]
+    fn define_strips_namespace_database_and_table_names() {
+        assert_eq!(
+            query(DefineResourceType::Namespace {
+                name: "my-ns!".into()
+            })
+            .unwrap(),
+            "DEFINE NAMESPACE myns;"
+        );
+        assert_eq!(
+            query(DefineResourceType::Database {
+                name: "my db".into()
+            })
+            .unwrap(),
+            "DEFINE DATABASE mydb;"
+        );
+        assert_eq!(
+            query(DefineResourceType::Table {
+                name: "user_data; REMOVE TABLE x".into(),
+                schemafull: false,
+            })
+            .unwrap(),
+            "DEFINE TABLE userdataREMOVETABLEx SCHEMALESS;"
+        );
+        assert_eq!(
+            query(DefineResourceType::Table {
+                name: "people".into(),
+                schemafull: true,
+            })
+            .unwrap(),
+            "DEFINE TABLE people SCHEMAFULL;"
+        );
+        assert!(query(DefineResourceType::Table {
+            name: "-".into(),
+            schemafull: false,
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn define_field() {
+        assert_eq!(
+            query(DefineResourceType::Field {
+                table: "my-table".into(),
+                name: "address.city".into(),
+                kind: Some(GraphDbFieldType::Option(Box::new(GraphDbFieldType::Record(
+                    Some("cities".into())
+                )))),
+                assertions: vec![],
+                default: None,
+            })
+            .unwrap(),
+            "DEFINE FIELD address.city ON TABLE mytable TYPE option<record<cities>>;"
+        );
+        assert_eq!(
+            query(DefineResourceType::Field {
+                table: "people".into(),
+                name: "tags.*".into(),
+                kind: Some(GraphDbFieldType::Array(Box::new(GraphDbFieldType::String))),
+                assertions: vec![
+                    GraphDbAssertion::NotNone,
+                    GraphDbAssertion::MaxLength(8),
+                    GraphDbAssertion::OneOf(vec!["a".into(), "b".into()]),
+                ],
+                default: Some("a".into()),
+            })
+            .unwrap(),
+            "DEFINE FIELD tags.* ON TABLE people TYPE array<string> ASSERT $value != NONE AND string::len($value) <= 8 AND $value INSIDE [\"a\",\"b\"] DEFAULT \"a\";"
+        );
+    }
+
+    #[test]
+    fn define_rejects_unsafe_names_and_numbers() {
+        let field = |name: &str, assertions| DefineResourceType::Field {
+            table: "people".into(),
+            name: name.into(),
+            kind: None,
+            assertions,
+            default: None,
+        };
+        assert!(query(field("age; REMOVE TABLE people", vec![])).is_err());
+        assert!(query(field("1st", vec![])).is_err());
+        assert!(query(field("a..b", vec![])).is_err());
+        assert!(query(field("age", vec![GraphDbAssertion::Min(f64::NAN)])).is_err());
+        assert_eq!(
+            query(field("age", vec![GraphDbAssertion::Min(0.5)])).unwrap(),
+            "DEFINE FIELD age ON TABLE people ASSERT $value >= 0.5;"
+        );
+    }
+
+    #[test]
+    fn define_index() {
+        assert_eq!(
+            query(DefineResourceType::Index {
+                table: "people".into(),
+                name: "people_email".into(),
+                fields: vec!["email".into(), "address.city".into()],
+                unique: true,
+            })
+            .unwrap(),
+            "DEFINE INDEX people_email ON TABLE people FIELDS email, address.city UNIQUE;"
+        );
+        assert!(query(DefineResourceType::Index {
+            table: "people".into(),
+            name: "empty".into(),
+            fields: vec![],
+            unique: false,
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn define_event() {
+        assert_eq!(
+            query(DefineResourceType::Event {
+                table: "people".into(),
+                name: "cleanup".into(),
+                when: vec![GraphDbLiveAction::Delete],
+                then: vec![GraphDbEventAction::CascadeDelete {
+                    table: "posts".into(),
+                    field: "author".into(),
+                }],
+            })
+            .unwrap(),
+            "DEFINE EVENT cleanup ON TABLE people WHEN $event = \"DELETE\" THEN { IF $event = \"DELETE\" THEN (DELETE posts WHERE author = $before.id) END };"
+        );
+        assert!(query(DefineResourceType::Event {
+            table: "people".into(),
+            name: "nothing".into(),
+            when: vec![],
+            then: vec![],
+        })
+        .is_err());
+    }
 }
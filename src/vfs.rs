@@ -8,8 +8,38 @@ use tokio::fs::OpenOptions;
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
 use tokio::sync::Mutex;
 
+use crate::encryption::{AtRest, DataId, CHUNK_LEN, CHUNK_OVERHEAD, HEADER_LEN};
+use crate::quota::{QuotaExceeded, StorageQuotas};
 use crate::types::*;
 
+/// how far past a file's end a write may start. the gap is zero-filled, which
+/// for a file encrypted at rest means sealing every byte of it.
+const MAX_WRITE_GAP: u64 = 16 * 1024 * 1024;
+
+/// entries in a page of `VfsAction::Walk` that doesn't ask for a `limit`
+const WALK_PAGE_SIZE: u64 = 1000;
+
+/// processes watching for changes, by the path they watch
+type Watchers = Arc<DashMap<String, Vec<VfsWatcher>>>;
+
+struct VfsWatcher {
+    address: Address,
+    recursive: bool,
+}
+
+/// files opened with `VfsAction::OpenHandle`, by handle id
+type Handles = Arc<DashMap<u64, FileHandle>>;
+
+/// a file descriptor of its own, so that processes reading and writing the same
+/// file in chunks don't share a cursor
+struct FileHandle {
+    owner: ProcessId,
+    path: PathBuf,
+    file: Arc<Mutex<fs::File>>,
+    /// opened for writing as well as reading, which takes write access to the drive
+    writable: bool,
+}
+
 pub async fn vfs(
     our_node: String,
     send_to_loop: MessageSender,
@@ -17,6 +47,9 @@ pub async fn vfs(
     mut recv_from_loop: MessageReceiver,
     send_to_caps_oracle: CapMessageSender,
     home_directory_path: String,
+    snapshot_lock: SnapshotLock,
+    at_rest: Arc<AtRest>,
+    quotas: Arc<StorageQuotas>,
 ) -> anyhow::Result<()> {
     let vfs_path = format!("{}/vfs", &home_directory_path);
 
@@ -26,6 +59,9 @@ pub async fn vfs(
 
     let open_files: Arc<DashMap<PathBuf, Arc<Mutex<fs::File>>>> = Arc::new(DashMap::new());
 
+    let watchers: Watchers = Arc::new(DashMap::new());
+    let handles: Handles = Arc::new(DashMap::new());
+
     let mut process_queues: HashMap<ProcessId, Arc<Mutex<VecDeque<KernelMessage>>>> =
         HashMap::new();
 
@@ -41,6 +77,21 @@ pub async fn vfs(
                     continue;
                 }
 
+                if km.source.process == *KERNEL_PROCESS_ID {
+                    if let Message::Request(Request { ref body, .. }) = km.message {
+                        if let Ok(KernelNotification::ProcessExited(process)) =
+                            serde_json::from_slice(body)
+                        {
+                            handles.retain(|_, handle| handle.owner != process);
+                            watchers.retain(|_, path_watchers| {
+                                path_watchers.retain(|w| w.address.process != process);
+                                !path_watchers.is_empty()
+                            });
+                            continue;
+                        }
+                    }
+                }
+
                 let queue = process_queues
                     .entry(km.source.process.clone())
                     .or_insert_with(|| Arc::new(Mutex::new(VecDeque::new())))
@@ -57,19 +108,31 @@ pub async fn vfs(
                 let send_to_terminal = send_to_terminal.clone();
                 let send_to_loop = send_to_loop.clone();
                 let open_files = open_files.clone();
+                let watchers = watchers.clone();
+                let handles = handles.clone();
                 let vfs_path = vfs_path.clone();
+                let at_rest = at_rest.clone();
+                let quotas = quotas.clone();
+
+                let snapshot_lock = snapshot_lock.clone();
 
                 tokio::spawn(async move {
                     let mut queue_lock = queue.lock().await;
                     if let Some(km) = queue_lock.pop_front() {
+                        // keep snapshots from copying the store mid-request
+                        let _snapshot_guard = snapshot_lock.read().await;
                         if let Err(e) = handle_request(
                             our_node.clone(),
                             km.clone(),
                             open_files.clone(),
+                            watchers.clone(),
+                            handles.clone(),
                             send_to_loop.clone(),
                             send_to_terminal.clone(),
                             send_to_caps_oracle.clone(),
                             vfs_path.clone(),
+                            at_rest.clone(),
+                            quotas.clone(),
                         )
                         .await
                         {
@@ -88,10 +151,14 @@ async fn handle_request(
     our_node: String,
     km: KernelMessage,
     open_files: Arc<DashMap<PathBuf, Arc<Mutex<fs::File>>>>,
+    watchers: Watchers,
+    handles: Handles,
     send_to_loop: MessageSender,
     send_to_terminal: PrintSender,
     send_to_caps_oracle: CapMessageSender,
     vfs_path: String,
+    at_rest: Arc<AtRest>,
+    quotas: Arc<StorageQuotas>,
 ) -> Result<(), VfsError> {
     let KernelMessage {
         id,
@@ -203,11 +270,19 @@ async fn handle_request(
             &request,
             path.clone(),
             drive.clone(),
-            package_id,
+            package_id.clone(),
             vfs_path.clone(),
         )
         .await?;
     }
+    // changes to report to watchers once the request has been handled
+    let watch_path = normalize_path(&request.path);
+    let mut changes: Vec<VfsChange> = Vec::new();
+    let change = |kind: VfsChangeKind| VfsChange {
+        path: watch_path.clone(),
+        kind,
+    };
+
     // real safe path that the vfs will use
     let path = PathBuf::from(format!("{}{}/{}", vfs_path, drive, rest));
     let (body, bytes) = match request.action {
@@ -219,21 +294,35 @@ async fn handle_request(
             // check error mapping
             //     fs::create_dir_all(path).await.map_err(|e| VfsError::IOError { source: e, path: path.clone() })?;
             fs::create_dir(path).await?;
+            changes.push(change(VfsChangeKind::Create));
             (serde_json::to_vec(&VfsResponse::Ok).unwrap(), None)
         }
         VfsAction::CreateDirAll => {
+            let existed = fs::metadata(&path).await.is_ok();
             fs::create_dir_all(path).await?;
+            if !existed {
+                changes.push(change(VfsChangeKind::Create));
+            }
             (serde_json::to_vec(&VfsResponse::Ok).unwrap(), None)
         }
         VfsAction::CreateFile => {
             // create truncates any file that might've existed before
+            let existed = fs::metadata(&path).await.is_ok();
             open_files.remove(&path);
-            let _file = open_file(open_files.clone(), path, true, true).await?;
+            let _ = open_file(open_files.clone(), path, true, true).await?;
+            changes.push(change(if existed {
+                VfsChangeKind::Modify
+            } else {
+                VfsChangeKind::Create
+            }));
 
             (serde_json::to_vec(&VfsResponse::Ok).unwrap(), None)
         }
         VfsAction::OpenFile { create } => {
             // open file opens an existing file, or creates a new one if create is true
+            if create && fs::metadata(&path).await.is_err() {
+                changes.push(change(VfsChangeKind::Create));
+            }
             let file = open_file(open_files.clone(), path, create, false).await?;
             let mut file = file.lock().await;
             // extra in the case file was just created, todo refactor out.
@@ -255,7 +344,20 @@ async fn handle_request(
             };
             let file = open_file(open_files.clone(), path, false, false).await?;
             let mut file = file.lock().await;
-            file.write_all(&blob.bytes).await?;
+            let id = encryption_id_for_write(&mut file, &at_rest).await?;
+            let pos = file.stream_position().await?;
+            // files are charged against the quota of the package owning the drive
+            let growth = (pos + blob.bytes.len() as u64)
+                .saturating_sub(file_len(&mut file, id.is_some()).await?);
+            quotas.reserve(&package_id, growth).await?;
+            if let Some(id) = id {
+                write_encrypted(&mut file, &at_rest, &id, pos, &blob.bytes).await?;
+                file.seek(SeekFrom::Start(pos + blob.bytes.len() as u64))
+                    .await?;
+            } else {
+                file.write_all(&blob.bytes).await?;
+            }
+            changes.push(change(VfsChangeKind::Modify));
             (serde_json::to_vec(&VfsResponse::Ok).unwrap(), None)
         }
         VfsAction::Write => {
@@ -264,7 +366,18 @@ async fn handle_request(
                     error: "blob needs to exist for Write".into(),
                 });
             };
-            fs::write(path, &blob.bytes).await?;
+            let existing = fs::metadata(&path).await.ok().map(|m| m.len());
+            quotas
+                .reserve(
+                    &package_id,
+                    (blob.bytes.len() as u64).saturating_sub(existing.unwrap_or(0)),
+                )
+                .await?;
+            replace_file(&path, &at_rest.seal(&blob.bytes), &open_files, &handles).await?;
+            changes.push(change(match existing {
+                Some(_) => VfsChangeKind::Modify,
+                None => VfsChangeKind::Create,
+            }));
             (serde_json::to_vec(&VfsResponse::Ok).unwrap(), None)
         }
         VfsAction::Append => {
@@ -273,10 +386,19 @@ async fn handle_request(
                     error: "blob needs to exist for Append".into(),
                 });
             };
+            quotas.reserve(&package_id, blob.bytes.len() as u64).await?;
             let file = open_file(open_files.clone(), path, false, false).await?;
             let mut file = file.lock().await;
-            file.seek(SeekFrom::End(0)).await?;
-            file.write_all(&blob.bytes).await?;
+            if let Some(id) = encryption_id_for_write(&mut file, &at_rest).await? {
+                let len = file_len(&mut file, true).await?;
+                write_encrypted(&mut file, &at_rest, &id, len, &blob.bytes).await?;
+                file.seek(SeekFrom::Start(len + blob.bytes.len() as u64))
+                    .await?;
+            } else {
+                file.seek(SeekFrom::End(0)).await?;
+                file.write_all(&blob.bytes).await?;
+            }
+            changes.push(change(VfsChangeKind::Modify));
 
             (serde_json::to_vec(&VfsResponse::Ok).unwrap(), None)
         }
@@ -287,7 +409,7 @@ async fn handle_request(
             (serde_json::to_vec(&VfsResponse::Ok).unwrap(), None)
         }
         VfsAction::Read => {
-            let contents = fs::read(&path).await?;
+            let contents = at_rest.open(fs::read(&path).await?)?;
             (
                 serde_json::to_vec(&VfsResponse::Read).unwrap(),
                 Some(contents),
@@ -298,7 +420,14 @@ async fn handle_request(
             let mut file = file.lock().await;
             let mut contents = Vec::new();
 
-            file.read_to_end(&mut contents).await?;
+            if let Some(id) = encryption_id(&mut file, &at_rest).await? {
+                let pos = file.stream_position().await?;
+                contents = read_encrypted(&mut file, &at_rest, &id, pos, None).await?;
+                file.seek(SeekFrom::Start(pos + contents.len() as u64))
+                    .await?;
+            } else {
+                file.read_to_end(&mut contents).await?;
+            }
 
             (
                 serde_json::to_vec(&VfsResponse::Read).unwrap(),
@@ -308,8 +437,19 @@ async fn handle_request(
         VfsAction::ReadExact(length) => {
             let file = open_file(open_files.clone(), path, false, false).await?;
             let mut file = file.lock().await;
-            let mut contents = vec![0; length as usize];
-            file.read_exact(&mut contents).await?;
+            let contents = if let Some(id) = encryption_id(&mut file, &at_rest).await? {
+                let pos = file.stream_position().await?;
+                let contents = read_encrypted(&mut file, &at_rest, &id, pos, Some(length)).await?;
+                if (contents.len() as u64) < length {
+                    return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                }
+                file.seek(SeekFrom::Start(pos + length)).await?;
+                contents
+            } else {
+                let mut contents = vec![0; length as usize];
+                file.read_exact(&mut contents).await?;
+                contents
+            };
             (
                 serde_json::to_vec(&VfsResponse::Read).unwrap(),
                 Some(contents),
@@ -339,7 +479,16 @@ async fn handle_request(
             let file = open_file(open_files.clone(), path, false, false).await?;
             let mut file = file.lock().await;
             let mut contents = String::new();
-            file.read_to_string(&mut contents).await?;
+            if let Some(id) = encryption_id(&mut file, &at_rest).await? {
+                let pos = file.stream_position().await?;
+                let plaintext = read_encrypted(&mut file, &at_rest, &id, pos, None).await?;
+                file.seek(SeekFrom::Start(pos + plaintext.len() as u64))
+                    .await?;
+                contents = String::from_utf8(plaintext)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            } else {
+                file.read_to_string(&mut contents).await?;
+            }
             (
                 serde_json::to_vec(&VfsResponse::ReadToString(contents)).unwrap(),
                 None,
@@ -354,7 +503,27 @@ async fn handle_request(
                 crate::types::SeekFrom::End(offset) => std::io::SeekFrom::End(offset),
                 crate::types::SeekFrom::Current(offset) => std::io::SeekFrom::Current(offset),
             };
-            let response = file.seek(seek_from).await?;
+            let response = if encryption_id(&mut file, &at_rest).await?.is_some() {
+                // the cursor of an encrypted file is its position in the plaintext
+                let len = file_len(&mut file, true).await? as i128;
+                let pos = file.stream_position().await? as i128;
+                let target = match seek_from {
+                    std::io::SeekFrom::Start(offset) => offset as i128,
+                    std::io::SeekFrom::End(offset) => len + offset as i128,
+                    std::io::SeekFrom::Current(offset) => pos + offset as i128,
+                };
+                if target < 0 {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "invalid seek to a negative position",
+                    )
+                    .into());
+                }
+                file.seek(SeekFrom::Start(target as u64)).await?;
+                target as u64
+            } else {
+                file.seek(seek_from).await?
+            };
             (
                 serde_json::to_vec(&VfsResponse::SeekFrom(response)).unwrap(),
                 None,
@@ -362,32 +531,37 @@ async fn handle_request(
         }
         VfsAction::RemoveFile => {
             fs::remove_file(path).await?;
+            changes.push(change(VfsChangeKind::Remove));
             (serde_json::to_vec(&VfsResponse::Ok).unwrap(), None)
         }
         VfsAction::RemoveDir => {
             fs::remove_dir(path).await?;
+            changes.push(change(VfsChangeKind::Remove));
             (serde_json::to_vec(&VfsResponse::Ok).unwrap(), None)
         }
         VfsAction::RemoveDirAll => {
             fs::remove_dir_all(path).await?;
+            changes.push(change(VfsChangeKind::Remove));
             (serde_json::to_vec(&VfsResponse::Ok).unwrap(), None)
         }
         VfsAction::Rename { new_path } => {
-            fs::rename(path, new_path).await?;
+            fs::rename(path, &new_path).await?;
+            changes.push(change(VfsChangeKind::Rename {
+                new_path: normalize_path(&new_path),
+            }));
             (serde_json::to_vec(&VfsResponse::Ok).unwrap(), None)
         }
         VfsAction::CopyFile { new_path } => {
-            fs::copy(path, new_path).await?;
+            fs::copy(path, &new_path).await?;
+            changes.push(VfsChange {
+                path: normalize_path(&new_path),
+                kind: VfsChangeKind::Create,
+            });
             (serde_json::to_vec(&VfsResponse::Ok).unwrap(), None)
         }
         VfsAction::Metadata => {
             let metadata = fs::metadata(&path).await?;
-
-            let file_type = get_file_type(&metadata);
-            let meta = FileMetadata {
-                len: metadata.len(),
-                file_type,
-            };
+            let meta = file_metadata(&path, &metadata, &at_rest).await?;
 
             (
                 serde_json::to_vec(&VfsResponse::Metadata(meta)).unwrap(),
@@ -396,28 +570,48 @@ async fn handle_request(
         }
         VfsAction::Len => {
             let file = open_file(open_files.clone(), path, false, false).await?;
-            let file = file.lock().await;
-            let len = file.metadata().await?.len();
+            let mut file = file.lock().await;
+            let encrypted = encryption_id(&mut file, &at_rest).await?.is_some();
+            let len = file_len(&mut file, encrypted).await?;
             (serde_json::to_vec(&VfsResponse::Len(len)).unwrap(), None)
         }
         VfsAction::SetLen(len) => {
             let file = open_file(open_files.clone(), path, false, false).await?;
-            let file = file.lock().await;
-            file.set_len(len).await?;
+            let mut file = file.lock().await;
+            let id = encryption_id_for_write(&mut file, &at_rest).await?;
+            quotas
+                .reserve(
+                    &package_id,
+                    len.saturating_sub(file_len(&mut file, id.is_some()).await?),
+                )
+                .await?;
+            if let Some(id) = id {
+                set_len_encrypted(&mut file, &at_rest, &id, len).await?;
+            } else {
+                file.set_len(len).await?;
+            }
+            changes.push(change(VfsChangeKind::Modify));
             (serde_json::to_vec(&VfsResponse::Ok).unwrap(), None)
         }
         VfsAction::Hash => {
             let file = open_file(open_files.clone(), path, false, false).await?;
             let mut file = file.lock().await;
-            file.seek(SeekFrom::Start(0)).await?;
             let mut hasher = blake3::Hasher::new();
-            let mut buffer = [0; 1024];
-            loop {
-                let bytes_read = file.read(&mut buffer).await?;
-                if bytes_read == 0 {
-                    break;
+            if let Some(id) = encryption_id(&mut file, &at_rest).await? {
+                let len = file_len(&mut file, true).await?;
+                for index in 0..AtRest::chunk_count(len) {
+                    hasher.update(&read_chunk(&mut file, &at_rest, &id, index, len).await?);
+                }
+            } else {
+                file.seek(SeekFrom::Start(0)).await?;
+                let mut buffer = [0; 1024];
+                loop {
+                    let bytes_read = file.read(&mut buffer).await?;
+                    if bytes_read == 0 {
+                        break;
+                    }
+                    hasher.update(&buffer[..bytes_read]);
                 }
-                hasher.update(&buffer[..bytes_read]);
             }
             let hash: [u8; 32] = hasher.finalize().into();
             (serde_json::to_vec(&VfsResponse::Hash(hash)).unwrap(), None)
@@ -449,6 +643,11 @@ async fn handle_request(
                 }
             };
 
+            let unzipped_len: u64 = (0..zip.len())
+                .filter_map(|i| zip.by_index(i).ok().map(|file| file.size()))
+                .sum();
+            quotas.reserve(&package_id, unzipped_len).await?;
+
             // loop through items in archive; recursively add to root
             for i in 0..zip.len() {
                 // must destruct the zip file created in zip.by_index()
@@ -463,10 +662,20 @@ async fn handle_request(
                         file.read_to_end(&mut file_contents)?;
                     };
                     let local_path = path.join(file.name());
+                    changes.push(VfsChange {
+                        path: normalize_path(&format!("{}/{}", watch_path, file.name())),
+                        kind: VfsChangeKind::Create,
+                    });
                     (is_file, is_dir, local_path, file_contents)
                 };
                 if is_file {
-                    fs::write(&local_path, &file_contents).await?;
+                    replace_file(
+                        &local_path,
+                        &at_rest.seal(&file_contents),
+                        &open_files,
+                        &handles,
+                    )
+                    .await?;
                 } else if is_dir {
                     fs::create_dir_all(local_path).await?;
                 } else {
@@ -479,6 +688,162 @@ async fn handle_request(
             }
             (serde_json::to_vec(&VfsResponse::Ok).unwrap(), None)
         }
+        VfsAction::Watch { recursive } => {
+            let address = Address {
+                node: our_node.clone(),
+                process: source.process.clone(),
+            };
+            let mut path_watchers = watchers.entry(watch_path.clone()).or_default();
+            path_watchers.retain(|w| w.address != address);
+            path_watchers.push(VfsWatcher { address, recursive });
+            (serde_json::to_vec(&VfsResponse::Ok).unwrap(), None)
+        }
+        VfsAction::Unwatch => {
+            if let Some(mut path_watchers) = watchers.get_mut(&watch_path) {
+                path_watchers.retain(|w| w.address.process != source.process);
+            }
+            watchers.remove_if(&watch_path, |_, path_watchers| path_watchers.is_empty());
+            (serde_json::to_vec(&VfsResponse::Ok).unwrap(), None)
+        }
+        VfsAction::OpenHandle { create } => {
+            // a handle that can't create its file is opened with read access,
+            // so is only writable if its owner could write to the drive anyway
+            let writable = create
+                || source.process == *KERNEL_PROCESS_ID
+                || can_write(
+                    &our_node,
+                    &source,
+                    &send_to_caps_oracle,
+                    &drive,
+                    &package_id,
+                )
+                .await?;
+            let existed = fs::metadata(&path).await.is_ok();
+            let file = OpenOptions::new()
+                .read(true)
+                .write(writable)
+                .create(create)
+                .open(&path)
+                .await
+                .map_err(|e| VfsError::IOError {
+                    error: e.to_string(),
+                    path: path.display().to_string(),
+                })?;
+            if !existed {
+                changes.push(change(VfsChangeKind::Create));
+            }
+            let handle = rand::random::<u64>();
+            handles.insert(
+                handle,
+                FileHandle {
+                    owner: source.process.clone(),
+                    path,
+                    file: Arc::new(Mutex::new(file)),
+                    writable,
+                },
+            );
+            (
+                serde_json::to_vec(&VfsResponse::Handle(handle)).unwrap(),
+                None,
+            )
+        }
+        VfsAction::CloseHandle { handle } => {
+            if handles
+                .remove_if(&handle, |_, h| h.owner == source.process && h.path == path)
+                .is_none()
+            {
+                return Err(VfsError::NoHandle { handle });
+            }
+            (serde_json::to_vec(&VfsResponse::Ok).unwrap(), None)
+        }
+        VfsAction::ReadAt {
+            offset,
+            len,
+            handle,
+        } => {
+            let file = match handle {
+                Some(handle) => get_handle(&handles, handle, &source.process, &path, false)?,
+                None => Arc::new(Mutex::new(fs::File::open(&path).await?)),
+            };
+            let mut file = file.lock().await;
+            let mut contents = Vec::new();
+            if let Some(id) = encryption_id(&mut file, &at_rest).await? {
+                contents = read_encrypted(&mut file, &at_rest, &id, offset, Some(len)).await?;
+            } else {
+                file.seek(SeekFrom::Start(offset)).await?;
+                (&mut *file).take(len).read_to_end(&mut contents).await?;
+            }
+            (
+                serde_json::to_vec(&VfsResponse::Read).unwrap(),
+                Some(contents),
+            )
+        }
+        VfsAction::Walk {
+            max_depth,
+            glob,
+            include_metadata,
+            limit,
+            cursor,
+        } => {
+            let (entries, cursor) = walk(
+                path,
+                &vfs_path,
+                max_depth,
+                glob.as_deref(),
+                include_metadata,
+                limit,
+                cursor.as_deref(),
+                &at_rest,
+            )
+            .await?;
+            (
+                serde_json::to_vec(&VfsResponse::Walk { entries, cursor }).unwrap(),
+                None,
+            )
+        }
+        VfsAction::WriteAt { offset, handle } => {
+            let Some(blob) = blob else {
+                return Err(VfsError::BadRequest {
+                    error: "blob needs to exist for WriteAt".into(),
+                });
+            };
+            let Some(end) = offset.checked_add(blob.bytes.len() as u64) else {
+                return Err(VfsError::BadRequest {
+                    error: "WriteAt offset and blob length overflow".into(),
+                });
+            };
+            let file = match handle {
+                Some(handle) => get_handle(&handles, handle, &source.process, &path, true)?,
+                None => Arc::new(Mutex::new(
+                    OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .open(&path)
+                        .await?,
+                )),
+            };
+            let mut file = file.lock().await;
+            let id = encryption_id_for_write(&mut file, &at_rest).await?;
+            let len = file_len(&mut file, id.is_some()).await?;
+            if offset > len.saturating_add(MAX_WRITE_GAP) {
+                return Err(VfsError::BadRequest {
+                    error: format!(
+                        "WriteAt offset {} is more than {} bytes past the end of the file",
+                        offset, MAX_WRITE_GAP
+                    ),
+                });
+            }
+            quotas.reserve(&package_id, end.saturating_sub(len)).await?;
+            if let Some(id) = id {
+                write_encrypted(&mut file, &at_rest, &id, offset, &blob.bytes).await?;
+                file.seek(SeekFrom::Start(end)).await?;
+            } else {
+                file.seek(SeekFrom::Start(offset)).await?;
+                file.write_all(&blob.bytes).await?;
+            }
+            changes.push(change(VfsChangeKind::Modify));
+            (serde_json::to_vec(&VfsResponse::Ok).unwrap(), None)
+        }
     };
 
     if let Some(target) = km.rsvp.or_else(|| {
@@ -525,9 +890,81 @@ async fn handle_request(
             .unwrap();
     }
 
+    notify_watchers(&our_node, &watchers, changes, &send_to_loop).await;
+
     Ok(())
 }
 
+/// a vfs path with a leading slash and no trailing one, as watches are keyed
+fn normalize_path(path: &str) -> String {
+    format!("/{}", path.trim_matches('/'))
+}
+
+/// whether a watch on `watched` covers a change at `path`
+fn is_covered(watched: &str, recursive: bool, path: &str) -> bool {
+    if path == watched {
+        return true;
+    }
+    match path
+        .strip_prefix(watched)
+        .and_then(|rest| rest.strip_prefix('/'))
+    {
+        Some(rest) => recursive || !rest.contains('/'),
+        None => false,
+    }
+}
+
+/// send each change as a Request to every process watching a path it covers
+async fn notify_watchers(
+    our_node: &str,
+    watchers: &Watchers,
+    changes: Vec<VfsChange>,
+    send_to_loop: &MessageSender,
+) {
+    if changes.is_empty() || watchers.is_empty() {
+        return;
+    }
+    let mut targets: Vec<(Address, Vec<u8>)> = Vec::new();
+    for change in &changes {
+        let new_path = match &change.kind {
+            VfsChangeKind::Rename { new_path } => Some(new_path.as_str()),
+            _ => None,
+        };
+        for entry in watchers.iter() {
+            for w in entry.value() {
+                let covered = is_covered(entry.key(), w.recursive, &change.path)
+                    || new_path.map_or(false, |new_path| {
+                        is_covered(entry.key(), w.recursive, new_path)
+                    });
+                if covered {
+                    targets.push((w.address.clone(), serde_json::to_vec(change).unwrap()));
+                }
+            }
+        }
+    }
+    for (target, body) in targets {
+        let _ = send_to_loop
+            .send(KernelMessage {
+                id: rand::random(),
+                source: Address {
+                    node: our_node.to_string(),
+                    process: VFS_PROCESS_ID.clone(),
+                },
+                target,
+                rsvp: None,
+                message: Message::Request(Request {
+                    inherit: false,
+                    expects_response: None,
+                    body,
+                    metadata: None,
+                    capabilities: vec![],
+                }),
+                lazy_load_blob: None,
+            })
+            .await;
+    }
+}
+
 async fn parse_package_and_drive(path: &str) -> Result<(PackageId, String, String), VfsError> {
     let mut parts: Vec<&str> = path.split('/').collect();
 
@@ -586,6 +1023,299 @@ async fn open_file<P: AsRef<Path>>(
     })
 }
 
+/// a handle opened by `owner` on the file at `path`, which must be writable if
+/// it is to be written through
+fn get_handle(
+    handles: &Handles,
+    handle: u64,
+    owner: &ProcessId,
+    path: &Path,
+    write: bool,
+) -> Result<Arc<Mutex<fs::File>>, VfsError> {
+    match handles.get(&handle) {
+        Some(h) if &h.owner == owner && h.path == path => {
+            if write && !h.writable {
+                return Err(VfsError::BadRequest {
+                    error: format!("handle {} was opened read-only", handle),
+                });
+            }
+            Ok(h.file.clone())
+        }
+        _ => Err(VfsError::NoHandle { handle }),
+    }
+}
+
+/// the id of an open file's data if it is encrypted at rest. leaves the cursor
+/// where it was, which for an encrypted file is its position in the plaintext.
+async fn encryption_id(file: &mut fs::File, at_rest: &AtRest) -> Result<Option<DataId>, VfsError> {
+    let pos = file.stream_position().await?;
+    file.seek(SeekFrom::Start(0)).await?;
+    let mut head = Vec::with_capacity(HEADER_LEN);
+    (&mut *file)
+        .take(HEADER_LEN as u64)
+        .read_to_end(&mut head)
+        .await?;
+    file.seek(SeekFrom::Start(pos)).await?;
+    Ok(at_rest.check_header(&head))
+}
+
+/// `encryption_id`, first starting an empty file off encrypted if writes are
+/// encrypted. a file that already holds plaintext keeps it: converting it in
+/// place could lose its data if interrupted, so it is only encrypted once it
+/// is replaced whole.
+async fn encryption_id_for_write(
+    file: &mut fs::File,
+    at_rest: &AtRest,
+) -> Result<Option<DataId>, VfsError> {
+    if let Some(id) = encryption_id(file, at_rest).await? {
+        return Ok(Some(id));
+    }
+    if !at_rest.enabled || file.metadata().await?.len() != 0 {
+        return Ok(None);
+    }
+    let pos = file.stream_position().await?;
+    let (header, id) = at_rest.new_header();
+    file.seek(SeekFrom::Start(0)).await?;
+    file.write_all(&[header.as_slice(), &at_rest.seal_chunk(&id, 0, true, &[])].concat())
+        .await?;
+    file.seek(SeekFrom::Start(pos)).await?;
+    Ok(Some(id))
+}
+
+/// length of an open file as processes see it: an encrypted file has the
+/// length of its plaintext
+async fn file_len(file: &mut fs::File, encrypted: bool) -> Result<u64, VfsError> {
+    let len = file.metadata().await?.len();
+    Ok(if encrypted {
+        AtRest::plaintext_len(len)
+    } else {
+        len
+    })
+}
+
+/// read and decrypt chunk `index` of an encrypted file holding `len` bytes of
+/// plaintext
+async fn read_chunk(
+    file: &mut fs::File,
+    at_rest: &AtRest,
+    id: &DataId,
+    index: u64,
+    len: u64,
+) -> Result<Vec<u8>, VfsError> {
+    let last = index + 1 == AtRest::chunk_count(len);
+    let chunk_len = if last {
+        len - index * CHUNK_LEN
+    } else {
+        CHUNK_LEN
+    };
+    let mut chunk = vec![0; (chunk_len + CHUNK_OVERHEAD) as usize];
+    file.seek(SeekFrom::Start(AtRest::chunk_offset(index)))
+        .await?;
+    file.read_exact(&mut chunk).await?;
+    Ok(at_rest.open_chunk(id, index, last, &chunk)?)
+}
+
+/// up to `len` bytes of an encrypted file's plaintext from `offset`, or all of
+/// it from `offset` on. only the chunks holding them are read. leaves the
+/// cursor where it was.
+async fn read_encrypted(
+    file: &mut fs::File,
+    at_rest: &AtRest,
+    id: &DataId,
+    offset: u64,
+    len: Option<u64>,
+) -> Result<Vec<u8>, VfsError> {
+    let pos = file.stream_position().await?;
+    let plaintext_len = file_len(file, true).await?;
+    let start = offset.min(plaintext_len);
+    let end = len.map_or(plaintext_len, |len| {
+        start.saturating_add(len).min(plaintext_len)
+    });
+    let mut contents = Vec::with_capacity((end - start) as usize);
+    if start < end {
+        for index in start / CHUNK_LEN..=(end - 1) / CHUNK_LEN {
+            let chunk = read_chunk(file, at_rest, id, index, plaintext_len).await?;
+            let chunk_start = index * CHUNK_LEN;
+            let from = start.max(chunk_start) - chunk_start;
+            let to = end.min(chunk_start + chunk.len() as u64) - chunk_start;
+            contents.extend_from_slice(&chunk[from as usize..to as usize]);
+        }
+    }
+    file.seek(SeekFrom::Start(pos)).await?;
+    Ok(contents)
+}
+
+/// write `data` into an encrypted file's plaintext at `offset`, zero-filling
+/// any gap past its end of up to `MAX_WRITE_GAP` bytes. only the chunks the
+/// write covers are resealed, along with the old last chunk if others now
+/// follow it. leaves the cursor where it was.
+async fn write_encrypted(
+    file: &mut fs::File,
+    at_rest: &AtRest,
+    id: &DataId,
+    offset: u64,
+    data: &[u8],
+) -> Result<(), VfsError> {
+    let pos = file.stream_position().await?;
+    let old_len = file_len(file, true).await?;
+    let end = match offset.checked_add(data.len() as u64) {
+        Some(end) if offset <= old_len.saturating_add(MAX_WRITE_GAP) => end,
+        _ => {
+            return Err(VfsError::BadRequest {
+                error: format!(
+                    "can't zero-fill more than {} bytes of an encrypted file",
+                    MAX_WRITE_GAP
+                ),
+            })
+        }
+    };
+    let new_len = old_len.max(end);
+    let old_last = AtRest::chunk_count(old_len) - 1;
+    let new_last = AtRest::chunk_count(new_len) - 1;
+    if new_len == old_len && data.is_empty() {
+        return Ok(());
+    }
+    let first = match offset.min(old_len) / CHUNK_LEN {
+        first if new_last != old_last => first.min(old_last),
+        first => first,
+    };
+    let last = if new_len > old_len {
+        new_last
+    } else {
+        (end - 1) / CHUNK_LEN
+    };
+    for index in first..=last {
+        let chunk_start = index * CHUNK_LEN;
+        let chunk_end = (chunk_start + CHUNK_LEN).min(new_len);
+        let mut chunk = if index <= old_last {
+            read_chunk(file, at_rest, id, index, old_len).await?
+        } else {
+            Vec::new()
+        };
+        chunk.resize((chunk_end - chunk_start) as usize, 0);
+        let (from, to) = (offset.max(chunk_start), end.min(chunk_end));
+        if from < to {
+            chunk[(from - chunk_start) as usize..(to - chunk_start) as usize]
+                .copy_from_slice(&data[(from - offset) as usize..(to - offset) as usize]);
+        }
+        file.seek(SeekFrom::Start(AtRest::chunk_offset(index)))
+            .await?;
+        file.write_all(&at_rest.seal_chunk(id, index, index == new_last, &chunk))
+            .await?;
+    }
+    file.seek(SeekFrom::Start(pos)).await?;
+    Ok(())
+}
+
+/// truncate or zero-extend an encrypted file's plaintext to `len`. leaves the
+/// cursor where it was.
+async fn set_len_encrypted(
+    file: &mut fs::File,
+    at_rest: &AtRest,
+    id: &DataId,
+    len: u64,
+) -> Result<(), VfsError> {
+    let old_len = file_len(file, true).await?;
+    if len >= old_len {
+        return write_encrypted(file, at_rest, id, len, &[]).await;
+    }
+    let pos = file.stream_position().await?;
+    let last = AtRest::chunk_count(len) - 1;
+    let mut chunk = read_chunk(file, at_rest, id, last, old_len).await?;
+    chunk.truncate((len - last * CHUNK_LEN) as usize);
+    let chunk = at_rest.seal_chunk(id, last, true, &chunk);
+    file.seek(SeekFrom::Start(AtRest::chunk_offset(last)))
+        .await?;
+    file.write_all(&chunk).await?;
+    file.set_len(AtRest::chunk_offset(last) + chunk.len() as u64)
+        .await?;
+    file.seek(SeekFrom::Start(pos)).await?;
+    Ok(())
+}
+
+/// Replace a file's contents whole. they are written beside it and renamed
+/// into place, so the file is never left half-written; open files and handles
+/// on it are then pointed at the new file.
+async fn replace_file(
+    path: &Path,
+    contents: &[u8],
+    open_files: &Arc<DashMap<PathBuf, Arc<Mutex<fs::File>>>>,
+    handles: &Handles,
+) -> Result<(), VfsError> {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let tmp_path =
+        path.with_file_name(format!(".{}.{:016x}.tmp", file_name, rand::random::<u64>()));
+    let written = async {
+        let mut file = fs::File::create(&tmp_path).await?;
+        file.write_all(contents).await?;
+        file.sync_all().await?;
+        fs::rename(&tmp_path, path).await
+    }
+    .await;
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp_path).await;
+        return Err(VfsError::IOError {
+            error: e.to_string(),
+            path: path.display().to_string(),
+        });
+    }
+    open_files.remove(path);
+    let handle_files: Vec<(Arc<Mutex<fs::File>>, bool)> = handles
+        .iter()
+        .filter(|handle| handle.path == path)
+        .map(|handle| (handle.file.clone(), handle.writable))
+        .collect();
+    for (handle_file, writable) in handle_files {
+        let reopened = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .open(path)
+            .await?;
+        *handle_file.lock().await = reopened;
+    }
+    Ok(())
+}
+
+/// whether `source` may write to `drive`: it belongs to the drive's package,
+/// or has the root cap or a write cap on the drive
+async fn can_write(
+    our_node: &str,
+    source: &Address,
+    send_to_caps_oracle: &CapMessageSender,
+    drive: &str,
+    package_id: &PackageId,
+) -> Result<bool, VfsError> {
+    if PackageId::new(source.process.package(), source.process.publisher()) == *package_id {
+        return Ok(true);
+    }
+    for params in [
+        serde_json::json!({ "root": true }),
+        serde_json::json!({ "kind": "write", "drive": drive }),
+    ] {
+        let (send_cap_bool, recv_cap_bool) = tokio::sync::oneshot::channel();
+        send_to_caps_oracle
+            .send(CapMessage::Has {
+                on: source.process.clone(),
+                cap: Capability {
+                    issuer: Address {
+                        node: our_node.to_string(),
+                        process: VFS_PROCESS_ID.clone(),
+                    },
+                    params: serde_json::to_string(&params).unwrap(),
+                },
+                responder: send_cap_bool,
+            })
+            .await?;
+        if recv_cap_bool.await? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
 async fn check_caps(
     our_node: String,
     source: Address,
@@ -632,7 +1362,9 @@ async fn check_caps(
         | VfsAction::RemoveDir
         | VfsAction::RemoveDirAll
         | VfsAction::AddZip
-        | VfsAction::SetLen(_) => {
+        | VfsAction::SetLen(_)
+        | VfsAction::OpenHandle { create: true }
+        | VfsAction::WriteAt { .. } => {
             if src_package_id == package_id {
                 return Ok(());
             }
@@ -675,7 +1407,13 @@ async fn check_caps(
         | VfsAction::Seek { .. }
         | VfsAction::Hash
         | VfsAction::Metadata
-        | VfsAction::Len => {
+        | VfsAction::Len
+        | VfsAction::Watch { .. }
+        | VfsAction::Unwatch
+        | VfsAction::OpenHandle { create: false }
+        | VfsAction::CloseHandle { .. }
+        | VfsAction::ReadAt { .. }
+        | VfsAction::Walk { .. } => {
             if src_package_id == package_id {
                 return Ok(());
             }
@@ -834,6 +1572,196 @@ async fn add_capability(
     Ok(())
 }
 
+/// metadata as processes see it: encrypted files have the length of their plaintext
+async fn file_metadata(
+    path: &Path,
+    metadata: &std::fs::Metadata,
+    at_rest: &AtRest,
+) -> Result<FileMetadata, VfsError> {
+    let len = if metadata.is_file() {
+        let mut file = fs::File::open(path).await?;
+        if encryption_id(&mut file, at_rest).await?.is_some() {
+            AtRest::plaintext_len(metadata.len())
+        } else {
+            metadata.len()
+        }
+    } else {
+        metadata.len()
+    };
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|since| since.as_millis() as u64);
+    Ok(FileMetadata {
+        file_type: get_file_type(metadata),
+        len,
+        modified,
+    })
+}
+
+/// an entry found by `walk`, yet to be listed and descended into
+struct WalkNode {
+    path: PathBuf,
+    /// path relative to the walk's root, which globs and cursors refer to
+    relative: String,
+    depth: u64,
+    is_dir: bool,
+    /// false for the root, and for the cursor and its ancestors, which were
+    /// listed on an earlier page
+    list: bool,
+    /// the rest of the cursor below this entry, if it lies on the cursor's path
+    cursor: Option<Vec<String>>,
+}
+
+/// Entries below `root` in `VfsAction::Walk` order, starting after `cursor`,
+/// and the cursor for the next page if there is one.
+///
+/// Subtrees that come before `cursor` are skipped without being read.
+async fn walk(
+    root: PathBuf,
+    vfs_path: &str,
+    max_depth: Option<u64>,
+    glob: Option<&str>,
+    include_metadata: bool,
+    limit: Option<u64>,
+    cursor: Option<&str>,
+    at_rest: &AtRest,
+) -> Result<(Vec<WalkEntry>, Option<String>), VfsError> {
+    let limit = limit.unwrap_or(WALK_PAGE_SIZE).max(1) as usize;
+    let cursor = cursor.map(|cursor| {
+        cursor
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| segment.to_string())
+            .collect::<Vec<_>>()
+    });
+
+    let mut entries = Vec::new();
+    let mut last_listed = String::new();
+    let mut stack = vec![WalkNode {
+        path: root,
+        relative: String::new(),
+        depth: 0,
+        is_dir: true,
+        list: false,
+        cursor,
+    }];
+    while let Some(node) = stack.pop() {
+        if node.list && glob.map_or(true, |glob| glob_match(glob, &node.relative)) {
+            if entries.len() == limit {
+                return Ok((entries, Some(last_listed)));
+            }
+            let metadata = fs::symlink_metadata(&node.path).await?;
+            let relative_path = node.path.strip_prefix(vfs_path).unwrap_or(&node.path);
+            entries.push(WalkEntry {
+                path: relative_path.display().to_string(),
+                file_type: get_file_type(&metadata),
+                metadata: if include_metadata {
+                    Some(file_metadata(&node.path, &metadata, at_rest).await?)
+                } else {
+                    None
+                },
+            });
+            last_listed = node.relative.clone();
+        }
+        if !node.is_dir || max_depth.is_some_and(|max_depth| node.depth >= max_depth) {
+            continue;
+        }
+
+        let mut dir = fs::read_dir(&node.path).await?;
+        let mut children = Vec::new();
+        while let Some(entry) = dir.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            children.push((name, entry.path(), entry.file_type().await?.is_dir()));
+        }
+        children.sort_by(|a, b| a.0.cmp(&b.0));
+        // pushed in reverse, so that siblings are popped in name order
+        for (name, path, is_dir) in children.into_iter().rev() {
+            let (list, cursor) = match node.cursor.as_deref() {
+                Some([next, rest @ ..]) => match name.cmp(next) {
+                    std::cmp::Ordering::Less => continue,
+                    std::cmp::Ordering::Equal => (false, Some(rest.to_vec())),
+                    std::cmp::Ordering::Greater => (true, None),
+                },
+                _ => (true, None),
+            };
+            let relative = if node.relative.is_empty() {
+                name
+            } else {
+                format!("{}/{}", node.relative, name)
+            };
+            stack.push(WalkNode {
+                path,
+                relative,
+                depth: node.depth + 1,
+                is_dir,
+                list,
+                cursor,
+            });
+        }
+    }
+    Ok((entries, None))
+}
+
+/// whether `path` matches `glob`: `*` and `?` match within one path segment,
+/// `**` matches any number of whole segments
+fn glob_match(glob: &str, path: &str) -> bool {
+    let glob: Vec<Vec<char>> = glob
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(|s| s.chars().collect())
+        .collect();
+    let path: Vec<Vec<char>> = path.split('/').map(|s| s.chars().collect()).collect();
+    wildcard_match(
+        &glob,
+        &path,
+        |pattern| pattern[..] == ['*', '*'],
+        |pattern, segment| {
+            wildcard_match(
+                pattern,
+                segment,
+                |c| *c == '*',
+                |c, segment_c| *c == '?' || c == segment_c,
+            )
+        },
+    )
+}
+
+/// Whether `items` matches `pattern`, where pattern elements for which
+/// `is_star` holds match any run of items, and each other element matches one
+/// item it `matches`.
+///
+/// On a mismatch only the last star is retried, one item further along, which
+/// is enough to find a match if there is one: time is linear in the product of
+/// the lengths, however many stars the pattern has.
+fn wildcard_match<P, T>(
+    pattern: &[P],
+    items: &[T],
+    is_star: impl Fn(&P) -> bool,
+    matches: impl Fn(&P, &T) -> bool,
+) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // the pattern index after the last star, and the item index it resumes from
+    let mut last_star: Option<(usize, usize)> = None;
+    while i < items.len() {
+        if p < pattern.len() && is_star(&pattern[p]) {
+            p += 1;
+            last_star = Some((p, i));
+        } else if p < pattern.len() && matches(&pattern[p], &items[i]) {
+            p += 1;
+            i += 1;
+        } else if let Some((star_p, star_i)) = last_star {
+            p = star_p;
+            i = star_i + 1;
+            last_star = Some((star_p, i));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(is_star)
+}
+
 fn get_file_type(metadata: &std::fs::Metadata) -> FileType {
     if metadata.is_file() {
         FileType::File
@@ -898,6 +1826,15 @@ impl From<zip::result::ZipError> for VfsError {
     }
 }
 
+impl From<QuotaExceeded> for VfsError {
+    fn from(err: QuotaExceeded) -> Self {
+        VfsError::QuotaExceeded {
+            quota: err.quota,
+            usage: err.usage,
+        }
+    }
+}
+
 impl From<std::io::Error> for VfsError {
     fn from(err: std::io::Error) -> Self {
         VfsError::IOError {
@@ -912,3 +1849,57 @@ impl std::fmt::Display for VfsAction {
         write!(f, "{:?}", self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_matches_within_segments() {
+        assert!(glob_match("a.txt", "a.txt"));
+        assert!(glob_match("*.txt", "notes.txt"));
+        assert!(glob_match("n?tes.*", "notes.md"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("*.txt", "notes.md"));
+        assert!(!glob_match("?", ""));
+        // `*` and `?` don't cross segments
+        assert!(!glob_match("*.txt", "dir/notes.txt"));
+        assert!(!glob_match("dir?notes.txt", "dir/notes.txt"));
+        assert!(glob_match("*/*.txt", "dir/notes.txt"));
+    }
+
+    #[test]
+    fn glob_double_star_matches_any_number_of_segments() {
+        assert!(glob_match("**", "a"));
+        assert!(glob_match("**", "a/b/c"));
+        assert!(glob_match("**/*.txt", "notes.txt"));
+        assert!(glob_match("**/*.txt", "a/b/notes.txt"));
+        assert!(glob_match("a/**/c", "a/c"));
+        assert!(glob_match("a/**/c", "a/b/b/c"));
+        assert!(glob_match("a/**", "a/b/c"));
+        assert!(!glob_match("a/**/c", "a/b/d"));
+        assert!(!glob_match("a/**/c", "b/c"));
+        assert!(glob_match("**/b/**/d", "a/b/c/d"));
+        assert!(!glob_match("**/b/**/d", "a/c/d"));
+    }
+
+    #[test]
+    fn glob_backtracks_past_earlier_stars() {
+        assert!(glob_match("*a*b", "xaxaxb"));
+        assert!(!glob_match("*a*b", "xaxaxc"));
+        assert!(glob_match("a*b*c", "abbbc"));
+        assert!(glob_match("**/x/y", "x/x/y"));
+        assert!(!glob_match("**/x/y", "x/y/x"));
+    }
+
+    #[test]
+    fn glob_takes_polynomial_time_on_many_stars() {
+        // exponential in the number of stars for a naive recursive matcher
+        let glob = format!("{}b", "*a".repeat(30));
+        let path = "a".repeat(200);
+        assert!(!glob_match(&glob, &path));
+        let glob = format!("{}/b", vec!["**"; 30].join("/"));
+        let path = vec!["a"; 200].join("/");
+        assert!(!glob_match(&glob, &path));
+    }
+}